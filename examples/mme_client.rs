@@ -0,0 +1,109 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use serde_json::{json, Value};
+
+//A stand-in Matchmaker Exchange node for trying /match locally. It sends a patient the way a partner node would
+//and checks the response has the MME shape
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        println!("Send an MME match request to a running server and check the response\n\n");
+        println!("Usage\nmme_client <HOST:PORT> <TOKEN> [PATIENT JSON FILE] [--version 1.0|1.1]");
+        println!("e.g.:\nmme_client localhost:8911 partner-token patient.json\n");
+        println!("Without a file a sample patient with seizures and a SCN1A variant is sent");
+        return;
+    }
+    let (address, token) = (&args[0], &args[1]);
+    let version = args.iter().position(|a| a == "--version").and_then(|i| args.get(i + 1)).map(|v| v.as_str()).unwrap_or("1.0");
+    let file = args.get(2).filter(|a| !a.starts_with("--"));
+    let body = match file {
+        Some(file) => std::fs::read_to_string(file).expect("Cannot read the patient file"),
+        None => sample_patient().to_string(),
+    };
+
+    let media_type = format!("application/vnd.ga4gh.matchmaker.v{}+json", version);
+    let request = format!(
+        "POST /match HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nAccept: {}\r\nX-Auth-Token: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        address, media_type, media_type, token, body.len(), body
+    );
+    let mut stream = TcpStream::connect(address).expect("Cannot connect to the server");
+    stream.write_all(request.as_bytes()).expect("Cannot send the request");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("Cannot read the response");
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or("");
+    let content_type = head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.trim())
+        .unwrap_or("");
+    println!("{}\nContent-Type: {}", status, content_type);
+
+    let json: Value = match serde_json::from_str(body) {
+        Ok(json) => json,
+        Err(err) => {
+            println!("Error: the body is not JSON: {}\n{}", err, body);
+            return;
+        }
+    };
+    if !status.contains(" 200 ") {
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        return;
+    }
+
+    let mut problems: Vec<String> = Vec::new();
+    if content_type != media_type {
+        problems.push(format!("expected Content-Type {}", media_type));
+    }
+    let results = json["results"].as_array().cloned().unwrap_or_else(|| {
+        problems.push("no results array".to_string());
+        Vec::new()
+    });
+    for (i, result) in results.iter().enumerate() {
+        let score = result["score"]["patient"].as_f64();
+        if !score.is_some_and(|s| (0.0..=1.0).contains(&s)) {
+            problems.push(format!("result {} has no score.patient between 0 and 1", i));
+        }
+        for field in ["id", "contact"] {
+            if result["patient"][field].is_null() {
+                problems.push(format!("result {} has no patient.{}", i, field));
+            }
+        }
+        println!(
+            "{:>2}. {:<12} score {:.3}  {} features, genes: {}",
+            i + 1,
+            result["patient"]["id"].as_str().unwrap_or("?"),
+            score.unwrap_or(0.0),
+            result["patient"]["features"].as_array().map(|f| f.len()).unwrap_or(0),
+            result["patient"]["genomicFeatures"].as_array().map(|genes| {
+                genes.iter().filter_map(|g| g["gene"]["id"].as_str()).collect::<Vec<_>>().join(", ")
+            }).unwrap_or_default(),
+        );
+    }
+    if problems.is_empty() {
+        println!("{} results, the response is a valid MME match response", results.len());
+    } else {
+        for problem in problems {
+            println!("Problem: {}", problem);
+        }
+    }
+}
+
+fn sample_patient() -> Value {
+    json!({
+        "patient": {
+            "id": "stand-in-1",
+            "label": "Stand-in patient",
+            "contact": {"name": "Stand-in node", "href": "mailto:matchmaker@example.org"},
+            "features": [
+                {"id": "HP:0001250", "observed": "yes"},
+                {"id": "HP:0001263"},
+                {"id": "HP:0000252", "observed": "no"}
+            ],
+            "genomicFeatures": [
+                {"gene": {"id": "SCN1A"}, "zygosity": 1}
+            ]
+        }
+    })
+}
@@ -0,0 +1,9 @@
+// Admin routes are only enabled when this variable holds a token, requests must send it in the x-admin-token header
+pub const ADMIN_TOKEN_ENV: &str = "PHENO_MATCHER_ADMIN_TOKEN";
+
+pub fn is_authorized(token: &Option<String>) -> bool {
+    match std::env::var(ADMIN_TOKEN_ENV) {
+        Ok(expected) if !expected.is_empty() => token.as_deref() == Some(expected.as_str()),
+        _ => false,
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::{params, Connection};
+
+// The JAX files build-db reads from its source folder, the same ones obo_to_bin uses
+const OBO_FILE: &str = "hp.obo";
+const HPOA_FILE: &str = "phenotype.hpoa";
+const GENES_TO_PHENOTYPE_FILE: &str = "genes_to_phenotype.txt";
+// Optional, fills gene_to_disease when present
+const GENES_TO_DISEASE_FILE: &str = "genes_to_disease.txt";
+
+const SCHEMA: &str = r#"
+    CREATE TABLE Terms (term_id TEXT PRIMARY KEY, name TEXT, definition TEXT, comment TEXT, synonyms TEXT);
+    CREATE TABLE Genes (gene_id TEXT PRIMARY KEY, gene_symbol TEXT);
+    CREATE TABLE Diseases (disease_id TEXT PRIMARY KEY, source TEXT, disease_name TEXT);
+    CREATE TABLE term_to_gene (term_id TEXT, gene_id TEXT, frequency TEXT, disease_id TEXT);
+    CREATE TABLE gene_to_disease (gene_id TEXT, disease_id TEXT, association_type TEXT);
+    CREATE TABLE metadata (key TEXT PRIMARY KEY, value TEXT);
+    CREATE INDEX idx_name ON Terms(name);
+    CREATE INDEX idx_gene_symbol ON Genes(gene_symbol);
+    CREATE INDEX term_to_gene_term ON term_to_gene (term_id);
+    CREATE INDEX term_to_gene_gene ON term_to_gene (gene_id);
+    CREATE INDEX term_to_gene_disease ON term_to_gene (disease_id);
+    CREATE INDEX gene_to_disease_disease ON gene_to_disease (disease_id);
+"#;
+
+#[derive(Debug)]
+pub enum BuildError {
+    Io(String, std::io::Error),
+    Sqlite(rusqlite::Error),
+    Format(String),
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BuildError::Io(path, e) => write!(f, "{}: {}", path, e),
+            BuildError::Sqlite(e) => write!(f, "db error: {}", e),
+            BuildError::Format(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for BuildError {
+    fn from(error: rusqlite::Error) -> BuildError {
+        BuildError::Sqlite(error)
+    }
+}
+
+// Row counts of what was written
+#[derive(Debug, Default)]
+pub struct BuildSummary {
+    pub terms: usize,
+    pub genes: usize,
+    pub diseases: usize,
+    pub term_to_gene: usize,
+    pub gene_to_disease: usize,
+    pub metadata: BTreeMap<String, String>,
+}
+
+struct OboTerm {
+    id: String,
+    name: Option<String>,
+    definition: Option<String>,
+    comment: Option<String>,
+    synonyms: Vec<String>,
+    obsolete: bool,
+}
+
+// Builds a fresh hpo.db at out_path from the files in source_folder. It is written next to out_path first
+// and moved over it at the end, so a running server never sees a half built db
+pub fn build_db(source_folder: &str, out_path: &str) -> Result<BuildSummary, BuildError> {
+    let source = Path::new(source_folder);
+    let obo_path = source.join(OBO_FILE);
+    let hpoa_path = source.join(HPOA_FILE);
+    let g2p_path = source.join(GENES_TO_PHENOTYPE_FILE);
+    let g2d_path = source.join(GENES_TO_DISEASE_FILE);
+
+    let (hpo_version, terms) = read_obo(&obo_path)?;
+    let (annotations_version, diseases) = read_hpoa(&hpoa_path)?;
+
+    let tmp_path = format!("{}.tmp", out_path);
+    if Path::new(&tmp_path).exists() {
+        fs::remove_file(&tmp_path).map_err(|e| BuildError::Io(tmp_path.clone(), e))?;
+    }
+    let mut conn = Connection::open(&tmp_path)?;
+    conn.execute_batch(SCHEMA)?;
+    let tx = conn.transaction()?;
+    let mut summary = BuildSummary::default();
+
+    {
+        let mut insert = tx.prepare("INSERT OR REPLACE INTO Terms (term_id, name, definition, comment, synonyms) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+        for term in terms.iter().filter(|term| !term.obsolete) {
+            // Synonyms are stored as a JSON array, a comma separated list can't tell their own commas apart
+            let synonyms = if term.synonyms.is_empty() { None } else { Some(serde_json::to_string(&term.synonyms).map_err(|e| BuildError::Format(e.to_string()))?) };
+            insert.execute(params![term.id, term.name, term.definition, term.comment, synonyms])?;
+            summary.terms += 1;
+        }
+
+        let mut insert = tx.prepare("INSERT OR REPLACE INTO Diseases (disease_id, source, disease_name) VALUES (?1, ?2, ?3)")?;
+        for (disease_id, disease_name) in &diseases {
+            let source = disease_id.split(':').next().unwrap_or("");
+            insert.execute(params![disease_id, source, disease_name])?;
+        }
+        summary.diseases = diseases.len();
+
+        // Genes come from both gene files, keyed by NCBI gene id
+        let mut genes: BTreeMap<String, String> = BTreeMap::new();
+        let mut insert = tx.prepare("INSERT INTO term_to_gene (term_id, gene_id, frequency, disease_id) VALUES (?1, ?2, ?3, ?4)")?;
+        for row in read_tsv(&g2p_path, &["ncbi_gene_id", "gene_symbol", "hpo_id", "frequency", "disease_id"])? {
+            let frequency = Some(row[3].as_str()).filter(|f| !f.is_empty() && *f != "-");
+            insert.execute(params![row[2], row[0], frequency, row[4]])?;
+            genes.insert(row[0].clone(), row[1].clone());
+            summary.term_to_gene += 1;
+        }
+
+        if g2d_path.exists() {
+            let mut insert = tx.prepare("INSERT INTO gene_to_disease (gene_id, disease_id, association_type) VALUES (?1, ?2, ?3)")?;
+            for row in read_tsv(&g2d_path, &["ncbi_gene_id", "gene_symbol", "association_type", "disease_id"])? {
+                // Stored as a plain id like genes_to_phenotype, not NCBIGene:1234
+                let gene_id = row[0].trim_start_matches("NCBIGene:").to_string();
+                insert.execute(params![gene_id, row[3], row[2]])?;
+                genes.insert(gene_id, row[1].clone());
+                summary.gene_to_disease += 1;
+            }
+        }
+
+        let mut insert = tx.prepare("INSERT INTO Genes (gene_id, gene_symbol) VALUES (?1, ?2)")?;
+        for (gene_id, gene_symbol) in &genes {
+            insert.execute(params![gene_id, gene_symbol])?;
+        }
+        summary.genes = genes.len();
+
+        let mut metadata: BTreeMap<String, String> = BTreeMap::new();
+        metadata.insert("hpo_version".to_string(), hpo_version);
+        metadata.insert("annotations_version".to_string(), annotations_version);
+        metadata.insert("built_at".to_string(), unix_now().to_string());
+        metadata.insert("built_by".to_string(), format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")));
+        for path in [&obo_path, &hpoa_path, &g2p_path, &g2d_path] {
+            if let Some(description) = describe_file(path) {
+                metadata.insert(format!("source:{}", path.file_name().unwrap_or_default().to_string_lossy()), description);
+            }
+        }
+        let mut insert = tx.prepare("INSERT INTO metadata (key, value) VALUES (?1, ?2)")?;
+        for (key, value) in &metadata {
+            insert.execute(params![key, value])?;
+        }
+        summary.metadata = metadata;
+    }
+
+    tx.commit()?;
+    drop(conn);
+    fs::rename(&tmp_path, out_path).map_err(|e| BuildError::Io(out_path.to_string(), e))?;
+    Ok(summary)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, BuildError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| BuildError::Io(path.display().to_string(), e))
+}
+
+// Terms and the release ("data-version: hp/releases/2024-04-26") from hp.obo
+fn read_obo(path: &Path) -> Result<(String, Vec<OboTerm>), BuildError> {
+    let mut version = String::new();
+    let mut terms: Vec<OboTerm> = Vec::new();
+    let mut in_term = false;
+
+    for line in open(path)?.lines() {
+        let line = line.map_err(|e| BuildError::Io(path.display().to_string(), e))?;
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_term = line == "[Term]";
+            continue;
+        }
+        let (tag, value) = match line.split_once(": ") {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        if !in_term {
+            if tag == "data-version" {
+                version = value.trim_start_matches("hp/releases/").to_string();
+            }
+            continue;
+        }
+        if tag == "id" {
+            terms.push(OboTerm { id: value.to_string(), name: None, definition: None, comment: None, synonyms: Vec::new(), obsolete: false });
+            continue;
+        }
+        let term = match terms.last_mut() {
+            Some(term) => term,
+            None => continue,
+        };
+        match tag {
+            "name" => term.name = Some(value.to_string()),
+            "def" => term.definition = quoted(value),
+            "comment" => term.comment = Some(value.to_string()),
+            "synonym" => term.synonyms.extend(quoted(value)),
+            "is_obsolete" => term.obsolete = value == "true",
+            _ => {}
+        }
+    }
+
+    if terms.is_empty() {
+        return Err(BuildError::Format(format!("{}: no terms found", path.display())));
+    }
+    Ok((version, terms))
+}
+
+// The text of an OBO quoted value like "Some \"text\"" [refs]
+fn quoted(value: &str) -> Option<String> {
+    let rest = value.strip_prefix('"')?;
+    let mut text = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            '"' => return Some(text),
+            _ => text.push(c),
+        }
+    }
+    None
+}
+
+// Disease names and the release ("#version: 2024-04-19") from phenotype.hpoa
+fn read_hpoa(path: &Path) -> Result<(String, BTreeMap<String, String>), BuildError> {
+    let mut version = String::new();
+    let mut diseases: BTreeMap<String, String> = BTreeMap::new();
+
+    for line in open(path)?.lines() {
+        let line = line.map_err(|e| BuildError::Io(path.display().to_string(), e))?;
+        if let Some(comment) = line.strip_prefix('#') {
+            if let Some(v) = comment.strip_prefix("version:").or_else(|| comment.strip_prefix("date:")) {
+                version = v.trim().to_string();
+            }
+            continue;
+        }
+        // The header row and the rows themselves both start with the disease id column
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 || fields[0] == "database_id" || fields[0] == "DatabaseID" {
+            continue;
+        }
+        diseases.entry(fields[0].to_string()).or_insert_with(|| fields[1].to_string());
+    }
+
+    if diseases.is_empty() {
+        return Err(BuildError::Format(format!("{}: no diseases found", path.display())));
+    }
+    Ok((version, diseases))
+}
+
+// The named columns of a tab separated file with a header row, in the order asked for
+fn read_tsv(path: &Path, columns: &[&str]) -> Result<Vec<Vec<String>>, BuildError> {
+    let mut lines = open(path)?.lines();
+    let header = match lines.next() {
+        Some(header) => header.map_err(|e| BuildError::Io(path.display().to_string(), e))?,
+        None => return Err(BuildError::Format(format!("{}: empty file", path.display()))),
+    };
+    let header: HashMap<&str, usize> = header.trim_start_matches('#').split('\t').enumerate().map(|(i, h)| (h.trim(), i)).collect();
+    let indexes = columns
+        .iter()
+        .map(|column| header.get(column).copied().ok_or_else(|| BuildError::Format(format!("{}: missing column {}", path.display(), column))))
+        .collect::<Result<Vec<usize>, BuildError>>()?;
+
+    let mut rows = Vec::new();
+    for line in lines {
+        let line = line.map_err(|e| BuildError::Io(path.display().to_string(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        rows.push(indexes.iter().map(|i| fields.get(*i).unwrap_or(&"").trim().to_string()).collect());
+    }
+    Ok(rows)
+}
+
+// Size and modification time, since genes_to_phenotype.txt carries no version of its own
+fn describe_file(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Some(format!("{} bytes, modified {}", metadata.len(), modified))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OBO: &str = "format-version: 1.2
+data-version: hp/releases/2024-04-26
+default-namespace: human_phenotype
+
+[Term]
+id: HP:0000001
+name: All
+
+[Term]
+id: HP:0000118
+name: Phenotypic abnormality
+def: \"A phenotypic abnormality, \\\"broadly\\\".\" [HPO:probinson]
+comment: Root of all phenotypic abnormalities.
+synonym: \"Organ abnormality, any\" EXACT []
+synonym: \"Abnormality\" RELATED []
+is_a: HP:0000001 ! All
+
+[Term]
+id: HP:0000005
+name: Mode of inheritance
+is_obsolete: true
+
+[Typedef]
+id: part_of
+name: part of
+";
+
+    const HPOA: &str = "#description: HPO annotations for rare diseases
+#version: 2024-04-19
+#tracking: https://github.com/obophenotype/human-phenotype-ontology/issues
+database_id\tdisease_name\tqualifier\thpo_id
+OMIM:100100\tPrune belly syndrome\t\tHP:0000118
+OMIM:100100\tPrune belly syndrome, again\t\tHP:0000001
+ORPHA:93\tAspartylglucosaminuria\t\tHP:0000118
+";
+
+    const GENES_TO_PHENOTYPE: &str = "ncbi_gene_id\tgene_symbol\thpo_id\thpo_name\tfrequency\tdisease_id
+10\tNAT2\tHP:0000118\tPhenotypic abnormality\t-\tOMIM:100100
+16\tAARS1\tHP:0000118\tPhenotypic abnormality\t1/2\tORPHA:93
+";
+
+    // A folder of its own under the temp dir, removed by the caller
+    fn source_folder(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let folder = std::env::temp_dir().join(format!("build_db_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&folder).unwrap();
+        for (file, contents) in files {
+            fs::write(folder.join(file), contents).unwrap();
+        }
+        folder
+    }
+
+    #[test]
+    fn reads_terms_from_the_obo() {
+        let folder = source_folder("obo", &[(OBO_FILE, OBO)]);
+        let (version, terms) = read_obo(&folder.join(OBO_FILE)).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(version, "2024-04-26");
+        assert_eq!(terms.iter().map(|term| term.id.as_str()).collect::<Vec<_>>(), vec!["HP:0000001", "HP:0000118", "HP:0000005"]);
+        let term = &terms[1];
+        assert_eq!(term.name.as_deref(), Some("Phenotypic abnormality"));
+        assert_eq!(term.definition.as_deref(), Some("A phenotypic abnormality, \"broadly\"."));
+        assert_eq!(term.comment.as_deref(), Some("Root of all phenotypic abnormalities."));
+        assert_eq!(term.synonyms, vec!["Organ abnormality, any", "Abnormality"]);
+        assert!(!term.obsolete);
+        assert!(terms[2].obsolete);
+        assert_eq!(terms[0].definition, None);
+    }
+
+    #[test]
+    fn rejects_an_obo_without_terms() {
+        let folder = source_folder("empty_obo", &[(OBO_FILE, "format-version: 1.2\n[Typedef]\nid: part_of\n")]);
+        let result = read_obo(&folder.join(OBO_FILE));
+        fs::remove_dir_all(&folder).unwrap();
+        assert!(matches!(result, Err(BuildError::Format(_))));
+    }
+
+    #[test]
+    fn reads_disease_names_from_the_hpoa() {
+        let folder = source_folder("hpoa", &[(HPOA_FILE, HPOA)]);
+        let (version, diseases) = read_hpoa(&folder.join(HPOA_FILE)).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(version, "2024-04-19");
+        // A disease keeps the name of its first row
+        assert_eq!(diseases.into_iter().collect::<Vec<_>>(), vec![
+            ("OMIM:100100".to_string(), "Prune belly syndrome".to_string()),
+            ("ORPHA:93".to_string(), "Aspartylglucosaminuria".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn reads_the_older_hpoa_header() {
+        let folder = source_folder("old_hpoa", &[(HPOA_FILE, "#date: 2021-10-10\n#DatabaseID\tDiseaseName\nOMIM:100100\tPrune belly syndrome\n")]);
+        let (version, diseases) = read_hpoa(&folder.join(HPOA_FILE)).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(version, "2021-10-10");
+        assert_eq!(diseases.len(), 1);
+    }
+
+    #[test]
+    fn reads_quoted_obo_values() {
+        assert_eq!(quoted("\"Some \\\"text\\\"\" [refs]").as_deref(), Some("Some \"text\""));
+        assert_eq!(quoted("unquoted"), None);
+        assert_eq!(quoted("\"unterminated"), None);
+    }
+
+    #[test]
+    fn builds_a_db_with_the_lookup_indexes() {
+        let folder = source_folder("db", &[(OBO_FILE, OBO), (HPOA_FILE, HPOA), (GENES_TO_PHENOTYPE_FILE, GENES_TO_PHENOTYPE)]);
+        let out = folder.join("hpo.db").to_string_lossy().to_string();
+        let summary = build_db(&folder.to_string_lossy(), &out).unwrap();
+        let conn = Connection::open(&out).unwrap();
+        let indexes: Vec<String> = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND name LIKE 'idx_%' ORDER BY name").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        let synonyms: String = conn.query_row("SELECT synonyms FROM Terms WHERE term_id = 'HP:0000118'", [], |row| row.get(0)).unwrap();
+        drop(conn);
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!((summary.terms, summary.genes, summary.diseases, summary.term_to_gene, summary.gene_to_disease), (2, 2, 2, 2, 0));
+        assert_eq!(indexes, vec!["idx_gene_symbol", "idx_name"]);
+        assert_eq!(synonyms, "[\"Organ abnormality, any\",\"Abnormality\"]");
+    }
+}
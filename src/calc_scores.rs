@@ -11,8 +11,10 @@ use crate::{custom_jaccard_ic, population};
 pub enum ScoreReturn {
     ScoreMap(HashMap<String, HashMap<String, f32>>),
     ScoreVec(Vec<Vec<String>>),
+    GeneMap(HashMap<String, Vec<String>>),
 }
 
+#[allow(clippy::needless_borrow)]
 pub fn calc_scores(ontology: &Arc<Ontology>, hpo_ids1: Vec<u32>, population: &Arc<HashMap<String, HashMap<String, String>>>) -> HashMap<String, ScoreReturn> {
     //Create a hashmap to store the scores
     let mut score_map: HashMap<String, HashMap<String, f32>> = HashMap::new();
@@ -21,34 +23,12 @@ pub fn calc_scores(ontology: &Arc<Ontology>, hpo_ids1: Vec<u32>, population: &Ar
     //Create a group from the hpo_ids1 vector
     let hpo_group1 = HpoGroup::from(hpo_ids1);
     let hpo_set1 = HpoSet::new(&ontology, hpo_group1);
-    let sim = GroupSimilarity::new(StandardCombiner::default(), custom_jaccard_ic::CustomJaccardIC{});
+    let sim = group_similarity();
 
     //Iterate through the population
     for (key, value) in population.iter() {
-        //Create a group from the hpo_ids2 vector take the HP: off of the HPO ids and make into a u32
-        // let hpo_ids2: Vec<u32> = value.get("Terms").unwrap().split("; ").map(|s| s.replace("HP:", "").parse::<u32>().unwrap()).collect();
-
-        let hpo_ids2: Vec<u32> = value.get("Terms").unwrap()
-            .split("; ")
-            .filter_map(|s| {
-                let id_str = s.replace("HP:", "");
-                match id_str.parse::<u32>() {
-                    Ok(id) => {
-                        // Check if the term exists in the ontology before including it
-                        if ontology.hpo(id).is_some() {
-                            Some(id)
-                        } else {
-                            eprintln!("Warning: HPO term {} not found in ontology", s);
-                            None
-                        }
-                    }
-                    Err(_) => {
-                        eprintln!("Warning: Failed to parse HPO ID: {}", s);
-                        None
-                    }
-                }
-            })
-            .collect();
+        //Create a group from the individual's terms
+        let hpo_ids2: Vec<u32> = population::individual_terms(ontology, value);
         let hpo_group2 = HpoGroup::from(hpo_ids2);
         let hpo_set2 = HpoSet::new(&ontology, hpo_group2);
         //Calculate the similarity
@@ -66,13 +46,18 @@ pub fn calc_scores(ontology: &Arc<Ontology>, hpo_ids1: Vec<u32>, population: &Ar
     return_map
 }
 
-fn create_ranked_vec(score_map: HashMap<String, HashMap<String, f32>>, score_vec: Vec<Vec<String>>) -> HashMap<String, ScoreReturn> {
+// The measure every population comparison uses
+pub fn group_similarity() -> GroupSimilarity<custom_jaccard_ic::CustomJaccardIC, StandardCombiner> {
+    GroupSimilarity::new(StandardCombiner::default(), custom_jaccard_ic::CustomJaccardIC{})
+}
+
+pub fn create_ranked_vec(score_map: HashMap<String, HashMap<String, f32>>, score_vec: Vec<Vec<String>>) -> HashMap<String, ScoreReturn> {
     //The score map will be modified to add rank as we determine the rank from the ordering of the scores
     let mut score_map = score_map;
     //Create a vector to store the ranked individuals
     let mut ranked_vec: Vec<Vec<String>> = score_vec.clone();
-    //Sort the score_vec by the score
-    ranked_vec.sort_by(|a, b| b[1].partial_cmp(&a[1]).unwrap());
+    //Sort the score_vec by the score, as numbers since gene aware scores can go past 1
+    ranked_vec.sort_by(|a, b| b[1].parse::<f32>().unwrap_or(0.0).total_cmp(&a[1].parse::<f32>().unwrap_or(0.0)));
 
     //Iterate through the ranked_vec get the rank and add it to the score_map based on the key and [0]
     for (i, v) in ranked_vec.iter().enumerate() {
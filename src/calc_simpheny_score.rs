@@ -3,47 +3,116 @@ use crate::{custom_jaccard_ic, population};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use rand::prelude::*;
-use rand::rng;
+use rand_chacha::ChaCha8Rng;
 use statrs::distribution::{ChiSquared, ContinuousCDF};
 use hpo::similarity::{Similarity, StandardCombiner, GroupSimilarity};
 use hpo::{Ontology, HpoSet, HpoTermId};
 use hpo::term::HpoGroup;
 use csv::Reader;
+use serde::Serialize;
+use crate::simpheny_background::BrownsParams;
+use crate::simpheny_nulls::NullTable;
+
+// Queries with more terms than this are truncated before sampling for computational efficiency
+pub const MAX_QUERY_TERMS: u32 = 10;
+
+// Draws taken before the stopping rule is first checked, so a handful of early exceedances can't end a run
+const MIN_ITERATIONS: u32 = 100;
+
+// Two-sided 95% normal quantile used for the Monte Carlo confidence intervals
+const Z_95: f64 = 1.959963984540054;
+
+// Everything that went into a SimPheny score so reviewers can see which component drives it
+#[derive(Serialize, Debug, Clone)]
+pub struct SimphenyResult {
+    pub score: f64, // -log10 of combined_p
+    pub combined_p: f64, // Brown's method combination of pheno_p and gene_p
+    pub pheno_p: f64,
+    pub pheno_p_ci: [f64; 2],
+    pub gene_p: f64,
+    pub gene_p_ci: [f64; 2],
+    pub iterations: u32, // Draws actually taken
+    pub max_iterations: u32,
+    pub precision: f64, // Target relative standard error of pheno_p
+    pub stopped_early: bool, // True when precision was reached before max_iterations
+    pub null_source: String, // "precomputed" when pheno_p came from the null table, otherwise "simulated"
+    pub scale: f64,
+    pub dof: f64,
+    pub num_hpo_terms: u32, // As requested
+    pub effective_num_hpo_terms: u32, // After truncation, the size of each random query
+    pub seed: u64,
+}
 
 // Using "top down" approach to organization; public function at the top, private functions below
-#[allow(unused_variables, unused_imports)]
-pub fn calc_simpheny_score(ontology: &Arc<Ontology>, hit_terms: Vec<u32>, hit_gene: String, sim_score: f32, num_query_genes: u32, num_hpo_terms: u32, data_bg: String, iterations: u32, terms_url: &str, genes_url: &str) -> f64 {
+#[allow(unused_variables, unused_imports, clippy::too_many_arguments)]
+pub fn calc_simpheny_score(ontology: &Arc<Ontology>, hit_terms: Vec<u32>, hit_gene: String, sim_score: f32, num_query_genes: u32, num_hpo_terms: u32, params: &BrownsParams, precision: f64, max_iterations: u32, seed: u64, nulls: Option<&NullTable>, terms_url: &str, genes_url: &str) -> Result<SimphenyResult, String> {
+    let all_gene_list = load_gene_list(genes_url).map_err(|e| format!("could not read gene list {}: {}", genes_url, e))?;
+    let all_term_list = load_term_list(ontology, terms_url).map_err(|e| format!("could not read term list {}: {}", terms_url, e))?;
+    let gene_p = calc_gene_p_val(&all_gene_list, &hit_gene, num_query_genes);
+    calc_simpheny_score_with_lists(ontology, hit_terms, gene_p, sim_score, num_hpo_terms, params, precision, max_iterations, seed, nulls, &all_term_list)
+}
+
+// calc_simpheny_score with the term list already loaded and the gene p-value already computed, for scoring many
+// hits at once. A hit that has none of the query's genes has no gene evidence, so its gene_p is 1
+#[allow(clippy::too_many_arguments)]
+pub fn calc_simpheny_score_with_lists(ontology: &Arc<Ontology>, hit_terms: Vec<u32>, gene_p: f64, sim_score: f32, num_hpo_terms: u32, params: &BrownsParams, precision: f64, max_iterations: u32, seed: u64, nulls: Option<&NullTable>, all_term_list: &[u32]) -> Result<SimphenyResult, String> {
     let mut num_terms = num_hpo_terms;
-    
-    // If the data_bg is udn use udn if it is clinvar then use clinvar otherwise default to udn
-    let scale: f64;
-    let dof: f64;
-    if data_bg == "udn" {
-            // UDN values
-        scale = 1.0703270447328037;
-        dof = 3.737175491999793;
-    } else if data_bg == "clinvar" {
-        // ClinVar values
-        scale = 1.0334745692972533;
-        dof = 3.8704387305049393;
-    } else {
-        // Default to UDN values if an invalid population is provided
-        scale = 1.0703270447328037;
-        dof = 3.737175491999793;
-    }
-
-    // Grab all the gene_symbols from the database
-    let mut gene_reader = Reader::from_path(genes_url).unwrap();
-    let all_gene_list: Vec<String> = gene_reader
+    // Every draw comes from this one seeded generator so a (request, seed) pair always replays to the same score.
+    // ChaCha8 rather than StdRng, whose algorithm rand may change in any release
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+    if num_terms > MAX_QUERY_TERMS {
+        // Truncate for computational efficiency
+        num_terms = MAX_QUERY_TERMS;
+    }
+
+    // A precomputed null for this exact profile and query size replaces the simulation
+    let precomputed = nulls.and_then(|nulls| nulls.lookup(&hit_terms, num_terms, sim_score));
+    let null_source = if precomputed.is_some() { "precomputed" } else { "simulated" };
+    let (pheno_hits, iterations) = match precomputed {
+        Some(counts) => counts,
+        None => calc_pheno_p_val(ontology, hit_terms, sim_score, all_term_list, num_terms, precision, max_iterations, &mut rng),
+    };
+    let (pheno_p, pheno_p_ci) = empirical_p_val(pheno_hits, iterations);
+    // The gene p-value is exact so its interval is just the value
+    let gene_p_ci = [gene_p, gene_p];
+    let combined_p = empirical_browns_method(pheno_p, gene_p, params.scale, params.dof)?;
+
+    Ok(SimphenyResult {
+        score: -combined_p.log10(),
+        combined_p,
+        pheno_p,
+        pheno_p_ci,
+        gene_p,
+        gene_p_ci,
+        iterations,
+        max_iterations,
+        precision,
+        stopped_early: precomputed.is_none() && iterations < max_iterations,
+        null_source: null_source.to_string(),
+        scale: params.scale,
+        dof: params.dof,
+        num_hpo_terms,
+        effective_num_hpo_terms: num_terms,
+        seed,
+    })
+}
+
+// Grab all the gene_symbols from the gene list csv
+pub fn load_gene_list(genes_url: &str) -> Result<Vec<String>, csv::Error> {
+    let mut gene_reader = Reader::from_path(genes_url)?;
+    Ok(gene_reader
         .records()
         .skip(1) // Skip header row
         .filter_map(|result| result.ok())
         .map(|record| record[0].to_string()) // Assuming the first column contains gene symbols
-        .collect();
+        .collect())
+}
 
-    // Grab all the hpo_terms from the database
-    let mut term_reader = Reader::from_path(terms_url).unwrap();
-    let all_term_list: Vec<u32> = term_reader
+// Grab all the hpo_terms from the term list csv
+pub fn load_term_list(ontology: &Arc<Ontology>, terms_url: &str) -> Result<Vec<u32>, csv::Error> {
+    let mut term_reader = Reader::from_path(terms_url)?;
+    Ok(term_reader
         .records()
         .skip(1) // Skip header row
         .filter_map(|result| result.ok())
@@ -56,88 +125,196 @@ pub fn calc_simpheny_score(ontology: &Arc<Ontology>, hit_terms: Vec<u32>, hit_ge
             let tid = HpoTermId::from(*term_id);
             ontology.hpo(tid).is_some()
         }) // Ensure the term exists in the ontology
-        .collect();
+        .collect())
+}
 
-    if num_terms > 10 {
-        // Truncate for computational efficiency
-        num_terms = 10;
-    }
+// Randomly sample num_hpo_terms unique terms to act as a null query
+pub fn sample_terms<R: Rng + ?Sized>(all_term_list: &[u32], num_hpo_terms: u32, rng: &mut R) -> Vec<u32> {
+    let mut random_terms: Vec<u32> = all_term_list
+        .choose_multiple(rng, num_hpo_terms as usize)
+        .cloned()
+        .collect::<HashSet<_>>() // Convert to HashSet to ensure uniqueness
+        .into_iter() // Convert to an iterator
+        .collect::<Vec<u32>>(); // Collect back into a Vec<u32>
+
+    // Ensure we have unique terms
+    while random_terms.len() < num_hpo_terms as usize {
+        let sample_num = num_hpo_terms as usize - random_terms.len();
 
-    let (pheno_p, gene_p) = calc_p_vals(ontology, hit_terms, hit_gene, sim_score, all_term_list, all_gene_list, num_terms, num_query_genes, iterations);
-    let combined_p = empirical_browns_method(pheno_p, gene_p, scale, dof);
+        // Sampling more terms (consumed by .extend below)
+        let more_terms: Vec<u32> = all_term_list
+            .choose_multiple(rng, sample_num)
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<u32>>();
 
-    -combined_p.log10() // Return the SimPheny score
+        random_terms.extend(more_terms); // Extend the random_terms with more unique terms we will do again if we still don't have enough
+    }
+    random_terms
 }
 
-fn calc_p_vals(ontology: &Arc<Ontology>, hit_terms: Vec<u32>, hit_gene: String, sim_score: f32, all_term_list: Vec<u32>, all_gene_list: Vec<String>, num_hpo_terms: u32, num_query_genes: u32, iterations: u32) -> (f64, f64) {
-    let mut i: u32 = 0;
-    let mut rand_genes: Vec<String> = Vec::new();
-    let mut rand_scores: Vec<f32> = Vec::new();
+// Sequential Monte Carlo for the phenotype p-value. Draws until the relative standard error of the
+// estimate, sqrt((1 - p) / (n * p)), is within precision or max_iterations is reached. Like Besag-Clifford
+// this stops once enough exceedances are seen, so insignificant hits finish after a few hundred draws.
+// Returns the exceedance count and the number of draws taken
+#[allow(clippy::too_many_arguments)]
+fn calc_pheno_p_val(ontology: &Arc<Ontology>, hit_terms: Vec<u32>, sim_score: f32, all_term_list: &[u32], num_hpo_terms: u32, precision: f64, max_iterations: u32, rng: &mut ChaCha8Rng) -> (u32, u32) {
+    let mut hits: u32 = 0;
+    let mut n: u32 = 0;
 
     //Convert the ids into a group and then set them
     let hit_group = HpoGroup::from(hit_terms);
-    let hit_set = HpoSet::new(&ontology, hit_group);
+    let hit_set = HpoSet::new(ontology, hit_group);
+    let sim = GroupSimilarity::new(StandardCombiner::default(), custom_jaccard_ic::CustomJaccardIC{});
 
-    while i < iterations {
-        // Randomly sample terms
-        let mut random_terms: Vec<u32> = all_term_list
-            .choose_multiple(&mut rng(), num_hpo_terms as usize)
-            .cloned()
-            .collect::<HashSet<_>>() // Convert to HashSet to ensure uniqueness
-            .into_iter() // Convert to an iterator
-            .collect::<Vec<u32>>(); // Collect back into a Vec<u32>
-
-        // Ensure we have unique terms
-        while random_terms.len() < num_hpo_terms as usize {
-            let sample_num = num_hpo_terms as usize - random_terms.len();
-
-            // Sampling more terms (consumed by .extend below)
-            let more_terms: Vec<u32> = all_term_list
-                .choose_multiple(&mut rng(), sample_num)
-                .cloned()
-                .collect::<HashSet<_>>()
-                .into_iter()
-                .collect::<Vec<u32>>();
-
-            random_terms.extend(more_terms); // Extend the random_terms with more unique terms we will do again if we still don't have enough
+    while n < max_iterations {
+        let random_terms = sample_terms(all_term_list, num_hpo_terms, rng);
+        let random_set = HpoSet::new(ontology, HpoGroup::from(random_terms));
+
+        // Count the draws at least as extreme as the observed hit
+        if sim.calculate(&hit_set, &random_set) >= sim_score {
+            hits += 1;
         }
+        n += 1;
 
-        let random_group = HpoGroup::from(random_terms);
-        let random_set = HpoSet::new(&ontology, random_group);
+        if precise_enough(hits, n, precision) {
+            break;
+        }
+    }
 
-        // Calculate the similarity score
-        let sim = GroupSimilarity::new(StandardCombiner::default(), custom_jaccard_ic::CustomJaccardIC{});
-        let score = sim.calculate(&hit_set, &random_set);
-        rand_scores.push(score);
+    (hits, n)
+}
 
-        // Randomly sample genes (will be consumed by .extend below)
-        let random_gene_sample: Vec<String> = all_gene_list
-            .choose_multiple(&mut rng(), num_query_genes as usize)
-            .cloned()
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect::<Vec<String>>();
+// The stopping rule: after MIN_ITERATIONS draws with at least one exceedance, stop once the relative standard
+// error of the estimate is within precision
+fn precise_enough(hits: u32, n: u32, precision: f64) -> bool {
+    if n < MIN_ITERATIONS || hits == 0 {
+        return false;
+    }
+    let p = (hits as f64 + 1.0) / (n as f64 + 1.0);
+    ((1.0 - p) / (n as f64 * p)).sqrt() <= precision
+}
 
-        // Gene sample is already unique, so we can just extend
-        rand_genes.extend(random_gene_sample);
+// The chance a random draw of num_query_genes genes from the background contains hit_gene, computed
+// exactly instead of by sampling. A gene missing from the background is counted as appearing once, and a query
+// without genes has no gene evidence so its p-value is 1.
+// Unlike the sampled (hits + 1) / (n + 1) it replaced, this has no 1 / (n + 1) floor, so a rare gene gets a smaller
+// p-value than before. The built in udn and clinvar scale and dof were fitted against the floored values, so scores
+// using them moved with this change; use calibrate for parameters fitted against the exact p-value
+pub fn calc_gene_p_val(all_gene_list: &[String], hit_gene: &str, num_query_genes: u32) -> f64 {
+    if num_query_genes == 0 {
+        return 1.0;
+    }
+    let occurrences = all_gene_list.iter().filter(|g| g.as_str() == hit_gene).count().max(1);
+    let total = all_gene_list.len().max(occurrences) as f64;
+    let draws = (num_query_genes as f64).min(total);
+
+    // 1 - C(total - occurrences, draws) / C(total, draws)
+    let mut p_missed = 1.0;
+    let mut i = 0.0;
+    while i < draws {
+        p_missed *= ((total - occurrences as f64 - i) / (total - i)).max(0.0);
+        i += 1.0;
+    }
+    1.0 - p_missed
+}
 
-        i += 1;
+// Turns an exceedance count into the (hits + 1) / (n + 1) p-value and a 95% Wilson interval for the exceedance
+// probability. The interval is of hits out of iterations, the + 1 only keeps the point estimate above 0
+fn empirical_p_val(hits: u32, iterations: u32) -> (f64, [f64; 2]) {
+    let p = (hits as f64 + 1.0) / (iterations as f64 + 1.0);
+    if iterations == 0 {
+        return (p, [0.0, 1.0]);
     }
 
-    // Calculate p-values
-    let pheno_pval = (rand_scores.iter().filter(|&s| s >= &sim_score).count() as f64 + 1.0) / (rand_scores.len() as f64 + 1.0);
-    let gene_pval = (rand_genes.iter().filter(|&g| g == &hit_gene).count() as f64 + 1.0) / (iterations as f64 + 1.0);
+    let n = iterations as f64;
+    let observed = hits as f64 / n;
+    let z2 = Z_95 * Z_95;
+    let center = (observed + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half_width = (Z_95 / (1.0 + z2 / n)) * (observed * (1.0 - observed) / n + z2 / (4.0 * n * n)).sqrt();
 
-    (pheno_pval, gene_pval)
+    (p, [(center - half_width).max(0.0), (center + half_width).min(1.0)])
 }
 
 
-fn empirical_browns_method(pheno_pval: f64, gene_pval: f64, scale: f64, dof: f64) -> f64 {
+pub fn empirical_browns_method(pheno_pval: f64, gene_pval: f64, scale: f64, dof: f64) -> Result<f64, String> {
     let stat = -2.0 * (pheno_pval.ln() + gene_pval.ln()); // ln is the natural logarithm which should be the same as np.log in Python
     let adjusted_stat = stat / scale;
 
-    let chi2_dist = ChiSquared::new(dof).unwrap(); // Should maybe handle if there is an error in creating the distribution instead of unwrap
-    let combined_p = 1.0 - chi2_dist.cdf(adjusted_stat);
+    let chi2_dist = ChiSquared::new(dof).map_err(|e| format!("invalid Brown's method dof {}: {}", dof, e))?;
+    Ok(1.0 - chi2_dist.cdf(adjusted_stat))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genes(list: &[&str]) -> Vec<String> {
+        list.iter().map(|g| g.to_string()).collect()
+    }
 
-    combined_p
-}
\ No newline at end of file
+    #[test]
+    fn gene_p_val_without_query_genes_is_one() {
+        assert_eq!(calc_gene_p_val(&genes(&["A", "B", "C"]), "A", 0), 1.0);
+    }
+
+    #[test]
+    fn gene_p_val_is_hypergeometric() {
+        // One A in four genes: a single draw finds it 1/4 of the time, two draws 1 - (3/4)(2/3) = 1/2
+        let background = genes(&["A", "B", "C", "D"]);
+        assert!((calc_gene_p_val(&background, "A", 1) - 0.25).abs() < 1e-12);
+        assert!((calc_gene_p_val(&background, "A", 2) - 0.5).abs() < 1e-12);
+        // Drawing every gene always finds it
+        assert!((calc_gene_p_val(&background, "A", 10) - 1.0).abs() < 1e-12);
+        // Two As out of four in one draw
+        assert!((calc_gene_p_val(&genes(&["A", "A", "B", "C"]), "A", 1) - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn gene_p_val_counts_missing_genes_once() {
+        let background = genes(&["A", "B", "C", "D"]);
+        assert!((calc_gene_p_val(&background, "Z", 1) - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn stopping_rule_waits_for_min_iterations_and_a_hit() {
+        assert!(!precise_enough(50, MIN_ITERATIONS - 1, 1.0));
+        assert!(!precise_enough(0, 100_000, 1.0));
+    }
+
+    #[test]
+    fn stopping_rule_checks_relative_standard_error() {
+        // p = 51 / 101, relative standard error sqrt((1 - p) / (100 p)) is about 0.099
+        assert!(precise_enough(50, 100, 0.1));
+        assert!(!precise_enough(50, 100, 0.05));
+        // p = 2 / 101 is far less precise after the same draws, about 0.70
+        assert!(!precise_enough(1, 100, 0.5));
+        assert!(precise_enough(1, 100, 0.75));
+    }
+
+    #[test]
+    fn empirical_p_val_adds_one() {
+        let (p, _) = empirical_p_val(0, 99);
+        assert!((p - 0.01).abs() < 1e-12);
+        let (p, _) = empirical_p_val(9, 99);
+        assert!((p - 0.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn empirical_p_val_interval_is_wilson_of_hits() {
+        // Wilson 95% intervals for 0/100 and 5/100
+        let (_, [low, high]) = empirical_p_val(0, 100);
+        assert!(low.abs() < 1e-12 && (high - 0.036993).abs() < 1e-5);
+        let (_, [low, high]) = empirical_p_val(5, 100);
+        assert!((low - 0.021544).abs() < 1e-5 && (high - 0.111750).abs() < 1e-5);
+    }
+
+    #[test]
+    fn empirical_p_val_interval_is_within_zero_and_one() {
+        for (hits, n) in [(0, 100), (5, 100), (100, 100), (0, 1), (0, 0)] {
+            let (_, [low, high]) = empirical_p_val(hits, n);
+            assert!((0.0..=1.0).contains(&low) && (0.0..=1.0).contains(&high) && low <= high);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::sync::Arc;
+use rand::Rng;
+use hpo::Ontology;
+use crate::{population, calc_simpheny_score, simpheny_background, simpheny_nulls, build_db, population_store, populations, similarity_matrix, cohort_filter, clustering, gene_search, vcf_prioritization};
+
+// Runs a subcommand if one was given, returns false when the server should start instead.
+// The ontology is only loaded for subcommands that use it, so build-db and the usage work without the binary file
+pub fn run(args: &[String], load_ontology: impl FnOnce() -> Arc<Ontology>) -> bool {
+    match args.first().map(|a| a.as_str()) {
+        Some("calibrate") => {
+            calibrate(&args[1..], &load_ontology());
+            true
+        }
+        Some("precompute-nulls") => {
+            precompute_nulls(&args[1..], &load_ontology());
+            true
+        }
+        Some("build-db") => {
+            build_db(&args[1..]);
+            true
+        }
+        Some("ingest") => {
+            ingest(&args[1..], &load_ontology());
+            true
+        }
+        Some("similarity-matrix") => {
+            similarity_matrix(&args[1..], &load_ontology());
+            true
+        }
+        Some("cluster") => {
+            cluster(&args[1..], &load_ontology());
+            true
+        }
+        Some("prioritize") => {
+            prioritize(&args[1..], &load_ontology());
+            true
+        }
+        Some("help") | Some("--help") | Some("-h") => {
+            print_usage();
+            true
+        }
+        _ => false,
+    }
+}
+
+fn print_usage() {
+    println!("Usage\npheno_matcher_be_rust [SUBCOMMAND]\n");
+    println!("Without a subcommand the server is started\n");
+    println!("Subcommands");
+    println!("calibrate <NAME> <POPULATION> [--terms N] [--genes N] [--samples N] [--draws N] [--seed N]");
+    println!("    Fit the Brown's method scale and dof for SimPheny from a population's null distribution");
+    println!("    and store them as the data_bg NAME. POPULATION is one of udn, orpha, decipher, clinvar");
+    println!("e.g.:\ncalibrate udn udn --samples 200 --draws 500\n");
+    println!("precompute-nulls <POPULATION>... [--draws N] [--seed N] [--out PATH]");
+    println!("    Precompute SimPheny null similarity distributions for every individual in the populations");
+    println!("    and every query size, added to the null file the server loads at startup ({})", crate::NULLS_URL);
+    println!("e.g.:\nprecompute-nulls udn clinvar --draws 2000\n");
+    println!("build-db <SOURCE FOLDER> [--out PATH]");
+    println!("    Build hpo.db from the hp.obo, phenotype.hpoa and genes_to_phenotype.txt (and genes_to_disease.txt");
+    println!("    if present) in the folder, recording their versions. Replaces {} unless --out is given", crate::get_db_path());
+    println!("e.g.:\nbuild-db example_data/\n");
+    println!("ingest [POPULATION]... [--force]");
+    println!("    Normalize the population files (all of them by default) into the population store ({})", crate::POPULATION_STORE_URL);
+    println!("    and record their hash and row count. Files whose hash hasn't changed are skipped unless --force is given");
+    println!("e.g.:\ningest udn orpha\n");
+    println!("similarity-matrix <POPULATION> [--against POPULATION] [--format neighbors|tsv|binary] [--top N] [--diagnosis D] [--out PATH]");
+    println!("    Score every individual in the population against every individual in it, or in the --against population,");
+    println!("    and write the top N neighbors of each (default {}), the dense matrix as TSV, or the dense matrix gzipped", similarity_matrix::DEFAULT_TOP_N);
+    println!("    and quantized. An interrupted run carries on from its last checkpoint when run again with the same arguments");
+    println!("e.g.:\nsimilarity-matrix udn --against orpha --format binary --out udn_orpha.smtx.gz\n");
+    println!("cluster <POPULATION> [--method graph|hierarchical] [--top N] [--min-similarity X] [--clusters N] [--min-size N] [--diagnosis D] [--out PATH]");
+    println!("    Group the individuals of a population by phenotype similarity and report each group's members, enriched");
+    println!("    terms and recurrent genes as JSON, to stdout unless --out is given. D is diagnosed, undiagnosed or any");
+    println!("e.g.:\ncluster udn --diagnosis undiagnosed --top 5\n");
+    println!("prioritize <VCF> --terms HP:...,HP:... [--max-af X] [--impacts I,...] [--consequences C,...] [--phenotype-weight W] [--all-filters] [--top N] [--out PATH]");
+    println!("    Rank the genes of a VEP (CSQ) or SnpEff (ANN) annotated VCF, gzipped or not, by their rare damaging variants");
+    println!("    and the similarity of their annotated terms to the patient's, as JSON. Defaults are --max-af 0.01,");
+    println!("    --impacts HIGH,MODERATE and --phenotype-weight 0.5. Only PASS variants are used unless --all-filters is given");
+    println!("e.g.:\nprioritize proband.vep.vcf.gz --terms HP:0001250,HP:0001263 --top 20\n");
+}
+
+// Reports a failed subcommand on stderr and exits non-zero, so scripts can tell it failed
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
+
+// A subcommand given without the arguments it needs
+fn usage_error() -> ! {
+    print_usage();
+    std::process::exit(1);
+}
+
+// Value following a "--flag" in the arguments, parsed. None when the flag isn't given, a flag without a value or
+// with one that doesn't parse fails rather than falling back to the default
+fn flag_value<T: std::str::FromStr>(args: &[String], flag: &str) -> Option<T> {
+    let i = args.iter().position(|a| a == flag)?;
+    match args.get(i + 1) {
+        Some(value) => match value.parse::<T>() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => fail(format!("invalid value for {}: {}", flag, value)),
+        },
+        None => fail(format!("{} needs a value", flag)),
+    }
+}
+
+// Loads a population's cohort from the population store by name, ingesting its file first if it changed, failing
+// when it can't be. diagnosis is diagnosed, undiagnosed or any, the population's default when None
+fn load_population(name: &str, ontology: &Ontology, diagnosis: Option<&str>) -> Arc<population::Population> {
+    let store = match population_store::PopulationStore::open(crate::POPULATION_STORE_URL) {
+        Ok(store) => store,
+        Err(err) => fail(format!("could not open the population store: {}", err)),
+    };
+    let ingested = match crate::population_url(name) {
+        Some(_) => populations::ingest(ontology, &store, name, false).map(|_| ()),
+        None => Ok(()),
+    };
+    let cohort = ingested
+        .and_then(|_| populations::read(&store, name))
+        .and_then(|(population, _)| {
+            let filter = cohort_filter::CohortFilter::new(ontology, name, diagnosis, &[], &[], &[])?;
+            filter.apply(ontology, &store, name, &Arc::new(population))
+        });
+    match cohort {
+        Ok(population) => population,
+        Err(err) => fail(format!("could not load population {}: {}", name, err.message())),
+    }
+}
+
+fn calibrate(args: &[String], ontology: &Arc<Ontology>) {
+    if args.len() < 2 {
+        usage_error();
+    }
+    let name = &args[0];
+    let population_name = &args[1];
+
+    let population = load_population(population_name, ontology, None);
+
+    let num_hpo_terms: u32 = flag_value(args, "--terms").unwrap_or(calc_simpheny_score::MAX_QUERY_TERMS);
+    let num_query_genes: u32 = flag_value(args, "--genes").unwrap_or(1);
+    let samples: u32 = flag_value(args, "--samples").unwrap_or(200);
+    let draws: u32 = flag_value(args, "--draws").unwrap_or(500);
+    let seed: u64 = flag_value(args, "--seed").unwrap_or_else(|| rand::rng().random());
+
+    let (all_term_list, all_gene_list) = match (calc_simpheny_score::load_term_list(ontology, crate::TERMS_LIST_URL), calc_simpheny_score::load_gene_list(crate::GENE_LIST_URL)) {
+        (Ok(terms), Ok(genes)) => (terms, genes),
+        (Err(err), _) | (_, Err(err)) => {
+            fail(format!("could not read the term or gene list: {}", err));
+        }
+    };
+
+    println!("Calibrating {} from {} individuals in {} ({} samples x {} draws, seed {})", name, population.len(), population_name, samples, draws, seed);
+    let params = match simpheny_background::fit_browns_params(ontology, population_name, &population, &all_term_list, &all_gene_list, num_hpo_terms, num_query_genes, samples, draws, seed) {
+        Some(params) => params,
+        None => fail("could not fit parameters, no two individuals of the population share a gene or the null has no variance"),
+    };
+    println!("scale: {}\ndof: {}", params.scale, params.dof);
+
+    let mut backgrounds = simpheny_background::load_backgrounds(crate::BACKGROUNDS_URL);
+    backgrounds.insert(name.to_string(), params);
+    match simpheny_background::save_backgrounds(crate::BACKGROUNDS_URL, &backgrounds) {
+        Ok(_) => println!("Saved to {}", crate::BACKGROUNDS_URL),
+        Err(err) => fail(err),
+    };
+}
+
+fn precompute_nulls(args: &[String], ontology: &Arc<Ontology>) {
+    // Population names are the arguments before the first flag
+    let population_names: Vec<&String> = args.iter().take_while(|a| !a.starts_with("--")).collect();
+    if population_names.is_empty() {
+        usage_error();
+    }
+    let draws: u32 = flag_value(args, "--draws").unwrap_or(1000);
+    let seed: u64 = flag_value(args, "--seed").unwrap_or(0);
+    let out: String = flag_value(args, "--out").unwrap_or_else(|| crate::NULLS_URL.to_string());
+
+    let all_term_list = match calc_simpheny_score::load_term_list(ontology, crate::TERMS_LIST_URL) {
+        Ok(terms) => terms,
+        Err(err) => fail(format!("could not read the term list: {}", err)),
+    };
+    // Add to an existing file with the same number of draws, ontology and term list, otherwise start over
+    let mut nulls = match simpheny_nulls::NullTable::read(&out, ontology, &all_term_list) {
+        Ok(existing) if existing.draws == draws => existing,
+        _ => simpheny_nulls::NullTable::new(draws, ontology, &all_term_list),
+    };
+
+    for population_name in population_names {
+        let population = load_population(population_name, ontology, None);
+        let profiles: Vec<Vec<u32>> = population.values().map(|individual| population::individual_terms(ontology, individual)).collect();
+        let before = nulls.len();
+        println!("Sampling nulls for {} individuals in {} ({} draws per query size)", profiles.len(), population_name, draws);
+        nulls.add_profiles(ontology, profiles, &all_term_list, seed);
+        println!("Added {} profiles", nulls.len() - before);
+    }
+
+    match nulls.write(&out) {
+        Ok(_) => println!("Saved {} profiles to {}", nulls.len(), out),
+        Err(err) => fail(err),
+    };
+}
+
+fn build_db(args: &[String]) {
+    let source_folder = match args.first().filter(|a| !a.starts_with("--")) {
+        Some(folder) => folder,
+        None => {
+            usage_error();
+        }
+    };
+    let out: String = flag_value(args, "--out").unwrap_or_else(crate::get_db_path);
+
+    println!("Building {} from {}", out, source_folder);
+    match build_db::build_db(source_folder, &out) {
+        Ok(summary) => {
+            println!("{} terms, {} genes, {} diseases", summary.terms, summary.genes, summary.diseases);
+            println!("{} term_to_gene rows, {} gene_to_disease rows", summary.term_to_gene, summary.gene_to_disease);
+            for (key, value) in &summary.metadata {
+                println!("{}: {}", key, value);
+            }
+        }
+        Err(err) => fail(err),
+    };
+}
+
+fn ingest(args: &[String], ontology: &Arc<Ontology>) {
+    let names: Vec<&str> = args.iter().map(|a| a.as_str()).filter(|a| !a.starts_with("--")).collect();
+    let names = if names.is_empty() { crate::POPULATION_NAMES.to_vec() } else { names };
+    let force = args.iter().any(|a| a == "--force");
+
+    let store = match population_store::PopulationStore::open(crate::POPULATION_STORE_URL) {
+        Ok(store) => store,
+        Err(err) => fail(format!("could not open the population store: {}", err)),
+    };
+    // Every population is attempted, a failure only decides the exit status
+    let mut failed = false;
+    for name in names {
+        match populations::ingest(ontology, &store, name, force) {
+            Ok((provenance, ingested)) => {
+                let state = if ingested { "ingested" } else { "unchanged" };
+                println!("{}: {} ({} rows, {} individuals, sha256 {})", name, state, provenance.row_count, provenance.individuals_stored, provenance.file_hash);
+            }
+            Err(err) => {
+                eprintln!("Error: {}: {}", name, err.message());
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn similarity_matrix(args: &[String], ontology: &Arc<Ontology>) {
+    let name = match args.first().filter(|a| !a.starts_with("--")) {
+        Some(name) => name,
+        None => {
+            usage_error();
+        }
+    };
+    let against: String = flag_value(args, "--against").unwrap_or_else(|| name.to_string());
+    let format_name: String = flag_value(args, "--format").unwrap_or_else(|| "neighbors".to_string());
+    let format = match similarity_matrix::MatrixFormat::parse(&format_name) {
+        Some(format) => format,
+        None => fail(format!("unknown format: {}, expected neighbors, tsv or binary", format_name)),
+    };
+    let top_n: usize = flag_value(args, "--top").unwrap_or(similarity_matrix::DEFAULT_TOP_N).max(1);
+    let out: String = flag_value(args, "--out").unwrap_or_else(|| format!("{}_{}.{}", name, against, format.extension()));
+
+    let diagnosis: Option<String> = flag_value(args, "--diagnosis");
+    let rows = similarity_matrix::Profiles::new(ontology, &load_population(name, ontology, diagnosis.as_deref()));
+    let columns = similarity_matrix::Profiles::new(ontology, &load_population(&against, ontology, diagnosis.as_deref()));
+
+    println!("Scoring {} individuals in {} against {} in {}", rows.len(), name, columns.len(), against);
+    let progress = |done: usize, total: usize| println!("{} of {} rows", done, total);
+    match similarity_matrix::compute(ontology, &rows, &columns, format, top_n, &out, &progress) {
+        Ok(summary) => println!("Saved a {} x {} {} matrix to {} ({} chunks resumed)", summary.rows, summary.columns, summary.format, summary.out, summary.resumed_chunks),
+        Err(err) => fail(err),
+    };
+}
+
+fn cluster(args: &[String], ontology: &Arc<Ontology>) {
+    let name = match args.first().filter(|a| !a.starts_with("--")) {
+        Some(name) => name,
+        None => {
+            usage_error();
+        }
+    };
+    let params = clustering::ClusterParams {
+        method: flag_value(args, "--method"),
+        top_n: flag_value(args, "--top"),
+        min_similarity: flag_value(args, "--min-similarity"),
+        clusters: flag_value(args, "--clusters"),
+        min_size: flag_value(args, "--min-size"),
+    };
+    let diagnosis: Option<String> = flag_value(args, "--diagnosis");
+    let population = load_population(name, ontology, diagnosis.as_deref());
+
+    let report = match clustering::cluster(ontology, &population, &params) {
+        Ok(report) => report,
+        Err(err) => fail(err.message()),
+    };
+    let json = serde_json::to_string_pretty(&report).expect("cluster reports serialize");
+    match flag_value::<String>(args, "--out") {
+        Some(out) => match std::fs::write(&out, json) {
+            Ok(_) => println!("Saved {} clusters of {} individuals to {}", report.clusters.len(), report.individuals, out),
+            Err(err) => fail(err),
+        },
+        None => println!("{}", json),
+    };
+}
+
+fn prioritize(args: &[String], ontology: &Arc<Ontology>) {
+    let (path, terms) = match (args.first().filter(|a| !a.starts_with("--")), flag_value::<String>(args, "--terms")) {
+        (Some(path), Some(terms)) => (path, terms),
+        _ => {
+            usage_error();
+        }
+    };
+    let params = vcf_prioritization::PrioritizeParams {
+        terms,
+        max_af: flag_value(args, "--max-af"),
+        impacts: flag_value(args, "--impacts"),
+        consequences: flag_value(args, "--consequences"),
+        pass_only: Some(!args.iter().any(|a| a == "--all-filters")),
+        phenotype_weight: flag_value(args, "--phenotype-weight"),
+        limit: flag_value(args, "--top"),
+    };
+    let vcf = match std::fs::read(path) {
+        Ok(vcf) => vcf,
+        Err(err) => fail(format!("{}: {}", path, err)),
+    };
+    let gene_index = gene_search::GeneIndex::load(crate::HGNC_URL).unwrap_or_else(|e| {
+        eprintln!("Warning: no HGNC table loaded from {}: {}", crate::HGNC_URL, e);
+        gene_search::GeneIndex::empty()
+    });
+
+    let report = match vcf_prioritization::prioritize(ontology, &gene_index, &params, &vcf) {
+        Ok(report) => report,
+        Err(err) => fail(err.message()),
+    };
+    let json = serde_json::to_string_pretty(&report).expect("prioritization reports serialize");
+    match flag_value::<String>(args, "--out") {
+        Some(out) => match std::fs::write(&out, json) {
+            Ok(_) => println!("Saved {} ranked genes of {} variants to {}", report.genes.len(), report.variants, out),
+            Err(err) => fail(err),
+        },
+        None => println!("{}", json),
+    };
+}
@@ -0,0 +1,531 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use hpo::Ontology;
+use crate::error::ApiError;
+use crate::population::{self, Population};
+use crate::similarity_matrix::{self, Profiles};
+use crate::term_enrichment::{Background, Correction, Test, TermEnrichment};
+
+// Hierarchical clustering holds every pairwise score in memory, larger cohorts have to use the neighbor graph
+pub const MAX_HIERARCHICAL: usize = 3000;
+
+// Clustering scores every pair of individuals, so the public route only takes cohorts up to this size. Larger ones
+// need the admin token or the cluster subcommand
+pub const MAX_UNAUTHENTICATED: usize = 500;
+
+// Terms and genes listed per cluster
+const MAX_ENRICHED_TERMS: usize = 10;
+const MAX_RECURRENT_GENES: usize = 10;
+
+// How a population is clustered, given as query parameters or CLI flags
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClusterParams {
+    pub method: Option<String>, // graph (default) or hierarchical
+    pub top_n: Option<usize>, // Neighbors per individual in the graph, default similarity_matrix::DEFAULT_TOP_N
+    pub min_similarity: Option<f32>, // Hierarchical merges stop below this average similarity, default 0.2
+    pub clusters: Option<usize>, // Hierarchical merges stop at this many clusters instead, when given
+    pub min_size: Option<usize>, // Smaller clusters are reported as unclustered, default 2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Graph, // Louvain communities of the top N neighbor graph
+    Hierarchical, // Average linkage over the full matrix
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClusterReport {
+    pub method: String,
+    pub individuals: usize,
+    pub clusters: Vec<Cluster>, // Largest first
+    pub unclustered: Vec<String>, // Individuals left in clusters under min_size
+}
+
+#[derive(Serialize, Debug)]
+pub struct Cluster {
+    pub id: usize, // Position in the report, 1 based
+    pub size: usize,
+    pub cohesion: f32, // Average similarity between the members
+    pub members: Vec<String>,
+    pub enriched_terms: Vec<TermEnrichment>, // Against the clustered population, q-values by Benjamini-Hochberg within the cluster
+    pub recurrent_genes: Vec<RecurrentGene>,
+}
+
+// A gene two or more of the cluster's members have
+#[derive(Serialize, Debug)]
+pub struct RecurrentGene {
+    pub gene: String,
+    pub count: usize,
+    pub members: Vec<String>,
+}
+
+impl ClusterParams {
+    pub fn method(&self) -> Result<Method, ApiError> {
+        match self.method.as_deref().map(|m| m.to_lowercase()).as_deref() {
+            None | Some("graph") => Ok(Method::Graph),
+            Some("hierarchical") => Ok(Method::Hierarchical),
+            Some(other) => Err(ApiError::BadRequest(format!("unknown method: {}, expected graph or hierarchical", other))),
+        }
+    }
+}
+
+// Groups the individuals of a population by phenotype similarity, and describes each group by the terms enriched
+// in it and the genes its members share
+pub fn cluster(ontology: &Arc<Ontology>, population: &Population, params: &ClusterParams) -> Result<ClusterReport, ApiError> {
+    let method = params.method()?;
+    let profiles = Profiles::new(ontology, population);
+    if profiles.len() == 0 {
+        return Err(ApiError::BadRequest("no individuals to cluster".to_string()));
+    }
+    if method == Method::Hierarchical && profiles.len() > MAX_HIERARCHICAL {
+        return Err(ApiError::BadRequest(format!("hierarchical clustering takes up to {} individuals, {} given, use method=graph", MAX_HIERARCHICAL, profiles.len())));
+    }
+
+    let (labels, scores) = match method {
+        Method::Graph => {
+            let top_n = params.top_n.unwrap_or(similarity_matrix::DEFAULT_TOP_N).max(1);
+            let neighbors = similarity_matrix::neighbor_lists(ontology, &profiles, &profiles, top_n);
+            (louvain(&Graph::from_neighbors(&neighbors)), Scores::Neighbors(neighbors))
+        }
+        Method::Hierarchical => {
+            let scores = similarity_matrix::dense(ontology, &profiles, &profiles);
+            let labels = average_linkage(&scores, params.min_similarity.unwrap_or(0.2), params.clusters.unwrap_or(1).max(1));
+            (labels, Scores::Dense(scores))
+        }
+    };
+
+    let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (individual, label) in labels.iter().enumerate() {
+        groups.entry(*label).or_default().push(individual);
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_values().collect();
+    // Largest first, ties by their first member so the order is stable
+    groups.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[0].cmp(&b[0])));
+
+    let min_size = params.min_size.unwrap_or(2).max(1);
+    let background = Background::new(ontology, &profiles.terms);
+    let mut report = ClusterReport { method: format!("{:?}", method).to_lowercase(), individuals: profiles.len(), clusters: Vec::new(), unclustered: Vec::new() };
+    for members in groups {
+        if members.len() < min_size {
+            report.unclustered.extend(members.iter().map(|m| profiles.ids[*m].clone()));
+            continue;
+        }
+        let ids: Vec<String> = members.iter().map(|m| profiles.ids[*m].clone()).collect();
+        report.clusters.push(Cluster {
+            id: report.clusters.len() + 1,
+            size: members.len(),
+            cohesion: scores.cohesion(&members),
+            enriched_terms: enriched_terms(ontology, &background, &members),
+            recurrent_genes: recurrent_genes(population, &ids),
+            members: ids,
+        });
+    }
+    report.unclustered.sort();
+    Ok(report)
+}
+
+// An undirected weighted graph. Edges between two nodes are listed under both, a node's edge to itself is kept
+// apart in loops
+struct Graph {
+    edges: Vec<Vec<(usize, f64)>>,
+    loops: Vec<f64>,
+}
+
+impl Graph {
+    // Links each individual to its neighbors, the weight being their similarity. A pair that lists each other is
+    // linked once
+    fn from_neighbors(neighbors: &[Vec<(usize, f32)>]) -> Graph {
+        let mut weights: HashMap<(usize, usize), f64> = HashMap::new();
+        for (node, list) in neighbors.iter().enumerate() {
+            for (other, score) in list {
+                if *other != node && *score > 0.0 {
+                    weights.entry((node.min(*other), node.max(*other))).or_insert(*score as f64);
+                }
+            }
+        }
+        let mut edges = vec![Vec::new(); neighbors.len()];
+        let mut pairs: Vec<_> = weights.into_iter().collect();
+        pairs.sort_by_key(|(pair, _)| *pair);
+        for ((a, b), weight) in pairs {
+            edges[a].push((b, weight));
+            edges[b].push((a, weight));
+        }
+        Graph { loops: vec![0.0; neighbors.len()], edges }
+    }
+
+    fn len(&self) -> usize {
+        self.edges.len()
+    }
+
+    // Sum of the weights at a node, a loop counting at both ends
+    fn degree(&self, node: usize) -> f64 {
+        self.edges[node].iter().map(|(_, w)| w).sum::<f64>() + 2.0 * self.loops[node]
+    }
+
+    // One node per community, the weights between communities summed and those inside one becoming its loop
+    fn aggregate(&self, communities: &[usize], count: usize) -> Graph {
+        let mut weights: Vec<BTreeMap<usize, f64>> = vec![BTreeMap::new(); count];
+        let mut loops = vec![0.0; count];
+        for node in 0..self.len() {
+            let community = communities[node];
+            loops[community] += self.loops[node];
+            for (other, weight) in &self.edges[node] {
+                if communities[*other] == community {
+                    // Seen from both ends
+                    loops[community] += weight / 2.0;
+                } else {
+                    *weights[community].entry(communities[*other]).or_insert(0.0) += weight;
+                }
+            }
+        }
+        Graph { edges: weights.into_iter().map(|w| w.into_iter().collect()).collect(), loops }
+    }
+}
+
+// Louvain community detection: nodes move to the neighboring community that raises modularity most until none
+// does, then each community becomes a node and it starts over, until nothing moves. Nodes are visited in order so
+// the result is the same on every run. Returns a community per node, isolated nodes each get their own
+fn louvain(graph: &Graph) -> Vec<usize> {
+    let mut membership: Vec<usize> = (0..graph.len()).collect();
+    let mut level = Graph { edges: graph.edges.clone(), loops: graph.loops.clone() };
+    loop {
+        let (communities, count, moved) = move_nodes(&level);
+        for community in membership.iter_mut() {
+            *community = communities[*community];
+        }
+        if !moved || count == level.len() {
+            return membership;
+        }
+        level = level.aggregate(&communities, count);
+    }
+}
+
+// One Louvain level. Returns each node's community numbered from 0, the number of communities, and whether any
+// node moved
+fn move_nodes(graph: &Graph) -> (Vec<usize>, usize, bool) {
+    let degrees: Vec<f64> = (0..graph.len()).map(|node| graph.degree(node)).collect();
+    let total: f64 = degrees.iter().sum();
+    let mut community: Vec<usize> = (0..graph.len()).collect();
+    if total == 0.0 {
+        return (community, graph.len(), false);
+    }
+    let mut community_degree = degrees.clone();
+    let mut moved_any = false;
+
+    loop {
+        let mut moved = false;
+        for node in 0..graph.len() {
+            let current = community[node];
+            let mut links: BTreeMap<usize, f64> = BTreeMap::new();
+            for (other, weight) in &graph.edges[node] {
+                *links.entry(community[*other]).or_insert(0.0) += weight;
+            }
+            community_degree[current] -= degrees[node];
+
+            // The gain of joining a community, up to a factor that is the same for every community
+            let gain = |c: usize, link: f64| link - community_degree[c] * degrees[node] / total;
+            let mut best = current;
+            let mut best_gain = gain(current, links.get(&current).copied().unwrap_or(0.0));
+            for (c, link) in &links {
+                let g = gain(*c, *link);
+                if g > best_gain + 1e-12 {
+                    best = *c;
+                    best_gain = g;
+                }
+            }
+
+            community_degree[best] += degrees[node];
+            if best != current {
+                community[node] = best;
+                moved = true;
+                moved_any = true;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    let mut numbers: HashMap<usize, usize> = HashMap::new();
+    for c in community.iter_mut() {
+        let next = numbers.len();
+        *c = *numbers.entry(*c).or_insert(next);
+    }
+    (community, numbers.len(), moved_any)
+}
+
+// Average linkage agglomerative clustering: the two clusters with the highest average similarity between their
+// members are merged until that falls below min_similarity or only target clusters are left. Each cluster keeps
+// its most similar other cluster so finding the next merge doesn't rescan the whole matrix
+fn average_linkage(scores: &[Vec<f32>], min_similarity: f32, target: usize) -> Vec<usize> {
+    let n = scores.len();
+    let mut similarity: Vec<Vec<f32>> = scores.to_vec();
+    let mut size = vec![1usize; n];
+    let mut active = vec![true; n];
+    let mut labels: Vec<usize> = (0..n).collect();
+    let mut remaining = n;
+
+    let best_of = |similarity: &Vec<Vec<f32>>, active: &Vec<bool>, i: usize| -> Option<(usize, f32)> {
+        (0..n)
+            .filter(|j| *j != i && active[*j])
+            .map(|j| (j, similarity[i][j]))
+            .fold(None, |best: Option<(usize, f32)>, (j, s)| match best {
+                Some((_, b)) if b >= s => best,
+                _ => Some((j, s)),
+            })
+    };
+    let mut nearest: Vec<Option<(usize, f32)>> = (0..n).map(|i| best_of(&similarity, &active, i)).collect();
+
+    while remaining > target {
+        let merge = (0..n)
+            .filter(|i| active[*i])
+            .filter_map(|i| nearest[i].map(|(j, s)| (i, j, s)))
+            .fold(None, |best: Option<(usize, usize, f32)>, candidate| match best {
+                Some((_, _, b)) if b >= candidate.2 => best,
+                _ => Some(candidate),
+            });
+        let (a, b) = match merge {
+            Some((a, b, s)) if s >= min_similarity => (a, b),
+            _ => break,
+        };
+
+        // b joins a, a's similarity to everyone else becomes the size weighted average of the two
+        for k in 0..n {
+            if active[k] && k != a && k != b {
+                let merged = (size[a] as f32 * similarity[a][k] + size[b] as f32 * similarity[b][k]) / (size[a] + size[b]) as f32;
+                similarity[a][k] = merged;
+                similarity[k][a] = merged;
+            }
+        }
+        size[a] += size[b];
+        active[b] = false;
+        remaining -= 1;
+        for label in labels.iter_mut() {
+            if *label == b {
+                *label = a;
+            }
+        }
+
+        // The merged similarity to k lies between a's and b's old ones, so it can rise above a's but never above
+        // the larger of the two. A cluster whose nearest was neither a nor b already had a best at least that
+        // large and keeps it, only a and the clusters that pointed at a or b need a rescan
+        for k in 0..n {
+            if !active[k] {
+                continue;
+            }
+            let stale = k == a || nearest[k].is_some_and(|(j, _)| j == a || j == b);
+            if stale {
+                nearest[k] = best_of(&similarity, &active, k);
+            } else if nearest[k].is_some_and(|(_, best)| similarity[k][a] > best) {
+                nearest[k] = Some((a, similarity[k][a]));
+            }
+        }
+    }
+    labels
+}
+
+// The scores a clustering was made from
+enum Scores {
+    Neighbors(Vec<Vec<(usize, f32)>>),
+    Dense(Vec<Vec<f32>>),
+}
+
+impl Scores {
+    // Average similarity between the members of a cluster. From neighbor lists, pairs that aren't each other's
+    // neighbors count as 0
+    fn cohesion(&self, members: &[usize]) -> f32 {
+        if members.len() < 2 {
+            return 1.0;
+        }
+        let mut pairs: HashMap<(usize, usize), f32> = HashMap::new();
+        match self {
+            Scores::Neighbors(neighbors) => {
+                let set: HashSet<usize> = members.iter().copied().collect();
+                for member in members {
+                    for (other, score) in neighbors[*member].iter().filter(|(other, _)| set.contains(other)) {
+                        pairs.insert((*member.min(other), *member.max(other)), *score);
+                    }
+                }
+            }
+            Scores::Dense(scores) => {
+                for (idx, a) in members.iter().enumerate() {
+                    for b in &members[idx + 1..] {
+                        pairs.insert((*a, *b), scores[*a][*b]);
+                    }
+                }
+            }
+        }
+        pairs.values().sum::<f32>() / (members.len() * (members.len() - 1) / 2) as f32
+    }
+}
+
+// Terms at least two members share that are over-represented in the cluster, most significant first
+fn enriched_terms(ontology: &Ontology, background: &Background, members: &[usize]) -> Vec<TermEnrichment> {
+    let mut enriched: Vec<TermEnrichment> = background.test(ontology, members, Test::Hypergeometric, Correction::BenjaminiHochberg, 2.min(members.len()))
+        .into_iter()
+        .filter(|term| term.fold > 1.0)
+        .collect();
+    enriched.truncate(MAX_ENRICHED_TERMS);
+    enriched
+}
+
+// Genes at least two members have, most shared first
+fn recurrent_genes(population: &Population, members: &[String]) -> Vec<RecurrentGene> {
+    let mut genes: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for member in members {
+        let individual_genes: HashSet<String> = population.get(member)
+            .map(|individual| population::individual_genes(individual).iter().map(|gene| gene.to_uppercase()).collect())
+            .unwrap_or_default();
+        for gene in individual_genes {
+            genes.entry(gene).or_default().push(member.clone());
+        }
+    }
+    let mut recurrent: Vec<RecurrentGene> = genes.into_iter()
+        .filter(|(_, members)| members.len() >= 2)
+        .map(|(gene, members)| RecurrentGene { gene, count: members.len(), members })
+        .collect();
+    recurrent.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.gene.cmp(&b.gene)));
+    recurrent.truncate(MAX_RECURRENT_GENES);
+    recurrent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand_chacha::ChaCha8Rng;
+
+    // Each node's cluster numbered by first appearance, so two labelings of the same partition compare equal
+    fn partition(labels: &[usize]) -> Vec<usize> {
+        let mut numbers: HashMap<usize, usize> = HashMap::new();
+        labels.iter().map(|label| {
+            let next = numbers.len();
+            *numbers.entry(*label).or_insert(next)
+        }).collect()
+    }
+
+    // Average linkage the slow way, averaging the original scores between every pair of clusters before each merge
+    fn naive_average_linkage(scores: &[Vec<f32>], min_similarity: f32, target: usize) -> Vec<usize> {
+        let mut clusters: Vec<Vec<usize>> = (0..scores.len()).map(|i| vec![i]).collect();
+        while clusters.len() > target {
+            let mut best: Option<(usize, usize, f64)> = None;
+            for i in 0..clusters.len() {
+                for j in i + 1..clusters.len() {
+                    let total: f64 = clusters[i].iter().flat_map(|a| clusters[j].iter().map(move |b| scores[*a][*b] as f64)).sum();
+                    let average = total / (clusters[i].len() * clusters[j].len()) as f64;
+                    if best.is_none_or(|(_, _, b)| average > b) {
+                        best = Some((i, j, average));
+                    }
+                }
+            }
+            match best {
+                Some((i, j, average)) if average >= min_similarity as f64 => {
+                    let merged = clusters.remove(j);
+                    clusters[i].extend(merged);
+                }
+                _ => break,
+            }
+        }
+        let mut labels = vec![0; scores.len()];
+        for (label, members) in clusters.iter().enumerate() {
+            for member in members {
+                labels[*member] = label;
+            }
+        }
+        labels
+    }
+
+    fn symmetric(n: usize, pairs: &[(usize, usize, f32)]) -> Vec<Vec<f32>> {
+        let mut scores = vec![vec![0.0; n]; n];
+        for (a, b, score) in pairs {
+            scores[*a][*b] = *score;
+            scores[*b][*a] = *score;
+        }
+        scores
+    }
+
+    #[test]
+    fn louvain_splits_two_triangles_joined_by_a_weak_edge() {
+        // 0-1-2 and 3-4-5 are triangles, 2-3 a weak bridge and 6 has no neighbors
+        let neighbors = vec![
+            vec![(1, 1.0), (2, 1.0)],
+            vec![(0, 1.0), (2, 1.0)],
+            vec![(0, 1.0), (1, 1.0), (3, 0.1)],
+            vec![(4, 1.0), (5, 1.0), (2, 0.1)],
+            vec![(3, 1.0), (5, 1.0)],
+            vec![(3, 1.0), (4, 1.0)],
+            vec![],
+        ];
+        let communities = louvain(&Graph::from_neighbors(&neighbors));
+        assert_eq!(partition(&communities), vec![0, 0, 0, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn louvain_joins_triangles_only_when_densely_linked() {
+        // Four triangles in a ring, each linked to the next by one weak edge, stay apart
+        let mut pairs = Vec::new();
+        for t in 0..4 {
+            let base = t * 3;
+            pairs.extend([(base, base + 1, 1.0), (base + 1, base + 2, 1.0), (base, base + 2, 1.0)]);
+            pairs.push((base + 2, (base + 3) % 12, 0.05));
+        }
+        let mut neighbors = vec![Vec::new(); 12];
+        for (a, b, score) in pairs {
+            neighbors[a].push((b, score));
+            neighbors[b].push((a, score));
+        }
+        let communities = louvain(&Graph::from_neighbors(&neighbors));
+        assert_eq!(partition(&communities), vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 3, 3, 3]);
+
+        // Four strong edges between the first two triangles and between the last two join each pair
+        for (a, b) in [(0, 4), (0, 5), (1, 4), (1, 5), (6, 10), (6, 11), (7, 10), (7, 11)] {
+            neighbors[a].push((b, 1.0));
+            neighbors[b].push((a, 1.0));
+        }
+        let communities = louvain(&Graph::from_neighbors(&neighbors));
+        assert_eq!(partition(&communities), vec![0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn louvain_leaves_an_edgeless_graph_apart() {
+        let communities = louvain(&Graph::from_neighbors(&[vec![], vec![], vec![]]));
+        assert_eq!(partition(&communities), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn average_linkage_stops_at_min_similarity_or_target() {
+        // {0, 1} and {2, 3} are close, the two pairs far apart
+        let scores = symmetric(4, &[(0, 1, 0.9), (2, 3, 0.8), (0, 2, 0.1), (0, 3, 0.2), (1, 2, 0.1), (1, 3, 0.2)]);
+        assert_eq!(partition(&average_linkage(&scores, 0.5, 1)), vec![0, 0, 1, 1]);
+        assert_eq!(partition(&average_linkage(&scores, 0.0, 1)), vec![0, 0, 0, 0]);
+        assert_eq!(partition(&average_linkage(&scores, 0.0, 3)), vec![0, 0, 1, 2]);
+        assert_eq!(partition(&average_linkage(&scores, 0.95, 1)), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn average_linkage_follows_a_merged_average_that_rises() {
+        // Merging 0 and 1 raises the cluster's similarity to 2 from 0.1 to 0.4, while 2's nearest stays 3
+        let scores = symmetric(4, &[(0, 1, 0.9), (0, 2, 0.1), (1, 2, 0.7), (2, 3, 0.75)]);
+        assert_eq!(partition(&average_linkage(&scores, 0.3, 1)), vec![0, 0, 1, 1]);
+        // {0, 1} to {2, 3} averages (0.1 + 0.7 + 0 + 0) / 4
+        assert_eq!(partition(&average_linkage(&scores, 0.2, 1)), vec![0, 0, 0, 0]);
+        assert_eq!(partition(&average_linkage(&scores, 0.21, 1)), vec![0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn average_linkage_matches_a_full_rescan() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        for _ in 0..50 {
+            let n = rng.random_range(2..16);
+            let pairs: Vec<(usize, usize, f32)> = (0..n).flat_map(|a| (a + 1..n).map(move |b| (a, b))).map(|(a, b)| (a, b, rng.random())).collect();
+            let scores = symmetric(n, &pairs);
+            let min_similarity = rng.random_range(0.0..0.6);
+            let target = rng.random_range(1..=n);
+            assert_eq!(
+                partition(&average_linkage(&scores, min_similarity, target)),
+                partition(&naive_average_linkage(&scores, min_similarity, target)),
+            );
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use serde::Deserialize;
+use hpo::annotations::AnnotationId;
+use hpo::{HpoTermId, Ontology};
+use crate::error::ApiError;
+use crate::ontology_nav;
+use crate::population::Population;
+use crate::populations;
+use crate::population_store::{CohortQuery, PopulationStore};
+
+// The filters the population and compare GET routes take in their query string, lists are comma separated
+#[derive(Deserialize, Debug, Default)]
+pub struct FilterParams {
+    pub diagnosis: Option<String>, // diagnosed, undiagnosed or any
+    pub genes: Option<String>,
+    pub has_terms: Option<String>,
+    pub attributes: Option<String>, // e.g. site:SLC,age>5
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Equals, // key:value
+    NotEquals, // key!:value
+    Contains, // key~value
+    GreaterThan, // key>number
+    LessThan, // key<number
+}
+
+// A condition on one column of an individual, text comparisons ignore case
+#[derive(Debug, Clone)]
+struct AttributePredicate {
+    key: String,
+    op: Op,
+    value: String,
+}
+
+// Which individuals of a population are candidates for a compare. Every condition has to hold, a condition
+// that isn't given matches everyone
+#[derive(Debug, Clone, Default)]
+pub struct CohortFilter {
+    diagnosis: Option<String>, // The Dx/Udx value, None for any
+    genes: HashSet<String>, // Has any of these genes, uppercased
+    has_terms: Vec<HpoTermId>, // Has any of these terms or a term below one of them
+    attributes: Vec<AttributePredicate>,
+}
+
+impl CohortFilter {
+    // Without a diagnosis the population's default applies, so UDN still only matches diagnosed patients unless
+    // diagnosis=any or diagnosis=undiagnosed is asked for
+    pub fn new(ontology: &Ontology, population: &str, diagnosis: Option<&str>, genes: &[String], has_terms: &[String], attributes: &[String]) -> Result<CohortFilter, ApiError> {
+        let diagnosis = match diagnosis.map(|d| d.trim().to_lowercase()) {
+            None => populations::default_diagnosis(population),
+            Some(d) if d == "diagnosed" => Some("Diagnosed".to_string()),
+            Some(d) if d == "undiagnosed" => Some("Undiagnosed".to_string()),
+            Some(d) if d == "any" || d.is_empty() => None,
+            Some(d) => return Err(ApiError::BadRequest(format!("invalid diagnosis: {}, expected diagnosed, undiagnosed or any", d))),
+        };
+        let has_terms = has_terms.iter()
+            .filter(|id| !id.trim().is_empty())
+            .map(|id| ontology_nav::find_term(ontology, id).map(|term| term.id()))
+            .collect::<Result<Vec<HpoTermId>, ApiError>>()?;
+        let attributes = attributes.iter()
+            .filter(|predicate| !predicate.trim().is_empty())
+            .map(|predicate| parse_predicate(predicate))
+            .collect::<Result<Vec<AttributePredicate>, ApiError>>()?;
+
+        Ok(CohortFilter {
+            diagnosis,
+            genes: genes.iter().map(|gene| gene.trim().to_uppercase()).filter(|gene| !gene.is_empty()).collect(),
+            has_terms,
+            attributes,
+        })
+    }
+
+    pub fn from_params(ontology: &Ontology, population: &str, params: &FilterParams) -> Result<CohortFilter, ApiError> {
+        let list = |value: &Option<String>| -> Vec<String> {
+            value.as_deref().unwrap_or("").split(',').map(|s| s.to_string()).collect()
+        };
+        CohortFilter::new(ontology, population, params.diagnosis.as_deref(), &list(&params.genes), &list(&params.has_terms), &list(&params.attributes))
+    }
+
+    // The individuals of a population that match. Diagnosis, genes and terms are a query on the population store's
+    // indexes, attributes are checked on the individuals it returns. Ids the store has that population doesn't, from
+    // a reload after the snapshot was taken, are left out. When every individual matches the population is shared
+    // as it is rather than copied
+    pub fn apply(&self, ontology: &Ontology, store: &PopulationStore, name: &str, population: &Arc<Population>) -> Result<Arc<Population>, ApiError> {
+        if self.is_empty() {
+            return Ok(Arc::clone(population));
+        }
+        let queried = if self.diagnosis.is_some() || !self.genes.is_empty() || !self.has_terms.is_empty() {
+            let query = CohortQuery {
+                diagnosis: self.diagnosis.clone(),
+                genes: self.genes.iter().cloned().collect(),
+                term_ids: self.has_terms.iter().flat_map(|term| descendants(ontology, *term)).collect(),
+            };
+            Some(store.cohort(name, &query).map_err(|e| ApiError::Internal(format!("population store error: {}", e)))?)
+        } else {
+            None
+        };
+
+        let matching: Vec<&String> = population.iter()
+            .filter(|(id, _)| queried.as_ref().is_none_or(|ids| ids.contains(*id)))
+            .filter(|(_, individual)| {
+                self.attributes.iter().all(|predicate| predicate.matches(individual.get(&predicate.key).map(|v| v.as_str()).unwrap_or("")))
+            })
+            .map(|(id, _)| id)
+            .collect();
+        if matching.len() == population.len() {
+            return Ok(Arc::clone(population));
+        }
+        Ok(Arc::new(matching.into_iter().map(|id| (id.clone(), population[id].clone())).collect()))
+    }
+
+    // True when nothing narrows the population down
+    pub fn is_empty(&self) -> bool {
+        self.diagnosis.is_none() && self.genes.is_empty() && self.has_terms.is_empty() && self.attributes.is_empty()
+    }
+
+    // The population's default cohort, what a route matches against when no filter is given
+    pub fn default_for(population: &str) -> CohortFilter {
+        CohortFilter { diagnosis: populations::default_diagnosis(population), ..Default::default() }
+    }
+
+    pub fn is_default_for(&self, population: &str) -> bool {
+        self.genes.is_empty() && self.has_terms.is_empty() && self.attributes.is_empty() && self.diagnosis == populations::default_diagnosis(population)
+    }
+}
+
+impl AttributePredicate {
+    fn matches(&self, actual: &str) -> bool {
+        let actual = actual.trim();
+        match self.op {
+            Op::Equals => actual.eq_ignore_ascii_case(&self.value),
+            Op::NotEquals => !actual.eq_ignore_ascii_case(&self.value),
+            Op::Contains => actual.to_lowercase().contains(&self.value.to_lowercase()),
+            // Values that aren't numbers never match a numeric comparison
+            Op::GreaterThan | Op::LessThan => match (actual.parse::<f64>(), self.value.parse::<f64>()) {
+                (Ok(actual), Ok(value)) => if self.op == Op::GreaterThan { actual > value } else { actual < value },
+                _ => false,
+            },
+        }
+    }
+}
+
+// A term and every term below it, as ids
+fn descendants(ontology: &Ontology, term: HpoTermId) -> Vec<u32> {
+    let mut found: HashSet<u32> = HashSet::from([term.as_u32()]);
+    let mut todo: Vec<HpoTermId> = vec![term];
+    while let Some(id) = todo.pop() {
+        for child in ontology.hpo(id).into_iter().flat_map(|term| term.children()) {
+            if found.insert(child.id().as_u32()) {
+                todo.push(child.id());
+            }
+        }
+    }
+    found.into_iter().collect()
+}
+
+// Reads "key:value", "key!:value", "key~value", "key>number" or "key<number", using the first operator found
+fn parse_predicate(predicate: &str) -> Result<AttributePredicate, ApiError> {
+    let operators = [("!:", Op::NotEquals), (":", Op::Equals), ("~", Op::Contains), (">", Op::GreaterThan), ("<", Op::LessThan)];
+    let found = operators.iter()
+        .filter_map(|(token, op)| predicate.find(token).map(|idx| (idx, *token, *op)))
+        .min_by_key(|(idx, token, _)| (*idx, std::cmp::Reverse(token.len())));
+    let (idx, token, op) = found.ok_or_else(|| ApiError::BadRequest(format!("invalid attribute filter: {}, expected key:value, key!:value, key~value, key>number or key<number", predicate)))?;
+
+    let key = predicate[..idx].trim().to_string();
+    let value = predicate[idx + token.len()..].trim().to_string();
+    if key.is_empty() {
+        return Err(ApiError::BadRequest(format!("invalid attribute filter: {}, the key is missing", predicate)));
+    }
+    if matches!(op, Op::GreaterThan | Op::LessThan) && value.parse::<f64>().is_err() {
+        return Err(ApiError::BadRequest(format!("invalid attribute filter: {}, {} needs a number", predicate, token)));
+    }
+    Ok(AttributePredicate { key, op, value })
+}
@@ -4,6 +4,7 @@ use hpo::similarity::Similarity;
 pub struct CustomJaccardIC {}
 
 impl Similarity for CustomJaccardIC {
+    #[allow(clippy::needless_return)]
     fn calculate(&self, a: &HpoTerm, b: &HpoTerm) -> f32 {
         //get the sum of the information content of each term in the union and intersection
         let union = a.union_ancestors(b);
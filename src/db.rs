@@ -0,0 +1,477 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::time::Duration;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Result, Row};
+use rusqlite::types::Value;
+use serde::Serialize;
+
+// Tables the routes query, /check_db reports these missing as unhealthy
+const EXPECTED_TABLES: [&str; 5] = ["Terms", "Genes", "Diseases", "term_to_gene", "gene_to_disease"];
+
+// Hands out read-only connections to hpo.db for the r2d2 pool
+pub struct SqliteConnectionManager {
+    path: String,
+}
+
+impl r2d2::ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection> {
+        // Read-only also means a missing file fails here instead of an empty db being created
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        conn.set_prepared_statement_cache_capacity(32);
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<()> {
+        conn.query_row("SELECT 1", [], |_| Ok(()))
+    }
+
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Pool(r2d2::Error),
+    Sqlite(rusqlite::Error),
+    Task(tokio::task::JoinError),
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DbError::Pool(e) => write!(f, "db connection error: {}", e),
+            DbError::Sqlite(e) => write!(f, "db query error: {}", e),
+            DbError::Task(e) => write!(f, "db task error: {}", e),
+        }
+    }
+}
+
+// A pool of read-only connections, queries are run on the blocking thread pool so they never stall the runtime
+pub struct Db {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TableInfo {
+    pub name: String,
+    pub rows: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DbHealth {
+    pub ok: bool,
+    pub path: String,
+    pub tables: Vec<TableInfo>,
+    pub missing_tables: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl Db {
+    // Connections are made lazily so the server still starts, and reports the problem, when the db is missing
+    pub fn open(path: &str) -> Db {
+        let manager = SqliteConnectionManager { path: path.to_string() };
+        let pool = r2d2::Pool::builder()
+            .max_size(8)
+            .connection_timeout(Duration::from_secs(5))
+            .build_unchecked(manager);
+        Db { pool }
+    }
+
+    pub async fn run<T, F>(&self, query: F) -> Result<T, DbError>
+    where
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get().map_err(DbError::Pool)?;
+            query(&conn).map_err(DbError::Sqlite)
+        })
+        .await
+        .map_err(DbError::Task)?
+    }
+
+    // Quick probe that a connection can be checked out and answer a query
+    pub async fn ping(&self) -> Result<(), DbError> {
+        self.run(|conn| conn.query_row("SELECT 1", [], |_| Ok(()))).await
+    }
+
+    // Schema and row counts, so a db that opens but is empty or from an old build is caught
+    pub async fn health(&self, path: &str) -> DbHealth {
+        let tables = self.run(|conn| {
+            let mut stmt = conn.prepare_cached("SELECT name FROM sqlite_master WHERE type='table' ORDER BY name")?;
+            let names = stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<String>>>()?;
+            names.into_iter().map(|name| {
+                let rows = conn.query_row(&format!("SELECT COUNT(*) FROM \"{}\"", name.replace('"', "\"\"")), [], |row| row.get(0))?;
+                Ok(TableInfo { name, rows })
+            }).collect::<Result<Vec<TableInfo>>>()
+        }).await;
+
+        match tables {
+            Ok(tables) => {
+                let missing_tables: Vec<String> = EXPECTED_TABLES
+                    .iter()
+                    .filter(|expected| !tables.iter().any(|t| t.name.eq_ignore_ascii_case(expected)))
+                    .map(|t| t.to_string())
+                    .collect();
+                DbHealth { ok: missing_tables.is_empty(), path: path.to_string(), tables, missing_tables, error: None }
+            }
+            Err(e) => DbHealth {
+                ok: false,
+                path: path.to_string(),
+                tables: Vec::new(),
+                missing_tables: EXPECTED_TABLES.iter().map(|t| t.to_string()).collect(),
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+// Row types. Every column other than the key can be NULL in hpo.db, so they are all optional
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Term {
+    pub hpo_id: String,
+    pub name: Option<String>,
+    pub definition: Option<String>,
+    pub comment: Option<String>,
+    pub synonyms: Option<String>, // As stored, see synonym_list
+}
+
+impl Term {
+    // build-db stores synonyms as a JSON array since they can contain commas. Older dbs have them comma separated
+    pub fn synonym_list(&self) -> Vec<String> {
+        let Some(synonyms) = self.synonyms.as_deref() else { return Vec::new() };
+        let synonyms: Vec<String> = match serde_json::from_str::<Vec<String>>(synonyms) {
+            Ok(list) => list,
+            Err(_) => synonyms.split(',').map(|s| s.to_string()).collect(),
+        };
+        synonyms.into_iter().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Gene {
+    pub gene_id: Option<u32>, // NCBI gene id
+    pub gene_symbol: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Disease {
+    pub disease_id: String, // OMIM:... or ORPHA:...
+    pub source: Option<String>,
+    pub disease_name: Option<String>,
+}
+
+// A term annotated to a disease
+#[derive(Serialize, Debug, Clone)]
+pub struct DiseaseTerm {
+    pub term_id: String,
+    pub name: Option<String>,
+    pub frequency: Option<f64>, // As a fraction, see parse_frequency
+}
+
+// A gene linked to a disease, through gene_to_disease or its term annotations
+#[derive(Serialize, Debug, Clone)]
+pub struct DiseaseGene {
+    pub gene_id: Option<u32>,
+    pub gene_symbol: Option<String>,
+    pub association_type: Option<String>, // From gene_to_disease, e.g. MENDELIAN. None when only the annotations link them
+}
+
+// A row of term_to_gene joined with the names of what it links
+#[derive(Serialize, Debug, Clone)]
+pub struct TermGeneAssociation {
+    pub term_id: Option<String>,
+    pub gene_id: Option<u32>,
+    pub frequency: Option<f64>, // As a fraction, see parse_frequency
+    pub disease_id: Option<String>,
+    pub gene_symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>, // The term name, only joined when looking up by gene
+    pub disease_name: Option<String>,
+}
+
+const TERM_COLUMNS: &str = "term_id, name, definition, comment, synonyms";
+const GENE_COLUMNS: &str = "gene_id, gene_symbol";
+const DISEASE_COLUMNS: &str = "disease_id, source, disease_name";
+
+fn term_from_row(row: &Row) -> Result<Term> {
+    Ok(Term {
+        hpo_id: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+        name: row.get(1)?,
+        definition: row.get(2)?,
+        comment: row.get(3)?,
+        synonyms: row.get(4)?,
+    })
+}
+
+fn gene_from_row(row: &Row) -> Result<Gene> {
+    Ok(Gene {
+        gene_id: numeric_id(row, 0)?,
+        gene_symbol: row.get(1)?,
+    })
+}
+
+fn disease_from_row(row: &Row) -> Result<Disease> {
+    Ok(Disease {
+        disease_id: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+        source: row.get(1)?,
+        disease_name: row.get(2)?,
+    })
+}
+
+// Gene ids are stored as TEXT but may come through as integers, and some sources prefix them with NCBIGene:
+fn numeric_id(row: &Row, idx: usize) -> Result<Option<u32>> {
+    Ok(match row.get::<_, Value>(idx)? {
+        Value::Integer(id) => u32::try_from(id).ok(),
+        Value::Text(id) => parse_gene_id(&id),
+        _ => None,
+    })
+}
+
+pub fn parse_gene_id(gene_id: &str) -> Option<u32> {
+    gene_id.trim().trim_start_matches("NCBIGene:").parse::<u32>().ok()
+}
+
+// Frequencies in the HPO annotations are a count ("3/5"), a percentage ("40%") or a frequency term.
+// Frequency terms are given the midpoint of the range they stand for
+pub fn parse_frequency(frequency: &str) -> Option<f64> {
+    let frequency = frequency.trim();
+    if let Some((n, d)) = frequency.split_once('/') {
+        let (n, d) = (n.trim().parse::<f64>().ok()?, d.trim().parse::<f64>().ok()?);
+        return if d > 0.0 { Some(n / d) } else { None };
+    }
+    if let Some(percent) = frequency.strip_suffix('%') {
+        return percent.trim().parse::<f64>().ok().map(|p| p / 100.0);
+    }
+    match frequency {
+        "HP:0040280" => Some(1.0), // Obligate
+        "HP:0040281" => Some(0.895), // Very frequent, 80-99%
+        "HP:0040282" => Some(0.545), // Frequent, 30-79%
+        "HP:0040283" => Some(0.17), // Occasional, 5-29%
+        "HP:0040284" => Some(0.025), // Very rare, 1-4%
+        "HP:0040285" => Some(0.0), // Excluded
+        _ => None,
+    }
+}
+
+fn association_from_row(row: &Row, with_name: bool) -> Result<TermGeneAssociation> {
+    let frequency: Option<String> = row.get(2)?;
+    Ok(TermGeneAssociation {
+        term_id: row.get(0)?,
+        gene_id: numeric_id(row, 1)?,
+        frequency: frequency.as_deref().and_then(parse_frequency),
+        disease_id: row.get(3)?,
+        gene_symbol: row.get(4)?,
+        name: if with_name { row.get(5)? } else { None },
+        disease_name: row.get(if with_name { 6 } else { 5 })?,
+    })
+}
+
+pub fn get_gene_by_id(conn: &Connection, gene_id: String) -> Result<Option<Gene>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Genes WHERE gene_id=?", GENE_COLUMNS))?;
+    //there should only be one gene returned
+    stmt.query_row([&gene_id], gene_from_row).optional()
+}
+
+pub fn get_gene_by_name(conn: &Connection, gene_name: String) -> Result<Option<Gene>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Genes WHERE gene_symbol COLLATE NOCASE LIKE ?", GENE_COLUMNS))?;
+    //there should only be one gene returned
+    stmt.query_row([&gene_name], gene_from_row).optional()
+}
+
+pub fn term_exists(conn: &Connection, term_id: &str) -> Result<bool> {
+    let mut stmt = conn.prepare_cached("SELECT 1 FROM Terms WHERE term_id=?")?;
+    stmt.exists([term_id])
+}
+
+pub fn gene_exists(conn: &Connection, gene_id: &str) -> Result<bool> {
+    let mut stmt = conn.prepare_cached("SELECT 1 FROM Genes WHERE gene_id=?")?;
+    stmt.exists([gene_id])
+}
+
+pub fn get_genes_from_names(conn: &Connection, gene_names: Vec<String>) -> Result<Vec<Gene>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Genes WHERE gene_symbol COLLATE NOCASE LIKE ?", GENE_COLUMNS))?;
+    let mut genes: Vec<Gene> = Vec::new();
+    for gene_name in gene_names {
+        let mut gene_iter = stmt.query_map([&gene_name], gene_from_row)?;
+
+        //there should only be one gene returned for each gene name
+        let gene = match gene_iter.next() {
+            Some(gene) => gene?,
+            None => Gene { gene_id: None, gene_symbol: Some(gene_name.to_string()) },
+        };
+        genes.push(gene);
+    }
+    Ok(genes)
+}
+
+pub fn get_genes_for_term(conn: &Connection, term_id: String) -> Result<Vec<TermGeneAssociation>> {
+    let mut stmt = conn.prepare_cached(r#"
+        SELECT term_to_gene.term_id, term_to_gene.gene_id, term_to_gene.frequency, term_to_gene.disease_id, genes.gene_symbol, diseases.disease_name
+        FROM term_to_gene 
+        LEFT JOIN genes ON term_to_gene.gene_id = genes.gene_id
+        JOIN diseases ON term_to_gene.disease_id = diseases.disease_id 
+        WHERE term_to_gene.term_id=?"#
+    )?;
+
+    let gene_iter = stmt.query_map([term_id], |row| association_from_row(row, false))?;
+    gene_iter.collect()
+}
+
+pub fn get_terms_for_gene(conn: &Connection, gene_id: String) -> Result<Vec<TermGeneAssociation>> {
+    let mut stmt = conn.prepare_cached(r#"
+        SELECT term_to_gene.term_id, term_to_gene.gene_id, term_to_gene.frequency, term_to_gene.disease_id, genes.gene_symbol, terms.name, diseases.disease_name
+        FROM term_to_gene
+        JOIN genes ON term_to_gene.gene_id = genes.gene_id 
+        LEFT JOIN terms ON term_to_gene.term_id = terms.term_id 
+        JOIN diseases ON term_to_gene.disease_id = diseases.disease_id
+        WHERE term_to_gene.gene_id=?"#
+    )?;
+
+    let phen_iter = stmt.query_map([gene_id], |row| association_from_row(row, true))?;
+    phen_iter.collect()
+}
+
+pub fn get_term_id(conn: &Connection, term_id: String) -> Result<Option<Term>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Terms WHERE term_id=?", TERM_COLUMNS))?;
+    //there should only be one term returned
+    stmt.query_row([term_id], term_from_row).optional()
+}
+
+// The terms among ids that are in the table, keyed by id
+pub fn get_terms_by_ids(conn: &Connection, term_ids: Vec<String>) -> Result<HashMap<String, Term>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Terms WHERE term_id=?", TERM_COLUMNS))?;
+    let mut terms = HashMap::new();
+    for term_id in term_ids {
+        if let Some(term) = stmt.query_row([&term_id], term_from_row).optional()? {
+            terms.insert(term_id, term);
+        }
+    }
+    Ok(terms)
+}
+
+pub fn get_term_name(conn: &Connection, term_name: String) -> Result<Option<Term>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Terms WHERE name COLLATE NOCASE LIKE ?", TERM_COLUMNS))?;
+    //there should only be one term returned
+    stmt.query_row([term_name], term_from_row).optional()
+}
+
+pub fn get_all_terms_ids(conn: &Connection) -> Result<HashMap<String, Term>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Terms", TERM_COLUMNS))?;
+    let term_iter = stmt.query_map([], term_from_row)?;
+
+    //put each term into the hashmap with the hpo_id as the key and the term as the value
+    term_iter.map(|term| term.map(|term| (term.hpo_id.clone(), term))).collect()
+}
+
+pub fn get_all_terms_names(conn: &Connection) -> Result<HashMap<String, Term>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Terms", TERM_COLUMNS))?;
+    let term_iter = stmt.query_map([], term_from_row)?;
+
+    //put each term into the hashmap with the name as the key and the term as the value
+    term_iter.map(|term| term.map(|term| (term.name.clone().unwrap_or_default(), term))).collect()
+}
+
+pub fn get_all_terms(conn: &Connection) -> Result<Vec<Term>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Terms", TERM_COLUMNS))?;
+    let term_iter = stmt.query_map([], term_from_row)?;
+    term_iter.collect()
+}
+
+pub fn get_disease(conn: &Connection, disease_id: String) -> Result<Option<Disease>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM Diseases WHERE disease_id=?", DISEASE_COLUMNS))?;
+    stmt.query_row([disease_id], disease_from_row).optional()
+}
+
+// The terms annotated to a disease, once each. term_to_gene repeats a term for every gene of the
+// disease, the first row with a usable frequency is kept
+pub fn get_terms_for_disease(conn: &Connection, disease_id: String) -> Result<Vec<DiseaseTerm>> {
+    let mut stmt = conn.prepare_cached(r#"
+        SELECT term_to_gene.term_id, terms.name, term_to_gene.frequency
+        FROM term_to_gene
+        LEFT JOIN terms ON term_to_gene.term_id = terms.term_id
+        WHERE term_to_gene.disease_id=?
+        ORDER BY term_to_gene.term_id"#
+    )?;
+
+    let mut terms: Vec<DiseaseTerm> = Vec::new();
+    let rows = stmt.query_map([disease_id], |row| {
+        let frequency: Option<String> = row.get(2)?;
+        Ok(DiseaseTerm {
+            term_id: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+            name: row.get(1)?,
+            frequency: frequency.as_deref().and_then(parse_frequency),
+        })
+    })?;
+    for term in rows {
+        let term = term?;
+        match terms.last_mut() {
+            Some(last) if last.term_id == term.term_id => {
+                if last.frequency.is_none() {
+                    last.frequency = term.frequency;
+                }
+            }
+            _ => terms.push(term),
+        }
+    }
+    Ok(terms)
+}
+
+// Genes from gene_to_disease first, then any others only the term annotations link to the disease
+pub fn get_genes_for_disease(conn: &Connection, disease_id: String) -> Result<Vec<DiseaseGene>> {
+    let mut stmt = conn.prepare_cached(r#"
+        SELECT gene_to_disease.gene_id, genes.gene_symbol, gene_to_disease.association_type
+        FROM gene_to_disease
+        LEFT JOIN genes ON gene_to_disease.gene_id = genes.gene_id
+        WHERE gene_to_disease.disease_id=?1
+        UNION
+        SELECT DISTINCT term_to_gene.gene_id, genes.gene_symbol, NULL
+        FROM term_to_gene
+        LEFT JOIN genes ON term_to_gene.gene_id = genes.gene_id
+        WHERE term_to_gene.disease_id=?1
+            AND term_to_gene.gene_id NOT IN (SELECT gene_id FROM gene_to_disease WHERE disease_id=?1)"#
+    )?;
+
+    let gene_iter = stmt.query_map([disease_id], |row| {
+        Ok(DiseaseGene {
+            gene_id: numeric_id(row, 0)?,
+            gene_symbol: row.get(1)?,
+            association_type: row.get(2)?,
+        })
+    })?;
+    let mut genes = gene_iter.collect::<Result<Vec<DiseaseGene>>>()?;
+    genes.sort_by(|a, b| b.association_type.is_some().cmp(&a.association_type.is_some()).then_with(|| a.gene_symbol.cmp(&b.gene_symbol)));
+    Ok(genes)
+}
+
+// Diseases whose name contains query, or whose id starts with it. Names starting with the query come
+// first, then shorter names
+pub fn search_diseases(conn: &Connection, query: String, limit: u32) -> Result<Vec<Disease>> {
+    let escaped = query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    let mut stmt = conn.prepare_cached(&format!(r#"
+        SELECT {} FROM Diseases
+        WHERE disease_name LIKE '%' || ?1 || '%' ESCAPE '\' OR disease_id LIKE ?1 || '%' ESCAPE '\'
+        ORDER BY disease_name LIKE ?1 || '%' ESCAPE '\' DESC, length(disease_name), disease_id
+        LIMIT ?2"#, DISEASE_COLUMNS)
+    )?;
+
+    let disease_iter = stmt.query_map(rusqlite::params![escaped, limit], disease_from_row)?;
+    disease_iter.collect()
+}
+
+// What build-db recorded about the sources, empty for a db that wasn't made by it
+pub fn get_metadata(conn: &Connection) -> Result<BTreeMap<String, String>> {
+    let exists = conn.prepare_cached("SELECT 1 FROM sqlite_master WHERE type='table' AND name='metadata'")?.exists([])?;
+    if !exists {
+        return Ok(BTreeMap::new());
+    }
+    let mut stmt = conn.prepare_cached("SELECT key, value FROM metadata")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default())))?;
+    rows.collect()
+}
@@ -0,0 +1,98 @@
+use std::convert::Infallible;
+use serde::Serialize;
+use warp::{Rejection, Reply, http::StatusCode};
+use crate::db::DbError;
+
+// Every failure a route can report. Handlers reject with these and handle_rejection turns them into responses
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    NotFound(String),
+    NotAcceptable(String),
+    UnsupportedMediaType(String),
+    PayloadTooLarge(String),
+    Internal(String),
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::NotAcceptable(_) => "not_acceptable",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::BadRequest(message) | ApiError::NotFound(message) | ApiError::NotAcceptable(message)
+            | ApiError::UnsupportedMediaType(message) | ApiError::PayloadTooLarge(message) | ApiError::Internal(message) => message.to_string(),
+            ApiError::Unauthorized => "a valid token is required, in the x-admin-token header or, for /match, X-Auth-Token".to_string(),
+        }
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(error: DbError) -> ApiError {
+        ApiError::Internal(error.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: String,
+    message: String,
+}
+
+// The JSON error body for our own errors and for the ones warp raises while matching a request
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, code, message) = if let Some(error) = err.find::<ApiError>() {
+        if let ApiError::Internal(message) = error {
+            eprintln!("Internal error: {}", message);
+        }
+        (error.status(), error.code(), error.message())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not_found", "no such route".to_string())
+    } else if let Some(error) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, "bad_request", error.to_string())
+    } else if let Some(error) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, "bad_request", error.to_string())
+    } else if let Some(error) = err.find::<warp::reject::MissingHeader>() {
+        (StatusCode::BAD_REQUEST, "bad_request", error.to_string())
+    } else if let Some(error) = err.find::<warp::reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, "bad_request", error.to_string())
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", "request body is too large".to_string())
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        (StatusCode::LENGTH_REQUIRED, "length_required", "a Content-Length header is required".to_string())
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", "unsupported content type".to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "method not allowed".to_string())
+    } else {
+        eprintln!("Unhandled rejection: {:?}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "internal server error".to_string())
+    };
+
+    let body = ErrorBody { code: code.to_string(), message };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
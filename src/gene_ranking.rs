@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use serde::Deserialize;
+use hpo::Ontology;
+use crate::calc_scores::{self, ScoreReturn};
+use crate::calc_simpheny_score;
+use crate::error::ApiError;
+use crate::population::{self, Population};
+use crate::simpheny_background::Backgrounds;
+use crate::simpheny_nulls::NullTable;
+
+// Added to the phenotype score of a hit that shares a candidate gene when no boost is given. Similarity scores are
+// at most 1, so by default every hit sharing a gene ranks above every hit that doesn't
+const DEFAULT_BOOST: f32 = 1.0;
+
+// Random draws per hit when simpheny mode has to simulate a phenotype p-value, lower than /simpheny_score's
+// default since every hit is scored
+const SIMPHENY_MAX_ITERATIONS: u32 = 1000;
+const SIMPHENY_PRECISION: f64 = 0.1;
+
+// Simpheny mode simulates a null for every hit without a precomputed one, so without the admin token at most this
+// many hits are simulated. Larger cohorts need the token, narrower filters or precompute-nulls
+pub const MAX_UNAUTHENTICATED_SIMULATIONS: usize = 500;
+
+// The gene options the compare GET routes take in their query string, candidate_genes is comma separated
+#[derive(Deserialize, Debug, Default)]
+pub struct GeneParams {
+    pub candidate_genes: Option<String>,
+    pub gene_mode: Option<String>, // flag, boost (default) or simpheny
+    pub gene_boost: Option<f32>,
+    pub data_bg: Option<String>, // The SimPheny background, the population's own when it has one, otherwise udn
+    pub seed: Option<u64>, // SimPheny's seed, so a compare can be replayed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeneMode {
+    Flag, // Scores and ranks as they were, with the shared genes reported
+    Boost, // gene_boost added to the score of hits sharing a gene
+    Simpheny, // Ranked by the combined phenotype and gene SimPheny score
+}
+
+// What SimPheny needs from the server to score hits inline. The term and gene lists are read once at startup, empty
+// when they couldn't be
+pub struct Simpheny {
+    pub backgrounds: Arc<RwLock<Backgrounds>>,
+    pub nulls: Option<Arc<NullTable>>,
+    pub all_term_list: Vec<u32>,
+    pub all_gene_list: Vec<String>,
+}
+
+// Reranks compare results by the candidate genes of the query, e.g. the genes from a patient's exome
+#[derive(Debug, Clone)]
+pub struct GeneRanking {
+    genes: Vec<String>, // Uppercased
+    mode: GeneMode,
+    boost: f32,
+    data_bg: Option<String>,
+    seed: u64,
+}
+
+impl GeneRanking {
+    // None when no candidate genes are given, compare then works as it always has
+    pub fn new(genes: &[String], mode: Option<&str>, boost: Option<f32>, data_bg: Option<String>, seed: Option<u64>) -> Result<Option<GeneRanking>, ApiError> {
+        let mut candidates: Vec<String> = Vec::new();
+        for gene in genes.iter().map(|gene| gene.trim().to_uppercase()).filter(|gene| !gene.is_empty()) {
+            if !candidates.contains(&gene) {
+                candidates.push(gene);
+            }
+        }
+        let mode = match mode.map(|m| m.trim().to_lowercase()).as_deref() {
+            None | Some("boost") => GeneMode::Boost,
+            Some("flag") => GeneMode::Flag,
+            Some("simpheny") => GeneMode::Simpheny,
+            Some(other) => return Err(ApiError::BadRequest(format!("unknown gene_mode: {}, expected flag, boost or simpheny", other))),
+        };
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(GeneRanking { genes: candidates, mode, boost: boost.unwrap_or(DEFAULT_BOOST), data_bg, seed: seed.unwrap_or(0) }))
+    }
+
+    pub fn from_params(params: &GeneParams) -> Result<Option<GeneRanking>, ApiError> {
+        let genes: Vec<String> = params.candidate_genes.as_deref().unwrap_or("").split(',').map(|s| s.to_string()).collect();
+        GeneRanking::new(&genes, params.gene_mode.as_deref(), params.gene_boost, params.data_bg.clone(), params.seed)
+    }
+
+    // Adds the shared genes to calc_scores' results and reranks them. Each hit's score map gets shared_genes (a
+    // count) and, unless flagging, phenotype_score with score replaced by the gene aware one. A "shared_genes"
+    // entry lists the candidate genes each hit has, for the hits with any. authorized lifts the simulation limit
+    #[allow(clippy::too_many_arguments)]
+    pub fn apply(&self, ontology: &Arc<Ontology>, query: &[u32], candidates: &Population, results: HashMap<String, ScoreReturn>, population: &str, simpheny: &Simpheny, authorized: bool) -> Result<HashMap<String, ScoreReturn>, ApiError> {
+        let mut score_map = match results.get("score_map") {
+            Some(ScoreReturn::ScoreMap(score_map)) => score_map.clone(),
+            _ => return Ok(results),
+        };
+
+        let mut shared: HashMap<String, Vec<String>> = HashMap::new();
+        for (id, individual) in candidates {
+            let genes = population::individual_genes(individual);
+            let found: Vec<String> = self.genes.iter().filter(|gene| genes.iter().any(|g| g.eq_ignore_ascii_case(gene))).cloned().collect();
+            if !found.is_empty() {
+                shared.insert(id.clone(), found);
+            }
+        }
+
+        let simpheny_scores = match self.mode {
+            GeneMode::Simpheny => self.simpheny_scores(ontology, query, candidates, &score_map, &shared, population, simpheny, authorized)?,
+            _ => HashMap::new(),
+        };
+
+        let mut score_vec: Vec<Vec<String>> = Vec::new();
+        for (id, scores) in score_map.iter_mut() {
+            scores.remove("rank");
+            let phenotype_score = scores.get("score").copied().unwrap_or(0.0);
+            let shared_genes = shared.get(id).map(|genes| genes.len()).unwrap_or(0);
+            scores.insert("shared_genes".to_string(), shared_genes as f32);
+
+            let score = match self.mode {
+                GeneMode::Flag => phenotype_score,
+                GeneMode::Boost => if shared_genes > 0 { phenotype_score + self.boost } else { phenotype_score },
+                GeneMode::Simpheny => {
+                    let result = &simpheny_scores[id];
+                    scores.insert("pheno_p".to_string(), result.pheno_p as f32);
+                    scores.insert("gene_p".to_string(), result.gene_p as f32);
+                    scores.insert("combined_p".to_string(), result.combined_p as f32);
+                    result.score as f32
+                }
+            };
+            if self.mode != GeneMode::Flag {
+                scores.insert("phenotype_score".to_string(), phenotype_score);
+                scores.insert("score".to_string(), score);
+            }
+            score_vec.push(vec![id.clone(), score.to_string()]);
+        }
+
+        let mut ranked = calc_scores::create_ranked_vec(score_map, score_vec);
+        ranked.insert("shared_genes".to_string(), ScoreReturn::GeneMap(shared));
+        Ok(ranked)
+    }
+
+    // The SimPheny result of every hit, scored across the available cores. A hit sharing several candidate genes is
+    // scored with the rarest of them
+    #[allow(clippy::too_many_arguments)]
+    fn simpheny_scores(&self, ontology: &Arc<Ontology>, query: &[u32], candidates: &Population, score_map: &HashMap<String, HashMap<String, f32>>, shared: &HashMap<String, Vec<String>>, population: &str, simpheny: &Simpheny, authorized: bool) -> Result<HashMap<String, calc_simpheny_score::SimphenyResult>, ApiError> {
+        let backgrounds = simpheny.backgrounds.read().unwrap();
+        let data_bg = match &self.data_bg {
+            Some(data_bg) => data_bg.as_str(),
+            None if backgrounds.contains_key(population) => population,
+            None => "udn",
+        };
+        let params = backgrounds.get(data_bg).cloned().ok_or_else(|| ApiError::BadRequest(format!("unknown data_bg: {}", data_bg)))?;
+        drop(backgrounds);
+
+        if simpheny.all_term_list.is_empty() || simpheny.all_gene_list.is_empty() {
+            return Err(ApiError::Internal("the SimPheny term and gene lists could not be read at startup".to_string()));
+        }
+        let num_query_genes = self.genes.len() as u32;
+        let num_terms = (query.len() as u32).min(calc_simpheny_score::MAX_QUERY_TERMS);
+
+        let hits: Vec<(&String, f32, Vec<u32>)> = score_map.iter().map(|(id, scores)| {
+            let hit_terms = candidates.get(id).map(|individual| population::individual_terms(ontology, individual)).unwrap_or_default();
+            (id, scores.get("score").copied().unwrap_or(0.0), hit_terms)
+        }).collect();
+        let simulated = hits.iter()
+            .filter(|(_, score, terms)| simpheny.nulls.as_ref().and_then(|nulls| nulls.lookup(terms, num_terms, *score)).is_none())
+            .count();
+        if simulated > MAX_UNAUTHENTICATED_SIMULATIONS && !authorized {
+            return Err(ApiError::BadRequest(format!(
+                "{} hits without a precomputed null for gene_mode=simpheny, up to {} without the x-admin-token header, narrow the cohort with the filters or precompute its nulls",
+                simulated, MAX_UNAUTHENTICATED_SIMULATIONS,
+            )));
+        }
+
+        // Each shared gene's p-value once, they are a scan of the whole gene list
+        let mut gene_p: HashMap<&str, f64> = HashMap::new();
+        for gene in shared.values().flatten() {
+            gene_p.entry(gene.as_str()).or_insert_with(|| calc_simpheny_score::calc_gene_p_val(&simpheny.all_gene_list, gene, num_query_genes));
+        }
+
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let chunk_size = hits.len().div_ceil(threads).max(1);
+        let scored: Vec<Result<(String, calc_simpheny_score::SimphenyResult), String>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = hits
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    let (params, gene_p) = (&params, &gene_p);
+                    scope.spawn(move || {
+                        chunk.iter().map(|(id, score, hit_terms)| {
+                            let hit_gene_p = shared.get(*id)
+                                .and_then(|genes| genes.iter().map(|gene| gene_p[gene.as_str()]).min_by(f64::total_cmp))
+                                .unwrap_or(1.0);
+                            calc_simpheny_score::calc_simpheny_score_with_lists(
+                                ontology,
+                                hit_terms.clone(),
+                                hit_gene_p,
+                                *score,
+                                query.len() as u32,
+                                params,
+                                SIMPHENY_PRECISION,
+                                SIMPHENY_MAX_ITERATIONS,
+                                self.seed,
+                                simpheny.nulls.as_deref(),
+                                &simpheny.all_term_list,
+                            ).map(|result| (id.to_string(), result))
+                        }).collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+        scored.into_iter().collect::<Result<HashMap<_, _>, String>>().map_err(ApiError::Internal)
+    }
+}
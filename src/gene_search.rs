@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use csv::{ReaderBuilder, Error};
+use serde::Serialize;
+
+// One approved gene from the HGNC complete set
+#[derive(Serialize, Debug, Clone)]
+pub struct HgncGene {
+    pub hgnc_id: String,
+    pub symbol: String,
+    pub name: String,
+    pub gene_id: Option<u32>, // NCBI (Entrez) gene id
+}
+
+// How a symbol was resolved to an approved HGNC symbol
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Approved, // Already the approved symbol
+    PreviousSymbol, // Remapped from a symbol the gene used to have
+    Alias, // Remapped from an alias
+    Ambiguous, // A previous symbol or alias of more than one gene, see candidates
+    NotFound,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ResolvedSymbol {
+    pub query: String,
+    pub resolution: Resolution,
+    pub remapped: bool, // True when symbol differs from what was asked for
+    pub symbol: Option<String>, // The approved symbol, None when ambiguous or not found
+    pub gene: Option<HgncGene>,
+    pub candidates: Vec<HgncGene>, // Every gene the symbol could mean when ambiguous
+}
+
+#[derive(Serialize, Debug)]
+pub struct GeneHit {
+    #[serde(flatten)]
+    pub gene: HgncGene,
+    pub matched_symbol: String, // The approved symbol, previous symbol or alias that matched
+    pub matched: Resolution,
+}
+
+// Approved symbols, previous symbols and aliases from a local copy of HGNC's hgnc_complete_set.txt.
+// All lookups are case insensitive
+pub struct GeneIndex {
+    genes: Vec<HgncGene>,
+    approved: HashMap<String, usize>,
+    previous: HashMap<String, Vec<usize>>,
+    aliases: HashMap<String, Vec<usize>>,
+    // Every (uppercased symbol, gene, kind) sorted by symbol for prefix search
+    sorted: Vec<(String, usize, Resolution)>,
+}
+
+impl GeneIndex {
+    pub fn empty() -> GeneIndex {
+        GeneIndex { genes: Vec::new(), approved: HashMap::new(), previous: HashMap::new(), aliases: HashMap::new(), sorted: Vec::new() }
+    }
+
+    // Reads the tab separated HGNC complete set, columns are found by their header names
+    pub fn load(tsv_url: &str) -> Result<GeneIndex, Error> {
+        let mut reader = ReaderBuilder::new()
+            .delimiter(b'\t')
+            .flexible(true)
+            .from_path(tsv_url)?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|h| h == name);
+        let (hgnc_id, symbol, name) = (column("hgnc_id"), column("symbol"), column("name"));
+        let (alias_symbol, prev_symbol, entrez_id) = (column("alias_symbol"), column("prev_symbol"), column("entrez_id"));
+
+        let mut index = GeneIndex::empty();
+        for result in reader.records() {
+            let record = result?;
+            let field = |idx: Option<usize>| idx.and_then(|i| record.get(i)).unwrap_or("").trim();
+            if field(symbol).is_empty() {
+                continue;
+            }
+
+            let idx = index.genes.len();
+            index.genes.push(HgncGene {
+                hgnc_id: field(hgnc_id).to_string(),
+                symbol: field(symbol).to_string(),
+                name: field(name).to_string(),
+                gene_id: field(entrez_id).parse::<u32>().ok(),
+            });
+            index.approved.insert(field(symbol).to_uppercase(), idx);
+            // Lists are "|" separated, and quoted in some releases
+            for previous in split_symbols(field(prev_symbol)) {
+                index.previous.entry(previous).or_default().push(idx);
+            }
+            for alias in split_symbols(field(alias_symbol)) {
+                index.aliases.entry(alias).or_default().push(idx);
+            }
+        }
+
+        let mut sorted: Vec<(String, usize, Resolution)> = Vec::new();
+        sorted.extend(index.approved.iter().map(|(s, idx)| (s.clone(), *idx, Resolution::Approved)));
+        sorted.extend(index.previous.iter().flat_map(|(s, idxs)| idxs.iter().map(move |idx| (s.clone(), *idx, Resolution::PreviousSymbol))));
+        sorted.extend(index.aliases.iter().flat_map(|(s, idxs)| idxs.iter().map(move |idx| (s.clone(), *idx, Resolution::Alias))));
+        sorted.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        index.sorted = sorted;
+
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.genes.len()
+    }
+
+    // Approved symbols win, then previous symbols, then aliases. A previous symbol or alias shared by
+    // several genes is reported as ambiguous rather than guessed at
+    pub fn resolve(&self, query: &str) -> ResolvedSymbol {
+        let key = query.trim().to_uppercase();
+        let (resolution, candidates): (Resolution, Vec<usize>) = if let Some(idx) = self.approved.get(&key) {
+            (Resolution::Approved, vec![*idx])
+        } else if let Some(idxs) = self.previous.get(&key) {
+            (Resolution::PreviousSymbol, idxs.clone())
+        } else if let Some(idxs) = self.aliases.get(&key) {
+            (Resolution::Alias, idxs.clone())
+        } else {
+            (Resolution::NotFound, Vec::new())
+        };
+
+        let mut candidates: Vec<HgncGene> = candidates.into_iter().map(|idx| self.genes[idx].clone()).collect();
+        candidates.dedup_by(|a, b| a.hgnc_id == b.hgnc_id);
+        let resolution = if candidates.len() > 1 { Resolution::Ambiguous } else { resolution };
+        let gene = if candidates.len() == 1 { candidates.pop() } else { None };
+        let symbol = gene.as_ref().map(|gene| gene.symbol.clone());
+
+        ResolvedSymbol {
+            query: query.to_string(),
+            resolution,
+            remapped: symbol.as_ref().is_some_and(|symbol| !symbol.eq_ignore_ascii_case(query.trim())),
+            symbol,
+            gene,
+            candidates,
+        }
+    }
+
+    // The gene with an HGNC id ("HGNC:5") or NCBI gene id ("2260"), the other ways genes are named in exchanged data
+    pub fn by_id(&self, id: &str) -> Option<&HgncGene> {
+        let id = id.trim();
+        match id.parse::<u32>() {
+            Ok(gene_id) => self.genes.iter().find(|gene| gene.gene_id == Some(gene_id)),
+            Err(_) => self.genes.iter().find(|gene| gene.hgnc_id.eq_ignore_ascii_case(id)),
+        }
+    }
+
+    // Autocomplete over approved symbols, previous symbols and aliases. Each gene is listed once, by its best
+    // match: exact before prefix, then approved before previous before alias, then shorter symbols first
+    pub fn search(&self, prefix: &str, limit: usize) -> Vec<GeneHit> {
+        let prefix = prefix.trim().to_uppercase();
+        if prefix.is_empty() {
+            return Vec::new();
+        }
+
+        let start = self.sorted.partition_point(|(symbol, _, _)| symbol.as_str() < prefix.as_str());
+        let mut best: HashMap<usize, (&str, Resolution)> = HashMap::new();
+        for (symbol, idx, kind) in self.sorted[start..].iter().take_while(|(symbol, _, _)| symbol.starts_with(&prefix)) {
+            let candidate = (symbol.as_str(), *kind);
+            let current = best.entry(*idx).or_insert(candidate);
+            if rank(&prefix, candidate) < rank(&prefix, *current) {
+                *current = candidate;
+            }
+        }
+
+        let mut hits: Vec<(usize, &str, Resolution)> = best.into_iter().map(|(idx, (symbol, kind))| (idx, symbol, kind)).collect();
+        hits.sort_by(|a, b| rank(&prefix, (a.1, a.2)).cmp(&rank(&prefix, (b.1, b.2))).then_with(|| a.1.cmp(b.1)));
+        hits.into_iter()
+            .take(limit)
+            .map(|(idx, symbol, kind)| {
+                let gene = self.genes[idx].clone();
+                // Report approved symbols in HGNC's casing
+                let matched_symbol = if kind == Resolution::Approved { gene.symbol.clone() } else { symbol.to_string() };
+                GeneHit { gene, matched_symbol, matched: kind }
+            })
+            .collect()
+    }
+}
+
+// Lower sorts first
+fn rank(prefix: &str, (symbol, kind): (&str, Resolution)) -> (bool, u8, usize) {
+    let kind_rank = match kind {
+        Resolution::Approved => 0,
+        Resolution::PreviousSymbol => 1,
+        _ => 2,
+    };
+    (symbol != prefix, kind_rank, symbol.len())
+}
+
+fn split_symbols(symbols: &str) -> Vec<String> {
+    symbols
+        .trim_matches('"')
+        .split('|')
+        .map(|s| s.trim().to_uppercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
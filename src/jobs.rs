@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use crate::population_store;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct JobInfo {
+    pub id: String,
+    pub kind: String,
+    pub status: JobStatus,
+    pub done: usize, // Units of work finished, out of total
+    pub total: usize,
+    pub started_at: u64, // Unix seconds
+    pub finished_at: Option<u64>,
+    pub output: Option<String>, // The file the job wrote, once done
+    pub error: Option<String>,
+}
+
+// Long running admin work, run off the async workers and polled by id. Jobs only live as long as the server, work
+// that checkpoints to disk picks up where it stopped when the same job is started again after a restart
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, JobInfo>>,
+}
+
+impl Jobs {
+    pub fn get(&self, id: &str) -> Option<JobInfo> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    // Every job, newest first
+    pub fn list(&self) -> Vec<JobInfo> {
+        let mut jobs: Vec<JobInfo> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by(|a, b| b.started_at.cmp(&a.started_at).then_with(|| a.id.cmp(&b.id)));
+        jobs
+    }
+
+    // Runs work on the blocking pool under id. work gets a progress callback taking (done, total) and returns the
+    // file it wrote. Starting a job that is already running returns it as it is rather than running it twice
+    pub fn start<F>(self: &Arc<Self>, id: &str, kind: &str, work: F) -> JobInfo
+    where
+        F: FnOnce(&dyn Fn(usize, usize)) -> Result<String, String> + Send + 'static,
+    {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get(id).filter(|job| job.status == JobStatus::Running) {
+            return job.clone();
+        }
+        let job = JobInfo {
+            id: id.to_string(),
+            kind: kind.to_string(),
+            status: JobStatus::Running,
+            done: 0,
+            total: 0,
+            started_at: population_store::now(),
+            finished_at: None,
+            output: None,
+            error: None,
+        };
+        jobs.insert(id.to_string(), job.clone());
+        drop(jobs);
+
+        let registry = Arc::clone(self);
+        let id = id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let progress = |done: usize, total: usize| registry.update(&id, |job| {
+                job.done = done;
+                job.total = total;
+            });
+            // A panicking job is failed like one returning an error, otherwise it would show as running forever
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| work(&progress)))
+                .unwrap_or_else(|panic| Err(panic_message(panic.as_ref())));
+            registry.update(&id, |job| {
+                job.finished_at = Some(population_store::now());
+                match result {
+                    Ok(output) => {
+                        job.status = JobStatus::Done;
+                        job.output = Some(output);
+                    }
+                    Err(e) => {
+                        eprintln!("Warning: job {} failed: {}", job.id, e);
+                        job.status = JobStatus::Failed;
+                        job.error = Some(e);
+                    }
+                }
+            });
+        });
+        job
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut JobInfo)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            change(job);
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    let message = panic.downcast_ref::<&str>().map(|m| m.to_string()).or_else(|| panic.downcast_ref::<String>().cloned());
+    format!("the job panicked: {}", message.unwrap_or_else(|| "no message".to_string()))
+}
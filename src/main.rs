@@ -3,40 +3,178 @@ mod custom_jaccard_ic;
 mod population;
 mod calc_scores;
 mod calc_simpheny_score;
+mod simpheny_background;
+mod simpheny_nulls;
+mod admin;
+mod cli;
+mod db;
+mod error;
+mod term_search;
+mod gene_search;
+mod ontology_nav;
+mod build_db;
+mod version;
+mod populations;
+mod population_store;
+mod population_upload;
+mod cohort_filter;
+mod similarity_matrix;
+mod jobs;
+mod clustering;
+mod term_enrichment;
+mod gene_ranking;
+mod vcf_prioritization;
+mod matchmaker;
 use warp::{Filter, path, reply, Rejection, Reply, http::StatusCode, http::Response, hyper::Body, cors};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use rusqlite::{params, Connection, Result};
 use serde::{Deserialize, Serialize, de::IntoDeserializer};
 use serde_json::Result as SerdeResult;
 use hpo::Ontology;
+use rand::Rng;
+use error::ApiError;
+
+// URLS PRODUCTION
+const UDN_CSV_URL: &str = "/data/UdnPatients.csv"; //Production URL
+const ORPHA_TSV_URL: &str = "/data/ORPHANETessentials.tsv"; //Production URL
+const DECIPHER_DATA_URL: &str = "/data/DecipherData.csv"; //Production URL
+const CLINVAR_DATA_URL: &str = "/data/ClinVar.csv"; //Production URL
+const GENE_LIST_URL: &str = "/data/gene_list.csv"; //Production URL
+const TERMS_LIST_URL: &str = "/data/term_list.csv"; //Production URL
+const BACKGROUNDS_URL: &str = "/data/simpheny_backgrounds.json"; //Production URL
+const NULLS_URL: &str = "/bin_simpheny_nulls"; //Production URL
+const HGNC_URL: &str = "/data/hgnc_complete_set.txt"; //Production URL
+const POPULATION_STORE_URL: &str = "/data/populations.db"; //Production URL
+const JOBS_DIR: &str = "/data/jobs"; //Production URL
+
+// URLS DEVELOPMENT
+// const UDN_CSV_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/UdnPatients.csv"; //Development URL
+// const ORPHA_TSV_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/ORPHANETessentials.tsv"; //Development URL
+// const DECIPHER_DATA_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/DecipherData.csv"; //Development URL
+// const CLINVAR_DATA_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/ClinVar.csv"; //Development URL
+// const GENE_LIST_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/gene_list.csv"; //Development URL
+// const TERMS_LIST_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/term_list.csv"; //Development URL
+// const BACKGROUNDS_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/simpheny_backgrounds.json"; //Development URL
+// const NULLS_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/bin_simpheny_nulls"; //Development URL
+// const HGNC_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/hgnc_complete_set.txt"; //Development URL
+// const POPULATION_STORE_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/populations.db"; //Development URL
+// const JOBS_DIR: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/jobs"; //Development URL
+
+// Largest list POST /terms/batch accepts
+const MAX_BATCH_TERMS: usize = 1000;
+
+// Largest file POST /admin/populations/{name} accepts, in bytes
+const MAX_UPLOAD_BYTES: u64 = 50 * 1024 * 1024;
+
+// The built in populations, each has a /{name}_population and /compare_{name} route. Uploaded ones are served
+// by /population/{name} and /compare/{name}/{terms}
+const POPULATION_NAMES: [&str; 4] = ["udn", "orpha", "decipher", "clinvar"];
+
+// The data file behind each population name used in the routes
+fn population_url(name: &str) -> Option<&'static str> {
+    match name {
+        "udn" => Some(UDN_CSV_URL),
+        "orpha" => Some(ORPHA_TSV_URL),
+        "decipher" => Some(DECIPHER_DATA_URL),
+        "clinvar" => Some(CLINVAR_DATA_URL),
+        _ => None,
+    }
+}
 
 #[tokio::main]
 async fn main() {
     // Overarching variables
-    let ontology = Arc::new(Ontology::from_binary("/bin_hpo_file").unwrap()); //Production URL
-    // let ontology = Arc::new(Ontology::from_binary("/Users/emerson/Documents/Code/pheno_matcher_be_rust/bin_hpo_file").unwrap()); //Development URL
-
-    // URLS PRODUCTION
-    const UDN_CSV_URL: &str = "/data/UdnPatients.csv"; //Production URL
-    const ORPHA_TSV_URL: &str = "/data/ORPHANETessentials.tsv"; //Production URL
-    const DECIPHER_DATA_URL: &str = "/data/DecipherData.csv"; //Production URL
-    const CLINVAR_DATA_URL: &str = "/data/ClinVar.csv"; //Production URL
-    const GENE_LIST_URL: &str = "/data/gene_list.csv"; //Production URL
-    const TERMS_LIST_URL: &str = "/data/term_list.csv"; //Production URL
-
-    // URLS DEVELOPMENT
-    // const UDN_CSV_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/UdnPatients.csv"; //Development URL
-    // const ORPHA_TSV_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/ORPHANETessentials.tsv"; //Development URL
-    // const DECIPHER_DATA_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/DecipherData.csv"; //Development URL
-    // const CLINVAR_DATA_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/ClinVar.csv"; //Development URL
-    // const GENE_LIST_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/gene_list.csv"; //Development URL
-    // const TERMS_LIST_URL: &str = "/Users/emerson/Documents/Code/pheno_matcher_be_rust/data/term_list.csv"; //Development URL
-
-    let udn_population = Arc::new(population::create_udn_population(UDN_CSV_URL.to_string()));
-    let orpha_population = Arc::new(population::create_orpha_population(ORPHA_TSV_URL.to_string()));
-    let deciper_population = Arc::new(population::create_deciper_population(DECIPHER_DATA_URL.to_string()));
-    let clinvar_population = Arc::new(population::create_clinvar_population(CLINVAR_DATA_URL.to_string()));
+    let load_ontology = || Arc::new(Ontology::from_binary("/bin_hpo_file").unwrap()); //Production URL
+    // let load_ontology = || Arc::new(Ontology::from_binary("/Users/emerson/Documents/Code/pheno_matcher_be_rust/bin_hpo_file").unwrap()); //Development URL
+
+    // Subcommands run instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if cli::run(&args, load_ontology) {
+        return;
+    }
+    let ontology = load_ontology();
+
+    // Held as swappable snapshots so POST /admin/populations/{name}/reload can re-read a file without a restart
+    let population_store = population_store::PopulationStore::open(POPULATION_STORE_URL).expect("Could not open the population store");
+    let populations = Arc::new(populations::Populations::load(&ontology, &POPULATION_NAMES, population_store).expect("Could not load the populations"));
+    // Admin jobs started through /admin/jobs, their files are written to JOBS_DIR
+    let jobs = Arc::new(jobs::Jobs::default());
+    // Brown's method parameters by data_bg name, the admin calibrate route adds to these
+    let backgrounds = Arc::new(RwLock::new(simpheny_background::load_backgrounds(BACKGROUNDS_URL)));
+    // The terms SimPheny draws random queries from
+    let all_term_list = calc_simpheny_score::load_term_list(&ontology, TERMS_LIST_URL).unwrap_or_else(|e| {
+        eprintln!("Warning: could not read the term list {}: {}", TERMS_LIST_URL, e);
+        Vec::new()
+    });
+    // Precomputed SimPheny nulls, built with the precompute-nulls subcommand. Without them, or when they were built
+    // from another ontology or term list, p-values are simulated
+    let simpheny_nulls: Option<Arc<simpheny_nulls::NullTable>> = match simpheny_nulls::NullTable::read(NULLS_URL, &ontology, &all_term_list) {
+        Ok(nulls) => {
+            println!("Loaded SimPheny nulls for {} profiles ({} draws each)", nulls.len(), nulls.draws);
+            Some(Arc::new(nulls))
+        }
+        Err(e) => {
+            eprintln!("Warning: no SimPheny nulls loaded from {}: {}", NULLS_URL, e);
+            None
+        }
+    };
+
+    // What compare needs to rank hits by SimPheny when asked to with gene_mode=simpheny
+    let simpheny = Arc::new(gene_ranking::Simpheny {
+        backgrounds: Arc::clone(&backgrounds),
+        nulls: simpheny_nulls.clone(),
+        all_term_list,
+        all_gene_list: calc_simpheny_score::load_gene_list(GENE_LIST_URL).unwrap_or_else(|e| {
+            eprintln!("Warning: could not read the gene list {}: {}", GENE_LIST_URL, e);
+            Vec::new()
+        }),
+    });
+
+    // Shared read-only connection pool for hpo.db
+    let db = Arc::new(db::Db::open(&get_db_path()));
+
+    // Check the ontology, hpo.db and populations come from the same HPO release. In strict mode a mismatch stops the server
+    let db_metadata = db.run(db::get_metadata).await.unwrap_or_else(|e| {
+        eprintln!("Warning: could not read build metadata: {}", e);
+        Default::default()
+    });
+    let snapshots = populations.snapshots();
+    let report = version::check(&ontology, db_metadata, &populations::as_checked(&snapshots));
+    println!(
+        "Ontology HPO {}, hpo.db HPO {}",
+        report.ontology_version,
+        report.db_hpo_version.as_deref().unwrap_or("unknown"),
+    );
+    for mismatch in &report.mismatches {
+        eprintln!("Warning: {}", mismatch);
+    }
+    if !report.consistent && version::is_strict() {
+        eprintln!("Error: refusing to start with mismatched HPO sources, unset {} to start anyway", version::STRICT_ENV);
+        std::process::exit(1);
+    }
+
+    // Index behind /search/terms, built once from the Terms table
+    let term_index = Arc::new(match db.run(db::get_all_terms).await {
+        Ok(terms) => term_search::TermIndex::new(terms),
+        Err(e) => {
+            eprintln!("Warning: term search index is empty, could not read Terms: {}", e);
+            term_search::TermIndex::new(Vec::new())
+        }
+    });
+    println!("Indexed {} terms for search", term_index.len());
+
+    // HGNC symbols, previous symbols and aliases for resolving outdated gene names. Without the file only exact symbols match
+    let gene_index = Arc::new(match gene_search::GeneIndex::load(HGNC_URL) {
+        Ok(index) => {
+            println!("Loaded {} HGNC genes", index.len());
+            index
+        }
+        Err(e) => {
+            eprintln!("Warning: no HGNC table loaded from {}: {}", HGNC_URL, e);
+            gene_search::GeneIndex::empty()
+        }
+    });
 
     // The "/" path will return a generic greeting showing that the backend is running okay
     let home = path::end().map(|| {
@@ -47,473 +185,837 @@ async fn main() {
         response.unwrap()
     });
 
-    // The "/check_db" path will return a message indicating whether the database is found or not
-    let check_db = path!("check_db").map(|| {
-        let db_path = get_db_path();
-        let db_conn = Connection::open(db_path);
+    // The "/check_db" path reports the tables in the database and their row counts, 500 if it can't be read or tables are missing
+    let check_db = path!("check_db")
+        .and(with_db(&db))
+        .and_then(|db: Arc<db::Db>| async move {
+            let health = db.health(&get_db_path()).await;
+            let status = if health.ok { StatusCode::OK } else { StatusCode::INTERNAL_SERVER_ERROR };
+            json_response_with_status(&health, status)
+        });
 
-        let response = match db_conn {
-            Ok(_) => Response::builder()
-                .status(StatusCode::OK)
-                .header("Access-Control-Allow-Origin", "*")
-                .body(format!("db found!")),
-            Err(error) => Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .header("Access-Control-Allow-Origin", "*")
-                .body(format!("db not found error: {}", error)),
-        };
-        response.unwrap()
-    });
+    // The "/health" path is a cheap liveness probe for the database pool
+    let health = path!("health")
+        .and(with_db(&db))
+        .and_then(|db: Arc<db::Db>| async move {
+            let response = match db.ping().await {
+                Ok(_) => Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body("ok".to_string()),
+                Err(error) => Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(error.to_string()),
+            };
+            Ok::<_, Rejection>(response)
+        });
 
-    //Get all the genes for a term by the term id
+    //Get all the genes for a term by the term id, 404 if the term is not in the database
     let get_genes_for_term = warp::path!("id" / "get_genes" / String)
-        .map(|param: String| {
-            let db_path = get_db_path();
-            let genes = get_genes_for_term(db_path, param);
-            let genes = genes.unwrap();
-            let genes = serde_json::to_string(&genes).unwrap();
-
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Content-Type", "application/json")
-                .body(genes);
-            response
+        .and(with_db(&db))
+        .and_then(|param: String, db: Arc<db::Db>| async move {
+            let genes = db.run(move |conn| {
+                if !db::term_exists(conn, &param)? {
+                    return Ok(Err(ApiError::NotFound(format!("unknown term: {}", param))));
+                }
+                db::get_genes_for_term(conn, param).map(Ok)
+            }).await.map_err(ApiError::from)??;
+            json_response(&genes)
     });
-    
-    //Get all the terms for a gene by the gene id
-    let get_terms_for_gene = warp::path!("gene" / "get_terms" / String)
-        .map(|param: String| {
-            let db_path = get_db_path();
-            let terms = get_terms_for_gene(db_path, param);
-            let terms = terms.unwrap();
-            let terms = serde_json::to_string(&terms).unwrap();
 
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Content-Type", "application/json")
-                .body(terms);
-            response
+    //Get all the terms for a gene by the gene id, 404 if the gene is not in the database
+    let get_terms_for_gene = warp::path!("gene" / "get_terms" / String)
+        .and(with_db(&db))
+        .and_then(|param: String, db: Arc<db::Db>| async move {
+            let terms = db.run(move |conn| {
+                if !db::gene_exists(conn, &param)? {
+                    return Ok(Err(ApiError::NotFound(format!("unknown gene: {}", param))));
+                }
+                db::get_terms_for_gene(conn, param).map(Ok)
+            }).await.map_err(ApiError::from)??;
+            json_response(&terms)
     });
 
     let get_terms_for_null_gene = warp::path!("gene" / "get_terms")
         .map(|| {
-            let response = Response::builder()
+            Response::builder()
                 .status(StatusCode::OK)
                 .header("Access-Control-Allow-Origin", "*")
                 .header("Content-Type", "application/json")
-                .body("[]");
-            response
+                .body("[]")
     });
 
     //Get a term from a term hpo id
-    let get_term_by_id = warp::path("id").and(warp::path::param()).map(|param: String| {
-        let db_path = get_db_path();
-        let term = get_term_id(db_path, param);
-        let term = term.unwrap();
-        let term = serde_json::to_string(&term).unwrap();
-
-        let response = Response::builder()
-            .status(StatusCode::OK)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Content-Type", "application/json")
-            .body(term);
-        response
+    let get_term_by_id = warp::path!("id" / String)
+        .and(with_db(&db))
+        .and_then(|param: String, db: Arc<db::Db>| async move {
+            let lookup = param.clone();
+            let term = db.run(move |conn| db::get_term_id(conn, lookup)).await.map_err(ApiError::from)?;
+            let term = term.ok_or_else(|| ApiError::NotFound(format!("unknown term: {}", param)))?;
+            json_response(&term)
     });
 
     //Get a term from a term name
-    let get_term_by_name = warp::path("name").and(warp::path::param()).map(|param: String| {
-        let db_path = get_db_path();
-        let param = param.replace("%20", " "); //replace %20 with a space, should be the only issue with names
-        let term = get_term_name(db_path, param);
-        let term = term.unwrap();
-        let term = serde_json::to_string(&term).unwrap();
-
-        let response = Response::builder()
-            .status(StatusCode::OK)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Content-Type", "application/json")
-            .body(term);
-        response
+    let get_term_by_name = warp::path!("name" / String)
+        .and(with_db(&db))
+        .and_then(|param: String, db: Arc<db::Db>| async move {
+            let param = param.replace("%20", " "); //replace %20 with a space, should be the only issue with names
+            let lookup = param.clone();
+            let term = db.run(move |conn| db::get_term_name(conn, lookup)).await.map_err(ApiError::from)?;
+            let term = term.ok_or_else(|| ApiError::NotFound(format!("unknown term name: {}", param)))?;
+            json_response(&term)
     });
 
     //Get a gene from a gene id
     let get_gene_by_id = warp::path!("gene" / "id" / String)
-        .map(|param: String| {
-            let db_path = get_db_path();
-            let gene = get_gene_by_id(db_path, param);
-            let gene = gene.unwrap();
-            let gene = serde_json::to_string(&gene).unwrap();
-
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Content-Type", "application/json")
-                .body(gene);
-            response
+        .and(with_db(&db))
+        .and_then(|param: String, db: Arc<db::Db>| async move {
+            let lookup = param.clone();
+            let gene = db.run(move |conn| db::get_gene_by_id(conn, lookup)).await.map_err(ApiError::from)?;
+            let gene = gene.ok_or_else(|| ApiError::NotFound(format!("unknown gene: {}", param)))?;
+            json_response(&gene)
     });
 
-    //Get gene from gene name
+    //Get gene from gene name, a previous symbol or alias is resolved to the approved symbol when the name itself isn't found
     let get_gene_by_name = warp::path!("gene" / "name" / String)
-        .map(|param: String| {
-            let db_path = get_db_path();
-            let gene = get_gene_by_name(db_path, param);
-            let gene = gene.unwrap();
-            let gene = serde_json::to_string(&gene).unwrap();
+        .and(with_db(&db))
+        .and_then({
+            let gene_index = Arc::clone(&gene_index);
+
+            move |param: String, db: Arc<db::Db>| {
+                let resolved = gene_index.resolve(&param);
+
+                async move {
+                    let lookup = param.clone();
+                    let mut gene = db.run(move |conn| db::get_gene_by_name(conn, lookup)).await.map_err(ApiError::from)?;
+                    if gene.is_none() {
+                        if let Some(symbol) = resolved.symbol.clone().filter(|_| resolved.remapped) {
+                            gene = db.run(move |conn| db::get_gene_by_name(conn, symbol)).await.map_err(ApiError::from)?;
+                        }
+                    }
 
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Content-Type", "application/json")
-                .body(gene);
-            response
+                    let gene = gene.ok_or_else(|| match resolved.resolution {
+                        gene_search::Resolution::Ambiguous => {
+                            let candidates: Vec<&str> = resolved.candidates.iter().map(|gene| gene.symbol.as_str()).collect();
+                            ApiError::NotFound(format!("ambiguous gene name: {} could be {}", param, candidates.join(", ")))
+                        }
+                        _ => ApiError::NotFound(format!("unknown gene name: {}", param)),
+                    })?;
+                    json_response(&gene)
+                }
+            }
     });
 
-    //Get genes from a list of gene names
+    //Get genes from a list of gene names, previous symbols and aliases are looked up by their approved symbol.
+    //Names not in the database come back with a null gene_id
     let get_genes_from_names = warp::path!("gene" / "names" / String)
-        .map(|param: String| {
-            //take out any %20 chars if there are any replace with nothing
-            let param = param.replace("%20", "");
-            //change the string separated by commas into a vector of strings
-            let param = param.split(",").map(|s| s.to_string()).collect::<Vec<String>>();
-            let db_path = get_db_path();
-
-            let genes = get_genes_from_names(db_path, param);
-            let genes = genes.unwrap();
-            let genes = serde_json::to_string(&genes).unwrap();
-
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Content-Type", "application/json")
-                .body(genes);
-            response
+        .and(with_db(&db))
+        .and_then({
+            let gene_index = Arc::clone(&gene_index);
+
+            move |param: String, db: Arc<db::Db>| {
+                //take out any %20 chars if there are any replace with nothing
+                let param = param.replace("%20", "");
+                //change the string separated by commas into a vector of strings, remapping the ones HGNC knows by another symbol
+                let param = param.split(',')
+                    .map(|s| {
+                        let resolved = gene_index.resolve(s);
+                        match resolved.symbol {
+                            Some(symbol) if resolved.remapped => symbol,
+                            _ => s.to_string(),
+                        }
+                    })
+                    .collect::<Vec<String>>();
+
+                async move {
+                    let genes = db.run(move |conn| db::get_genes_from_names(conn, param)).await.map_err(ApiError::from)?;
+                    json_response(&genes)
+                }
+            }
     });
 
-    // The "/all/terms/ids" path will return a json of all the terms in the database with the hpo_id as the key
-    let get_all_terms_ids = warp::path!("all" / "terms" / "ids").map(|| {
-        let db_path = get_db_path();
-        let terms = get_all_terms_ids(db_path);
-    
-        let response = match terms {
-            Ok(terms) => {
-                let json_terms = serde_json::to_string(&terms).unwrap();
-    
-                warp::http::Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Content-Type", "application/json")
-                    .body(json_terms)  // Convert String directly to Body
-                    .unwrap_or_else(|_| warp::http::Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body("Internal server error".into())
-                        .unwrap())
-            },
-            Err(e) => {
-                // Log the error or handle it appropriately
-                eprintln!("Database error: {:?}", e);
-                warp::http::Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .body("error: db cannot be found".into())
-                    .unwrap()
+    //Resolve a comma separated list of gene names against HGNC, reporting which were remapped and which are ambiguous
+    let resolve_genes = warp::path!("gene" / "resolve" / String)
+        .and_then({
+            let gene_index = Arc::clone(&gene_index);
+
+            move |param: String| {
+                let param = param.replace("%20", "");
+                let resolved: Vec<gene_search::ResolvedSymbol> = param.split(',')
+                    .filter(|s| !s.trim().is_empty())
+                    .map(|s| gene_index.resolve(s))
+                    .collect();
+                let response = json_response(&resolved);
+                async move { response }
+            }
+    });
+
+    #[derive(Deserialize)]
+    struct GeneSearchQuery {
+        q: String,
+        limit: Option<usize>, // Defaults to 20, at most 100
+    }
+
+    // Prefix autocomplete over approved symbols, previous symbols and aliases
+    let search_genes = warp::path!("search" / "genes")
+        .and(warp::query::<GeneSearchQuery>())
+        .and_then({
+            let gene_index = Arc::clone(&gene_index);
+
+            move |query: GeneSearchQuery| {
+                let response = if query.q.trim().is_empty() {
+                    Err(ApiError::BadRequest("q must not be empty".to_string()).into())
+                } else {
+                    json_response(&gene_index.search(&query.q, query.limit.unwrap_or(20).clamp(1, 100)))
+                };
+                async move { response }
+            }
+        });
+
+    //Get a disease by its OMIM:... or ORPHA:... id
+    let get_disease = warp::path!("disease" / String)
+        .and(with_db(&db))
+        .and_then(|param: String, db: Arc<db::Db>| async move {
+            let lookup = param.clone();
+            let disease = db.run(move |conn| db::get_disease(conn, lookup)).await.map_err(ApiError::from)?;
+            let disease = disease.ok_or_else(|| ApiError::NotFound(format!("unknown disease: {}", param)))?;
+            json_response(&disease)
+    });
+
+    //Get the terms annotated to a disease with their frequencies, 404 if the disease is not in the database
+    let get_terms_for_disease = warp::path!("disease" / String / "terms")
+        .and(with_db(&db))
+        .and_then(|param: String, db: Arc<db::Db>| async move {
+            let terms = db.run(move |conn| {
+                if db::get_disease(conn, param.clone())?.is_none() {
+                    return Ok(Err(ApiError::NotFound(format!("unknown disease: {}", param))));
+                }
+                db::get_terms_for_disease(conn, param).map(Ok)
+            }).await.map_err(ApiError::from)??;
+            json_response(&terms)
+    });
+
+    //Get the genes linked to a disease, 404 if the disease is not in the database
+    let get_genes_for_disease = warp::path!("disease" / String / "genes")
+        .and(with_db(&db))
+        .and_then(|param: String, db: Arc<db::Db>| async move {
+            let genes = db.run(move |conn| {
+                if db::get_disease(conn, param.clone())?.is_none() {
+                    return Ok(Err(ApiError::NotFound(format!("unknown disease: {}", param))));
+                }
+                db::get_genes_for_disease(conn, param).map(Ok)
+            }).await.map_err(ApiError::from)??;
+            json_response(&genes)
+    });
+
+    #[derive(Deserialize)]
+    struct DiseaseSearchQuery {
+        q: String,
+        limit: Option<u32>, // Defaults to 20, at most 100
+    }
+
+    // Diseases by name, or by id prefix
+    let search_diseases = warp::path!("search" / "diseases")
+        .and(warp::query::<DiseaseSearchQuery>())
+        .and(with_db(&db))
+        .and_then(|query: DiseaseSearchQuery, db: Arc<db::Db>| async move {
+            let q = query.q.trim().to_string();
+            if q.is_empty() {
+                return Err(ApiError::BadRequest("q must not be empty".to_string()).into());
             }
-        };
-        response
+            let limit = query.limit.unwrap_or(20).clamp(1, 100);
+            let diseases = db.run(move |conn| db::search_diseases(conn, q, limit)).await.map_err(ApiError::from)?;
+            json_response(&diseases)
     });
 
+    // The "/version" path reports the HPO release of each data source and the population terms missing from the ontology
+    let get_version = warp::path!("version")
+        .and(with_db(&db))
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+
+            move |db: Arc<db::Db>| {
+                let ontology = Arc::clone(&ontology);
+                let snapshots = populations.snapshots();
+
+                async move {
+                    let db_metadata = db.run(db::get_metadata).await.map_err(ApiError::from)?;
+                    json_response(&version::check(&ontology, db_metadata, &populations::as_checked(&snapshots)))
+                }
+            }
+        });
+
+    #[derive(Deserialize)]
+    struct TermBatchRequest {
+        ids: Vec<String>, // "HP:0001250" format
+    }
+
+    #[derive(Serialize)]
+    struct TermBatchResponse {
+        found: usize,
+        missing: usize,
+        terms: Vec<ontology_nav::BatchTerm>,
+    }
+
+    // Names, definitions, synonyms and IC for a list of terms in one request, unknown ids are reported per item
+    let terms_batch = warp::path!("terms" / "batch")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_db(&db))
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+
+            move |body: TermBatchRequest, db: Arc<db::Db>| {
+                let ontology = Arc::clone(&ontology);
+
+                async move {
+                    if body.ids.len() > MAX_BATCH_TERMS {
+                        return Err(ApiError::BadRequest(format!("at most {} ids can be looked up at once", MAX_BATCH_TERMS)).into());
+                    }
+                    let lookup: Vec<String> = body.ids.iter().filter_map(|id| ontology_nav::normalize_id(id)).collect();
+                    let db_terms = db.run(move |conn| db::get_terms_by_ids(conn, lookup)).await.map_err(ApiError::from)?;
+
+                    let terms = ontology_nav::batch_terms(&ontology, &body.ids, &db_terms);
+                    let found = terms.iter().filter(|term| term.found).count();
+                    json_response(&TermBatchResponse { found, missing: terms.len() - found, terms })
+                }
+            }
+        });
+
+    // The "/all/terms/ids" path will return a json of all the terms in the database with the hpo_id as the key
+    let get_all_terms_ids = warp::path!("all" / "terms" / "ids")
+        .and(with_db(&db))
+        .and_then(|db: Arc<db::Db>| async move {
+            let terms = db.run(db::get_all_terms_ids).await.map_err(ApiError::from)?;
+            json_response(&terms)
+        });
+
     // The "/all/terms/names" path will return a json of all the terms in the database by name
-    let get_all_terms_names = warp::path!("all" / "terms" / "names").map(|| {
-        let db_path = get_db_path();
-        let terms = get_all_terms_names(db_path);
-    
-        let response = match terms {
-            Ok(terms) => {
-                let json_terms = serde_json::to_string(&terms).unwrap();
-    
-                warp::http::Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Content-Type", "application/json")
-                    .body(json_terms)  // Convert String directly to Body
-                    .unwrap_or_else(|_| warp::http::Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body("Internal server error".into())
-                        .unwrap())
-            },
-            Err(e) => {
-                // Log the error or handle it appropriately
-                eprintln!("Database error: {:?}", e);
-                warp::http::Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .body("error: db cannot be found".into())
-                    .unwrap()
+    let get_all_terms_names = warp::path!("all" / "terms" / "names")
+        .and(with_db(&db))
+        .and_then(|db: Arc<db::Db>| async move {
+            let terms = db.run(db::get_all_terms_names).await.map_err(ApiError::from)?;
+            json_response(&terms)
+        });
+
+    #[derive(Deserialize)]
+    struct TermSearchQuery {
+        q: String,
+        limit: Option<usize>, // Defaults to 20, at most 100
+        offset: Option<usize>,
+    }
+
+    // Ranked prefix and typo tolerant search over term names, synonyms and definitions
+    let search_terms = warp::path!("search" / "terms")
+        .and(warp::query::<TermSearchQuery>())
+        .and_then({
+            let term_index = Arc::clone(&term_index);
+
+            move |query: TermSearchQuery| {
+                let term_index = Arc::clone(&term_index);
+
+                async move {
+                    if query.q.trim().is_empty() {
+                        return Err(ApiError::BadRequest("q must not be empty".to_string()).into());
+                    }
+                    // Typo tolerant matching scans every term, so it is kept off the async workers
+                    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+                    let results = tokio::task::spawn_blocking(move || term_index.search(&query.q, limit, query.offset.unwrap_or(0)))
+                        .await.map_err(|e| ApiError::Internal(e.to_string()))?;
+                    json_response(&results)
+                }
+            }
+        });
+
+    // Walk the ontology from a term: parents, children, ancestors, descendants or path_to_root
+    let term_relations = warp::path!("term" / String / String)
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+
+            move |id: String, relation: String| {
+                let response = ontology_nav::related_terms(&ontology, &id, &relation)
+                    .map_err(Rejection::from)
+                    .and_then(|terms| json_response(&terms));
+                async move { response }
+            }
+        });
+
+    #[derive(Deserialize)]
+    struct CommonAncestorsQuery {
+        a: String,
+        b: String,
+    }
+
+    // The ancestors two terms share, with the most informative one picked out
+    let common_ancestors = warp::path!("common_ancestors")
+        .and(warp::query::<CommonAncestorsQuery>())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+
+            move |query: CommonAncestorsQuery| {
+                let response = ontology_nav::common_ancestors(&ontology, &query.a, &query.b)
+                    .map_err(Rejection::from)
+                    .and_then(|ancestors| json_response(&ancestors));
+                async move { response }
+            }
+        });
+
+    //The population's current snapshot, the same one its compare route scores against
+    let get_orpha_population = warp::path!("orpha_population")
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+
+            move |filter: cohort_filter::FilterParams| {
+                population_response(Arc::clone(&ontology), Arc::clone(&populations), "orpha".to_string(), filter)
             }
-        };
-        response
     });
 
-    //Use the population function to get the population structure from the csv
-    let get_orpha_population = warp::path!("orpha_population").map(|| {
-        let orpha_population = population::create_orpha_population(ORPHA_TSV_URL.to_string());
-        let json_orpha_population = serde_json::to_string(&orpha_population).unwrap();
+    //The population's current snapshot, the same one its compare route scores against
+    let get_udn_population = warp::path!("udn_population")
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
 
-        let response = warp::http::Response::builder()
-            .status(StatusCode::OK)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Content-Type", "application/json")
-            .body(json_orpha_population)  // Convert String directly to Body
-            .unwrap_or_else(|_| warp::http::Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body("Internal server error".into())
-                .unwrap());
-        response
+            move |filter: cohort_filter::FilterParams| {
+                population_response(Arc::clone(&ontology), Arc::clone(&populations), "udn".to_string(), filter)
+            }
     });
 
-    //Use the population function to get the population structure from the csv
-    let get_udn_population = warp::path!("udn_population").map(|| {
-        let udn_population = population::create_udn_population(UDN_CSV_URL.to_string());
-        let json_udn_population = serde_json::to_string(&udn_population).unwrap();
+    let get_decipher_population = warp::path!("decipher_population")
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
 
-        let response = warp::http::Response::builder()
-            .status(StatusCode::OK)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Content-Type", "application/json")
-            .body(json_udn_population)  // Convert String directly to Body
-            .unwrap_or_else(|_| warp::http::Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body("Internal server error".into())
-                .unwrap());
-        response
+            move |filter: cohort_filter::FilterParams| {
+                population_response(Arc::clone(&ontology), Arc::clone(&populations), "decipher".to_string(), filter)
+            }
     });
 
-    let get_decipher_population = warp::path!("decipher_population").map(|| {
-        let decipher_population = population::create_deciper_population(DECIPHER_DATA_URL.to_string());
-        let json_decipher_population = serde_json::to_string(&decipher_population).unwrap();
+    let get_clinvar_population = warp::path!("clinvar_population")
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
 
-        let response = warp::http::Response::builder()
-            .status(StatusCode::OK)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Content-Type", "application/json")
-            .body(json_decipher_population)  // Convert String directly to Body
-            .unwrap_or_else(|_| warp::http::Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body("Internal server error".into())
-                .unwrap());
-        response
+            move |filter: cohort_filter::FilterParams| {
+                population_response(Arc::clone(&ontology), Arc::clone(&populations), "clinvar".to_string(), filter)
+            }
     });
 
-    let get_clinvar_population = warp::path!("clinvar_population").map(|| {
-        let clinvar_population = population::create_clinvar_population(CLINVAR_DATA_URL.to_string());
-        let json_clinvar_population = serde_json::to_string(&clinvar_population).unwrap();
+    // The loaded populations with their size, version and when they were loaded
+    let list_populations = warp::path!("populations")
+        .and_then({
+            let populations = Arc::clone(&populations);
 
-        let response = warp::http::Response::builder()
-            .status(StatusCode::OK)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Content-Type", "application/json")
-            .body(json_clinvar_population)  // Convert String directly to Body
-            .unwrap_or_else(|_| warp::http::Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body("Internal server error".into())
-                .unwrap());
-        response
+            move || {
+                let response = json_response(&populations.list());
+                async move { response }
+            }
     });
 
-    // Get a map of all of the similarity scores for a given set of terms
-    let udn_compare = warp::path("compare_udn" )
-        .and(warp::path::param())
-        .map({
+    // Re-read a population's file and swap it in, the old snapshot is kept if the new one doesn't validate
+    let admin_reload_population = warp::path!("admin" / "populations" / String / "reload")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
             let ontology = Arc::clone(&ontology);
-            let population = Arc::clone(&udn_population);
+            let populations = Arc::clone(&populations);
 
-            move |param: String| {
-                let param = param.replace("%20", "");
-                let param_string = param.split(",").map(|s| s.to_string()).collect::<Vec<String>>();
-                // let param_u32 = param_string.iter().map(|s| s.replace("HP:", "").parse::<u32>().unwrap()).collect::<Vec<u32>>();
-                let param_u32 = param_string
-                    .iter()
-                    .filter_map(|s| {
-                        let id_str = s.replace("HP:", "");
-                        match id_str.parse::<u32>() {
-                            Ok(id) => {
-                                // Check if the term exists in the ontology before including it
-                                if ontology.hpo(id).is_some() {
-                                    Some(id)
-                                } else {
-                                    eprintln!("Warning: HPO term {} not found in ontology", s);
-                                    None
-                                }
-                            }
-                            Err(_) => {
-                                eprintln!("Warning: Failed to parse HPO ID: {}", s);
-                                None
-                            }
+            move |name: String, token: Option<String>| {
+                let ontology = Arc::clone(&ontology);
+                let populations = Arc::clone(&populations);
+
+                async move {
+                    if !admin::is_authorized(&token) {
+                        return Err(ApiError::Unauthorized.into());
+                    }
+
+                    let snapshot = tokio::task::spawn_blocking(move || populations.reload(&ontology, &name))
+                        .await
+                        .map_err(|e| ApiError::Internal(e.to_string()))??;
+                    println!("Reloaded population {} (version {}, {} individuals)", snapshot.name, snapshot.version, snapshot.population.len());
+                    json_response(&snapshot.summary())
+                }
+            }
+        });
+
+    #[derive(Serialize)]
+    struct UploadResponse {
+        population: populations::PopulationSummary,
+        #[serde(flatten)]
+        report: population_upload::UploadReport,
+    }
+
+    // Add or replace a population from a CSV, TSV or Phenopackets JSONL body, the column mapping is in the query
+    // string. It is stored so it is loaded again on restart
+    let admin_upload_population = warp::path!("admin" / "populations" / String)
+        .and(warp::post())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and(warp::query::<population_upload::ColumnMapping>())
+        .and(warp::body::content_length_limit(MAX_UPLOAD_BYTES))
+        .and(warp::body::bytes())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+
+            move |name: String, token: Option<String>, mapping: population_upload::ColumnMapping, body: warp::hyper::body::Bytes| {
+                let ontology = Arc::clone(&ontology);
+                let populations = Arc::clone(&populations);
+
+                async move {
+                    if !admin::is_authorized(&token) {
+                        return Err(ApiError::Unauthorized.into());
+                    }
+
+                    populations::check_upload_name(&name)?;
+                    let uploaded = tokio::task::spawn_blocking(move || {
+                        let parsed = population_upload::parse(&ontology, &name, &mapping, &body).map_err(ApiError::BadRequest)?;
+                        let provenance = population_store::Provenance {
+                            source: format!("upload:{}", mapping.file_name.as_deref().unwrap_or("upload")),
+                            format: mapping.format.to_lowercase(),
+                            file_hash: population_store::hash_bytes(&body),
+                            row_count: parsed.rows,
+                            individuals_stored: parsed.population.len(),
+                            uploaded: true,
+                            ingested_at: population_store::now(),
+                        };
+                        let report = parsed.report();
+                        let snapshot = populations.upload(&ontology, &name, parsed.population, provenance)?;
+                        Ok::<_, ApiError>(UploadResponse { population: snapshot.summary(), report })
+                    }).await.map_err(|e| ApiError::Internal(e.to_string()))??;
+                    println!("Uploaded population {} (version {}, {} individuals)", uploaded.population.name, uploaded.population.version, uploaded.population.individuals);
+                    json_response(&uploaded)
+                }
+            }
+        });
+
+    // Remove an uploaded population, the built in ones can't be deleted
+    let admin_delete_population = warp::path!("admin" / "populations" / String)
+        .and(warp::delete())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
+            let populations = Arc::clone(&populations);
+
+            move |name: String, token: Option<String>| {
+                let populations = Arc::clone(&populations);
+
+                async move {
+                    if !admin::is_authorized(&token) {
+                        return Err(ApiError::Unauthorized.into());
+                    }
+
+                    tokio::task::spawn_blocking({
+                        let name = name.clone();
+                        move || populations.delete(&name)
+                    }).await.map_err(|e| ApiError::Internal(e.to_string()))??;
+                    println!("Deleted population {}", name);
+                    json_response(&serde_json::json!({ "deleted": name }))
+                }
+            }
+        });
+
+    // Any loaded population by name, including uploaded ones
+    let get_population = warp::path!("population" / String)
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+
+            move |name: String, filter: cohort_filter::FilterParams| {
+                population_response(Arc::clone(&ontology), Arc::clone(&populations), name, filter)
+            }
+    });
+
+    // Group a population's individuals, narrowed by the usual filters, by phenotype similarity
+    let population_clusters = warp::path!("population" / String / "clusters")
+        .and(warp::query::<clustering::ClusterParams>())
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+
+            move |name: String, params: clustering::ClusterParams, filter: cohort_filter::FilterParams, token: Option<String>| {
+                let ontology = Arc::clone(&ontology);
+                let snapshot = populations.get(&name);
+
+                async move {
+                    let snapshot = snapshot.ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", name)))?;
+                    let filter = cohort_filter::CohortFilter::from_params(&ontology, &name, &filter)?;
+                    let authorized = admin::is_authorized(&token);
+
+                    // Every individual is scored against every other so it is kept off the async workers
+                    let cohort_snapshot = Arc::clone(&snapshot);
+                    let report = tokio::task::spawn_blocking(move || {
+                        let candidates = cohort_snapshot.cohort(&ontology, &filter)?;
+                        if candidates.len() > clustering::MAX_UNAUTHENTICATED && !authorized {
+                            return Err(ApiError::BadRequest(format!(
+                                "{} individuals to cluster, up to {} without the x-admin-token header, narrow the cohort with the filters or use the cluster subcommand",
+                                candidates.len(), clustering::MAX_UNAUTHENTICATED,
+                            )));
                         }
-                    })
-                    .collect();
-                let return_map = calc_scores::calc_scores(&ontology, param_u32, &population);
-                let return_map = serde_json::to_string(&return_map).unwrap();
+                        clustering::cluster(&ontology, &candidates, &params)
+                    }).await.map_err(|e| ApiError::Internal(e.to_string()))??;
+                    with_snapshot_headers(json_response(&report)?, &snapshot)
+                }
+            }
+        });
 
-                let response = Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Content-Type", "application/json")
-                    .body(warp::hyper::Body::from(return_map))
-                    .unwrap_or_else(|_| warp::http::Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body("Internal server error".into())
-                        .unwrap());
-                response
+    // Score a comma separated list of terms against any loaded population by name
+    let compare = warp::path!("compare" / String / String)
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and(warp::query::<gene_ranking::GeneParams>())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+            let simpheny = Arc::clone(&simpheny);
+
+            move |name: String, param: String, filter: cohort_filter::FilterParams, genes: gene_ranking::GeneParams, token: Option<String>| {
+                compare_population(Arc::clone(&ontology), param, Arc::clone(&populations), name, filter, genes, Arc::clone(&simpheny), token)
             }
     });
 
-    // Get a map of all of the similarity scores for a given set of terms
-    let orpha_compare = warp::path("compare_orpha")
-        .and(warp::path::param())
-        .map({
+    #[derive(Deserialize)]
+    struct CompareRequest {
+        terms: Vec<String>,
+        sources: Option<Vec<String>>, // Population names, every loaded population when left out
+        diagnosis: Option<String>, // diagnosed, undiagnosed or any, each population's default when left out
+        #[serde(default)]
+        genes: Vec<String>,
+        #[serde(default)]
+        has_terms: Vec<String>,
+        #[serde(default)]
+        attributes: Vec<String>, // e.g. ["site:SLC", "age>5"]
+        #[serde(default)]
+        candidate_genes: Vec<String>,
+        gene_mode: Option<String>, // flag, boost (default) or simpheny
+        gene_boost: Option<f32>,
+        data_bg: Option<String>,
+        seed: Option<u64>,
+    }
+
+    // Score terms against the filtered candidates of several populations at once, ranked together
+    let compare_filtered = warp::path!("compare")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and(warp::body::json())
+        .and_then({
             let ontology = Arc::clone(&ontology);
-            let population = Arc::clone(&orpha_population);
+            let populations = Arc::clone(&populations);
+            let simpheny = Arc::clone(&simpheny);
+
+            move |token: Option<String>, body: CompareRequest| {
+                let ontology = Arc::clone(&ontology);
+                let populations = Arc::clone(&populations);
+                let simpheny = Arc::clone(&simpheny);
+
+                async move {
+                    let hpo_ids = parse_hpo_ids(&ontology, &body.terms);
+                    if hpo_ids.is_empty() {
+                        return Err(ApiError::BadRequest("no HPO terms found in the ontology for the given terms".to_string()).into());
+                    }
+                    let sources = match &body.sources {
+                        Some(sources) => sources.clone(),
+                        None => populations.snapshots().iter().map(|snapshot| snapshot.name.clone()).collect(),
+                    };
+
+                    let mut cohorts = Vec::new();
+                    for source in &sources {
+                        let snapshot = populations.get(source).ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", source)))?;
+                        let filter = cohort_filter::CohortFilter::new(&ontology, source, body.diagnosis.as_deref(), &body.genes, &body.has_terms, &body.attributes)?;
+                        cohorts.push((snapshot, filter));
+                    }
 
-            move |param: String| {
-                let param = param.replace("%20", "");
-                let param_string = param.split(",").map(|s| s.to_string()).collect::<Vec<String>>();
-                // let param_u32 = param_string.iter().map(|s| s.replace("HP:", "").parse::<u32>().unwrap()).collect::<Vec<u32>>();
-                let param_u32 = param_string
-                    .iter()
-                    .filter_map(|s| {
-                        let id_str = s.replace("HP:", "");
-                        match id_str.parse::<u32>() {
-                            Ok(id) => {
-                                // Check if the term exists in the ontology before including it
-                                if ontology.hpo(id).is_some() {
-                                    Some(id)
-                                } else {
-                                    eprintln!("Warning: HPO term {} not found in ontology", s);
-                                    None
+                    let gene_ranking = gene_ranking::GeneRanking::new(&body.candidate_genes, body.gene_mode.as_deref(), body.gene_boost, body.data_bg.clone(), body.seed)?;
+                    // SimPheny's own background when every source is the same population
+                    let background_population = if sources.len() == 1 { sources[0].clone() } else { "udn".to_string() };
+
+                    let return_map = tokio::task::spawn_blocking(move || {
+                        // A single source is scored as it is, several are merged into one population
+                        let candidates = match cohorts.as_slice() {
+                            [(snapshot, filter)] => snapshot.cohort(&ontology, filter)?,
+                            _ => {
+                                let mut candidates: population::Population = HashMap::new();
+                                for (snapshot, filter) in &cohorts {
+                                    candidates.extend(snapshot.cohort(&ontology, filter)?.iter().map(|(id, individual)| (id.clone(), individual.clone())));
                                 }
+                                Arc::new(candidates)
                             }
-                            Err(_) => {
-                                eprintln!("Warning: Failed to parse HPO ID: {}", s);
-                                None
-                            }
+                        };
+                        let return_map = calc_scores::calc_scores(&ontology, hpo_ids.clone(), &candidates);
+                        match gene_ranking {
+                            Some(gene_ranking) => gene_ranking.apply(&ontology, &hpo_ids, &candidates, return_map, &background_population, &simpheny, admin::is_authorized(&token)),
+                            None => Ok(return_map),
                         }
-                    })
-                    .collect();
+                    }).await.map_err(|e| ApiError::Internal(e.to_string()))??;
+                    json_response(&return_map)
+                }
+            }
+        });
+
+    // Test which terms, counting the terms below them, a subset of a population has more often than the rest of it
+    let enrichment = warp::path!("enrichment")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+
+            move |body: term_enrichment::EnrichmentRequest| {
+                let ontology = Arc::clone(&ontology);
+                let snapshot = populations.get(&body.population);
+
+                async move {
+                    let snapshot = snapshot.ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", body.population)))?;
+                    let filter = cohort_filter::CohortFilter::new(&ontology, &body.population, body.diagnosis.as_deref(), &[], &[], &[])?;
+
+                    let cohort_snapshot = Arc::clone(&snapshot);
+                    let report = tokio::task::spawn_blocking(move || {
+                        let cohort = cohort_snapshot.cohort(&ontology, &filter)?;
+                        term_enrichment::enrich(&ontology, &cohort, &body)
+                    }).await.map_err(|e| ApiError::Internal(e.to_string()))??;
+                    with_snapshot_headers(json_response(&report)?, &snapshot)
+                }
+            }
+        });
 
-                let return_map = calc_scores::calc_scores(&ontology, param_u32, &population);
-                let return_map = serde_json::to_string(&return_map).unwrap();
+    // Rank the genes of an annotated VCF body by their rare, damaging variants and by how well their annotated
+    // terms match the patient's, the terms and filters are in the query string
+    let prioritize = warp::path!("prioritize")
+        .and(warp::post())
+        .and(warp::query::<vcf_prioritization::PrioritizeParams>())
+        .and(warp::body::content_length_limit(MAX_UPLOAD_BYTES))
+        .and(warp::body::bytes())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let gene_index = Arc::clone(&gene_index);
+
+            move |params: vcf_prioritization::PrioritizeParams, body: warp::hyper::body::Bytes| {
+                let ontology = Arc::clone(&ontology);
+                let gene_index = Arc::clone(&gene_index);
+
+                async move {
+                    let report = tokio::task::spawn_blocking(move || vcf_prioritization::prioritize(&ontology, &gene_index, &params, &body))
+                        .await
+                        .map_err(|e| ApiError::Internal(e.to_string()))??;
+                    json_response(&report)
+                }
+            }
+        });
 
-                let response = Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Content-Type", "application/json")
-                    .body(warp::hyper::Body::from(return_map))
-                    .unwrap_or_else(|_| warp::http::Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body("Internal server error".into())
-                        .unwrap());
-                response
+    // Matchmaker Exchange: partner nodes send an MME patient and get back the matching patients of the shareable
+    // populations, in the MME version negotiated from the Content-Type and Accept headers
+    let mme_match = warp::path!("match")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("x-auth-token"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::body::content_length_limit(matchmaker::MAX_REQUEST_BYTES))
+        .and(warp::body::bytes())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+            let gene_index = Arc::clone(&gene_index);
+
+            move |token: Option<String>, content_type: Option<String>, accept: Option<String>, body: warp::hyper::body::Bytes| {
+                let ontology = Arc::clone(&ontology);
+                let gene_index = Arc::clone(&gene_index);
+                let snapshots: Vec<_> = matchmaker::shareable_populations().iter().filter_map(|name| {
+                    let snapshot = populations.get(name);
+                    if snapshot.is_none() {
+                        eprintln!("Warning: MME population {} is not loaded", name);
+                    }
+                    snapshot
+                }).collect();
+
+                async move {
+                    if !matchmaker::is_authorized(&token) {
+                        return Err(ApiError::Unauthorized.into());
+                    }
+                    let media_type = matchmaker::negotiate(content_type.as_deref(), accept.as_deref())?;
+                    let request: matchmaker::MatchRequest = serde_json::from_slice(&body)
+                        .map_err(|e| ApiError::BadRequest(format!("not an MME match request: {}", e)))?;
+
+                    let matches = tokio::task::spawn_blocking(move || matchmaker::match_patient(&ontology, &gene_index, &snapshots, &request))
+                        .await
+                        .map_err(|e| ApiError::Internal(e.to_string()))??;
+                    // The partner's patient id is theirs to keep, only the result count is logged
+                    println!("MME match request: {} results", matches.results.len());
+                    let mut response = json_response(&matches)?;
+                    response.headers_mut().insert("Content-Type", warp::http::HeaderValue::from_static(media_type));
+                    Ok::<_, Rejection>(response)
+                }
             }
-    });
+        });
 
     // Get a map of all of the similarity scores for a given set of terms
-    let decipher_compare = warp::path("compare_decipher")
+    let udn_compare = warp::path("compare_udn" )
         .and(warp::path::param())
-        .map({
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and(warp::query::<gene_ranking::GeneParams>())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
             let ontology = Arc::clone(&ontology);
-            let population = Arc::clone(&deciper_population);
+            let populations = Arc::clone(&populations);
+            let simpheny = Arc::clone(&simpheny);
 
-            move |param: String| {
-                let param = param.replace("%20", "");
-                let param_string = param.split(",").map(|s| s.to_string()).collect::<Vec<String>>();
-                // let param_u32 = param_string.iter().map(|s| s.replace("HP:", "").parse::<u32>().unwrap()).collect::<Vec<u32>>();
-                let param_u32 = param_string
-                    .iter()
-                    .filter_map(|s| {
-                        let id_str = s.replace("HP:", "");
-                        match id_str.parse::<u32>() {
-                            Ok(id) => {
-                                // Check if the term exists in the ontology before including it
-                                if ontology.hpo(id).is_some() {
-                                    Some(id)
-                                } else {
-                                    eprintln!("Warning: HPO term {} not found in ontology", s);
-                                    None
-                                }
-                            }
-                            Err(_) => {
-                                eprintln!("Warning: Failed to parse HPO ID: {}", s);
-                                None
-                            }
-                        }
-                    })
-                    .collect();
+            move |param: String, filter: cohort_filter::FilterParams, genes: gene_ranking::GeneParams, token: Option<String>| {
+                compare_population(Arc::clone(&ontology), param, Arc::clone(&populations), "udn".to_string(), filter, genes, Arc::clone(&simpheny), token)
+            }
+    });
 
-                let return_map = calc_scores::calc_scores(&ontology, param_u32, &population);
-                let return_map = serde_json::to_string(&return_map).unwrap();
+    // Get a map of all of the similarity scores for a given set of terms
+    let orpha_compare = warp::path("compare_orpha")
+        .and(warp::path::param())
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and(warp::query::<gene_ranking::GeneParams>())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+            let simpheny = Arc::clone(&simpheny);
 
-                let response = Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Content-Type", "application/json")
-                    .body(warp::hyper::Body::from(return_map))
-                    .unwrap_or_else(|_| warp::http::Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body("Internal server error".into())
-                        .unwrap());
-                response
+            move |param: String, filter: cohort_filter::FilterParams, genes: gene_ranking::GeneParams, token: Option<String>| {
+                compare_population(Arc::clone(&ontology), param, Arc::clone(&populations), "orpha".to_string(), filter, genes, Arc::clone(&simpheny), token)
             }
     });
 
     // Get a map of all of the similarity scores for a given set of terms
-    let clinvar_compare = warp::path("compare_clinvar")
+    let decipher_compare = warp::path("compare_decipher")
         .and(warp::path::param())
-        .map({
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and(warp::query::<gene_ranking::GeneParams>())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
             let ontology = Arc::clone(&ontology);
-            let population = Arc::clone(&clinvar_population);
+            let populations = Arc::clone(&populations);
+            let simpheny = Arc::clone(&simpheny);
 
-            move |param: String| {
-                let param = param.replace("%20", "");
-                let param_string = param.split(",").map(|s| s.to_string()).collect::<Vec<String>>();
-                // let param_u32 = param_string.iter().map(|s| s.replace("HP:", "").parse::<u32>().unwrap()).collect::<Vec<u32>>();
-
-                let param_u32 = param_string
-                    .iter()
-                    .filter_map(|s| {
-                        let id_str = s.replace("HP:", "");
-                        match id_str.parse::<u32>() {
-                            Ok(id) => {
-                                // Check if the term exists in the ontology before including it
-                                if ontology.hpo(id).is_some() {
-                                    Some(id)
-                                } else {
-                                    eprintln!("Warning: HPO term {} not found in ontology", s);
-                                    None
-                                }
-                            }
-                            Err(_) => {
-                                eprintln!("Warning: Failed to parse HPO ID: {}", s);
-                                None
-                            }
-                        }
-                    })
-                    .collect();
+            move |param: String, filter: cohort_filter::FilterParams, genes: gene_ranking::GeneParams, token: Option<String>| {
+                compare_population(Arc::clone(&ontology), param, Arc::clone(&populations), "decipher".to_string(), filter, genes, Arc::clone(&simpheny), token)
+            }
+    });
 
-                let return_map = calc_scores::calc_scores(&ontology, param_u32, &population);
-                let return_map = serde_json::to_string(&return_map).unwrap();
+    // Get a map of all of the similarity scores for a given set of terms
+    let clinvar_compare = warp::path("compare_clinvar")
+        .and(warp::path::param())
+        .and(warp::query::<cohort_filter::FilterParams>())
+        .and(warp::query::<gene_ranking::GeneParams>())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+            let simpheny = Arc::clone(&simpheny);
 
-                let response = Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Content-Type", "application/json")
-                    .body(warp::hyper::Body::from(return_map))
-                    .unwrap_or_else(|_| warp::http::Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body("Internal server error".into())
-                        .unwrap());
-                response
+            move |param: String, filter: cohort_filter::FilterParams, genes: gene_ranking::GeneParams, token: Option<String>| {
+                compare_population(Arc::clone(&ontology), param, Arc::clone(&populations), "clinvar".to_string(), filter, genes, Arc::clone(&simpheny), token)
             }
     });
 
@@ -525,66 +1027,294 @@ async fn main() {
         num_query_genes: u32,
         num_hpo_terms: u32,
         data_bg: String, // Background data for the score calculation
+        seed: Option<u64>, // Replays a previous run when given, otherwise a fresh seed is drawn
+        precision: Option<f64>, // Target relative error of the phenotype p-value, sampling stops once it is reached
+        max_iterations: Option<u32>, // Upper bound on the number of random draws
     }
 
     let simpheny_score = warp::path("simpheny_score")
         .and(warp::post())
         .and(warp::body::json())
-        .map(move |body: SimphenyScoreRequest| {
+        .and_then({
             let ontology = Arc::clone(&ontology);
+            let backgrounds = Arc::clone(&backgrounds);
+            let simpheny_nulls = simpheny_nulls.clone();
+
+            move |body: SimphenyScoreRequest| {
+                let ontology = Arc::clone(&ontology);
+                let backgrounds = Arc::clone(&backgrounds);
+                let simpheny_nulls = simpheny_nulls.clone();
+
+                async move {
+                    // Unknown backgrounds are rejected rather than silently scored against UDN
+                    let params = backgrounds.read().unwrap().get(&body.data_bg).cloned()
+                        .ok_or_else(|| ApiError::BadRequest(format!("unknown data_bg: {}", body.data_bg)))?;
+
+                    // Default values for the simulation
+                    let precision: f64 = body.precision.filter(|p| *p > 0.0).unwrap_or(0.1);
+                    let max_iterations: u32 = body.max_iterations.unwrap_or(10000).clamp(1, 1_000_000);
+                    let terms_url: &str = TERMS_LIST_URL;
+                    let genes_url: &str = GENE_LIST_URL;
+                    let seed: u64 = body.seed.unwrap_or_else(|| rand::rng().random());
+                    // Terms need the prefix "HP:" removed and then parsed to u32
+                    let terms_cleaned = parse_hpo_ids(&ontology, &body.hit_terms);
+                    if terms_cleaned.is_empty() {
+                        return Err(ApiError::BadRequest("hit_terms has no HPO terms found in the ontology".to_string()).into());
+                    }
 
-            // Default values for the simulation
-            let iterations: u32 = 10000; // Number of iterations for the simulation
-            let terms_url: &str = TERMS_LIST_URL;
-            let genes_url: &str = GENE_LIST_URL;
-            // Terms need the prefix "HP:" removed and then parsed to u32
-            let terms_cleaned: Vec<u32> = body.hit_terms
-                .iter()
-                .filter_map(|s| {
-                    let id_str = s.replace("HP:", "");
-                    match id_str.parse::<u32>() {
-                        Ok(id) => {
-                            // Check if the term exists in the ontology before including it
-                            if ontology.hpo(id).is_some() {
-                                Some(id)
-                            } else {
-                                eprintln!("Warning: HPO term {} not found in ontology", s);
-                                None
-                            }
-                        }
-                        Err(_) => {
-                            eprintln!("Warning: Failed to parse HPO ID: {}", s);
-                            None
-                        }
+                    // The simulation can take a while so it is kept off the async workers
+                    let simpheny_score = tokio::task::spawn_blocking(move || {
+                        calc_simpheny_score::calc_simpheny_score(
+                            &ontology,
+                            terms_cleaned,
+                            body.gene_symbol,
+                            body.sim_score,
+                            body.num_query_genes,
+                            body.num_hpo_terms,
+                            &params,
+                            precision,
+                            max_iterations,
+                            seed,
+                            simpheny_nulls.as_deref(),
+                            terms_url,
+                            genes_url,
+                        )
+                    }).await
+                        .map_err(|e| ApiError::Internal(e.to_string()))?
+                        .map_err(ApiError::Internal)?;
+
+                    json_response(&simpheny_score)
+                }
+            }
+        });
+
+    #[derive(Deserialize)]
+    struct CalibrateRequest {
+        name: String, // The data_bg name the fitted parameters are stored under
+        population: String, // Any loaded population, see /populations
+        num_hpo_terms: Option<u32>,
+        num_query_genes: Option<u32>,
+        samples: Option<u32>,
+        draws: Option<u32>,
+        seed: Option<u64>,
+    }
+
+    // Fit and store Brown's method parameters for a background, see `calibrate` in cli.rs for the same from the command line
+    let admin_calibrate = warp::path!("admin" / "calibrate")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and(warp::body::json())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let backgrounds = Arc::clone(&backgrounds);
+            let populations = Arc::clone(&populations);
+
+            move |token: Option<String>, body: CalibrateRequest| {
+                let ontology = Arc::clone(&ontology);
+                let backgrounds = Arc::clone(&backgrounds);
+                let snapshot = populations.get(&body.population);
+
+                async move {
+                    if !admin::is_authorized(&token) {
+                        return Err(ApiError::Unauthorized.into());
                     }
-                })
-                .collect();
-            
-            // We aren't borrowing anything but the ontology because we don't need the other variables after this point
-            let simpheny_score: f64 = calc_simpheny_score::calc_simpheny_score(
-                &ontology,
-                terms_cleaned,
-                body.gene_symbol,
-                body.sim_score,
-                body.num_query_genes,
-                body.num_hpo_terms,
-                body.data_bg,
-                iterations,
-                terms_url,
-                genes_url,
-            );
-
-            let response = Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "application/json")
-                .body(serde_json::to_string(&simpheny_score)
-                    .unwrap_or_else(|_| "Error serializing response".to_string()));
-            response
+
+                    // Fitting runs thousands of similarity calculations so it is kept off the async workers
+                    let fitted = tokio::task::spawn_blocking(move || {
+                        let snapshot = snapshot.ok_or_else(|| ApiError::BadRequest(format!("unknown population: {}", body.population)))?;
+                        let all_term_list = calc_simpheny_score::load_term_list(&ontology, TERMS_LIST_URL)
+                            .map_err(|e| ApiError::Internal(format!("could not read the term list: {}", e)))?;
+                        let all_gene_list = calc_simpheny_score::load_gene_list(GENE_LIST_URL)
+                            .map_err(|e| ApiError::Internal(format!("could not read the gene list: {}", e)))?;
+                        let params = simpheny_background::fit_browns_params(
+                            &ontology,
+                            &body.population,
+                            &snapshot.population,
+                            &all_term_list,
+                            &all_gene_list,
+                            body.num_hpo_terms.unwrap_or(calc_simpheny_score::MAX_QUERY_TERMS),
+                            body.num_query_genes.unwrap_or(1),
+                            body.samples.unwrap_or(200),
+                            body.draws.unwrap_or(500),
+                            body.seed.unwrap_or_else(|| rand::rng().random()),
+                        ).ok_or_else(|| ApiError::BadRequest("could not fit parameters for this population, it needs individuals sharing a gene".to_string()))?;
+                        Ok::<_, ApiError>((body.name, params))
+                    }).await.map_err(|e| ApiError::Internal(e.to_string()))?;
+                    let (name, params) = fitted?;
+
+                    // The guard is dropped before the file is written so readers aren't held up by the disk
+                    let saved = {
+                        let mut backgrounds = backgrounds.write().unwrap();
+                        backgrounds.insert(name, params.clone());
+                        backgrounds.clone()
+                    };
+                    let written = tokio::task::spawn_blocking(move || simpheny_background::save_backgrounds(BACKGROUNDS_URL, &saved))
+                        .await.map_err(|e| ApiError::Internal(e.to_string()))?;
+                    if let Err(e) = written {
+                        eprintln!("Warning: could not save backgrounds: {}", e);
+                    }
+                    json_response(&params)
+                }
+            }
+        });
+
+    #[derive(Deserialize)]
+    struct SimilarityMatrixRequest {
+        population: String,
+        against: Option<String>, // A second population for the columns, the rows' population when left out
+        format: Option<String>, // neighbors (default), tsv or binary
+        top_n: Option<usize>, // Neighbors kept per individual
+        diagnosis: Option<String>, // diagnosed, undiagnosed or any, each population's default when left out
+    }
+
+    // Start computing the similarity of every individual in a population to every individual in it or in another
+    // population. The job id follows from the request, so sending it again after a restart resumes the work
+    let admin_similarity_matrix = warp::path!("admin" / "jobs" / "similarity_matrix")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and(warp::body::json())
+        .and_then({
+            let ontology = Arc::clone(&ontology);
+            let populations = Arc::clone(&populations);
+            let jobs = Arc::clone(&jobs);
+
+            move |token: Option<String>, body: SimilarityMatrixRequest| {
+                let ontology = Arc::clone(&ontology);
+                let populations = Arc::clone(&populations);
+                let jobs = Arc::clone(&jobs);
+
+                async move {
+                    if !admin::is_authorized(&token) {
+                        return Err(ApiError::Unauthorized.into());
+                    }
+                    let format_name = body.format.as_deref().unwrap_or("neighbors");
+                    let format = similarity_matrix::MatrixFormat::parse(format_name)
+                        .ok_or_else(|| ApiError::BadRequest(format!("unknown format: {}, expected neighbors, tsv or binary", format_name)))?;
+                    let top_n = body.top_n.unwrap_or(similarity_matrix::DEFAULT_TOP_N).max(1);
+                    let against = body.against.clone().unwrap_or_else(|| body.population.clone());
+
+                    let mut sides = Vec::new();
+                    for name in [&body.population, &against] {
+                        let snapshot = populations.get(name).ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", name)))?;
+                        let filter = cohort_filter::CohortFilter::new(&ontology, name, body.diagnosis.as_deref(), &[], &[], &[])?;
+                        sides.push((snapshot, filter));
+                    }
+
+                    let mut id = format!("similarity_matrix-{}-{}-{}", body.population, against, format.name());
+                    if format == similarity_matrix::MatrixFormat::Neighbors {
+                        id.push_str(&format!("-top{}", top_n));
+                    }
+                    if let Some(diagnosis) = &body.diagnosis {
+                        id.push_str(&format!("-{}", diagnosis.trim().to_lowercase()));
+                    }
+                    let out = format!("{}/{}.{}", JOBS_DIR, id, format.extension());
+
+                    let job = jobs.start(&id, "similarity_matrix", move |progress| {
+                        std::fs::create_dir_all(JOBS_DIR).map_err(|e| format!("could not create {}: {}", JOBS_DIR, e))?;
+                        let rows = sides[0].0.cohort(&ontology, &sides[0].1).map_err(|e| e.message())?;
+                        let columns = sides[1].0.cohort(&ontology, &sides[1].1).map_err(|e| e.message())?;
+                        let rows = similarity_matrix::Profiles::new(&ontology, &rows);
+                        let columns = similarity_matrix::Profiles::new(&ontology, &columns);
+                        let summary = similarity_matrix::compute(&ontology, &rows, &columns, format, top_n, &out, progress)
+                            .map_err(|e| format!("could not write {}: {}", out, e))?;
+                        println!("Wrote a {} x {} similarity matrix to {} ({} chunks resumed)", summary.rows, summary.columns, summary.out, summary.resumed_chunks);
+                        Ok(summary.out)
+                    });
+                    json_response_with_status(&job, StatusCode::ACCEPTED)
+                }
+            }
+        });
+
+    // Every job started since the server came up, newest first
+    let admin_list_jobs = warp::path!("admin" / "jobs")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
+            let jobs = Arc::clone(&jobs);
+
+            move |token: Option<String>| {
+                let response = if admin::is_authorized(&token) {
+                    json_response(&jobs.list())
+                } else {
+                    Err(ApiError::Unauthorized.into())
+                };
+                async move { response }
+            }
+        });
+
+    // A job's status and progress
+    let admin_get_job = warp::path!("admin" / "jobs" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
+            let jobs = Arc::clone(&jobs);
+
+            move |id: String, token: Option<String>| {
+                let response = if admin::is_authorized(&token) {
+                    jobs.get(&id)
+                        .ok_or_else(|| ApiError::NotFound(format!("unknown job: {}", id)).into())
+                        .and_then(|job| json_response(&job))
+                } else {
+                    Err(ApiError::Unauthorized.into())
+                };
+                async move { response }
+            }
+        });
+
+    // The file a finished job wrote
+    let admin_job_result = warp::path!("admin" / "jobs" / String / "result")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and_then({
+            let jobs = Arc::clone(&jobs);
+
+            move |id: String, token: Option<String>| {
+                let job = jobs.get(&id);
+
+                async move {
+                    if !admin::is_authorized(&token) {
+                        return Err(ApiError::Unauthorized.into());
+                    }
+                    let job = job.ok_or_else(|| ApiError::NotFound(format!("unknown job: {}", id)))?;
+                    let output = match (job.status, job.output) {
+                        (jobs::JobStatus::Done, Some(output)) => output,
+                        (jobs::JobStatus::Failed, _) => return Err(ApiError::BadRequest(format!("job {} failed: {}", id, job.error.unwrap_or_default())).into()),
+                        _ => return Err(ApiError::BadRequest(format!("job {} is still running ({} of {})", id, job.done, job.total)).into()),
+                    };
+                    let body = tokio::fs::read(&output).await
+                        .map_err(|e| ApiError::Internal(format!("could not read {}: {}", output, e)))?;
+                    let content_type = if output.ends_with(".gz") { "application/gzip" } else { "text/tab-separated-values" };
+                    let file_name = output.rsplit('/').next().unwrap_or(&output).to_string();
+
+                    let response = Response::builder()
+                        .status(StatusCode::OK)
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Content-Type", content_type)
+                        .header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name))
+                        .body(body)
+                        .map_err(|e| ApiError::Internal(e.to_string()))?;
+                    Ok::<_, Rejection>(response)
+                }
+            }
+        });
+
+    // List the data_bg names SimPheny accepts and the parameters behind them
+    let simpheny_backgrounds = warp::path!("simpheny_backgrounds")
+        .and_then({
+            let backgrounds = Arc::clone(&backgrounds);
+
+            move || {
+                let response = json_response(&*backgrounds.read().unwrap());
+                async move { response }
+            }
         });
 
     //Combine all the routes and serve them
-    let routes = home
+    // Grouped and boxed so the combined filter type stays within the compiler's limits
+    let term_routes = home
         .or(check_db) // "/check_db"
+        .or(health) // "/health"
+        .or(get_version) // "/version"
         .or(get_genes_for_term) // "/id/get_genes/{term_id}"
         .or(get_terms_for_gene) // "/gene/get_terms/{gene_id}"
         .or(get_terms_for_null_gene) // "/gene/get_terms"
@@ -593,9 +1323,22 @@ async fn main() {
         .or(get_gene_by_id) // "/gene/id/{gene_id}"
         .or(get_gene_by_name) // "/gene/name/{gene_name}"
         .or(get_genes_from_names) // "/gene/names/{gene_names}" (comma separated)
+        .or(resolve_genes) // "/gene/resolve/{gene_names}" (comma separated)
+        .or(search_genes) // "/search/genes?q={prefix}&limit={limit}"
+        .or(get_disease) // "/disease/{disease_id}"
+        .or(get_terms_for_disease) // "/disease/{disease_id}/terms"
+        .or(get_genes_for_disease) // "/disease/{disease_id}/genes"
+        .or(search_diseases) // "/search/diseases?q={query}&limit={limit}"
+        .or(terms_batch) // "/terms/batch" (POST)
         .or(get_all_terms_ids) // "/all/terms/ids"
         .or(get_all_terms_names) // "/all/terms/names"
-        .or(udn_compare) // "/compare/udn/{term_ids}" (comma separated)
+        .or(search_terms) // "/search/terms?q={query}&limit={limit}&offset={offset}"
+        .or(term_relations) // "/term/{term_id}/{parents|children|ancestors|descendants|path_to_root}"
+        .or(common_ancestors) // "/common_ancestors?a={term_id}&b={term_id}"
+        .map(Reply::into_response)
+        .boxed();
+
+    let population_routes = udn_compare // "/compare/udn/{term_ids}" (comma separated)
         .or(orpha_compare) // "/compare/orpha/{term_ids}" (comma separated)
         .or(decipher_compare) // "/compare/decipher/{term_ids}" (comma separated)
         .or(clinvar_compare) // "/compare/clinvar/{term_ids}" (comma separated)
@@ -603,12 +1346,40 @@ async fn main() {
         .or(get_udn_population)// "/udn_population"
         .or(get_decipher_population) // "/decipher_population"
         .or(get_clinvar_population) // "/clinvar_population"
-        .or(simpheny_score); // "/simpheny_score"
-    
+        .or(list_populations) // "/populations"
+        .or(admin_reload_population) // "/admin/populations/{name}/reload"
+        .or(admin_upload_population) // "/admin/populations/{name}?format={csv|tsv|phenopackets}&id=...&terms=..." (POST)
+        .or(admin_delete_population) // "/admin/populations/{name}" (DELETE)
+        .or(get_population) // "/population/{name}"
+        .or(population_clusters) // "/population/{name}/clusters?method={graph|hierarchical}&top_n=..."
+        .or(compare) // "/compare/{name}/{term_ids}" (comma separated)
+        .or(compare_filtered) // "/compare" (POST)
+        .or(enrichment) // "/enrichment" (POST)
+        .or(prioritize) // "/prioritize?terms={term_ids}&max_af=...&impacts=..." (POST)
+        .or(mme_match) // "/match" (POST)
+        .or(simpheny_score) // "/simpheny_score"
+        .or(simpheny_backgrounds) // "/simpheny_backgrounds"
+        .or(admin_calibrate) // "/admin/calibrate"
+        .map(Reply::into_response)
+        .boxed();
+
+    let job_routes = admin_similarity_matrix // "/admin/jobs/similarity_matrix" (POST)
+        .or(admin_list_jobs) // "/admin/jobs"
+        .or(admin_get_job) // "/admin/jobs/{id}"
+        .or(admin_job_result) // "/admin/jobs/{id}/result"
+        .map(Reply::into_response)
+        .boxed();
+
+    let routes = term_routes
+        .or(population_routes)
+        .or(job_routes)
+        .recover(error::handle_rejection); // Every failure becomes a JSON {code, message} body
+
     let cors = cors()
         .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "OPTIONS"])
-        .allow_headers(vec!["content-type"]);
+        .allow_methods(vec!["GET", "POST", "DELETE", "OPTIONS"])
+        .allow_headers(vec!["content-type", "accept", "x-admin-token", "x-auth-token"])
+        .expose_headers(vec!["x-population-version", "x-population-loaded-at"]);
 
     warp::serve(routes.with(cors))
     .run(([127, 0, 0, 1], 8911))
@@ -616,243 +1387,108 @@ async fn main() {
 }
 
 //-------------
-// Database functions
+// Route helpers
 //-------------
 
-fn get_db_path() -> String {
-    let db_path = String::from("/hpoAssociations/hpo.db"); //Production
-    // let db_path = String::from("/Users/emerson/Documents/Code/pheno_matcher_be_rust/src/hpoAssociations/hpo.db"); //Development
-    db_path
+// Serializes a route's result as a 200 JSON response
+fn json_response<T: Serialize + ?Sized>(value: &T) -> Result<Response<String>, Rejection> {
+    json_response_with_status(value, StatusCode::OK)
 }
 
-fn get_gene_by_id(db_path: String, gene_id: String) -> Result<HashMap<String, String>, rusqlite::Error> {
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT * FROM Genes WHERE gene_id=?")?;
-    let mut gene_iter = stmt.query_map([&gene_id], |row| {
-        let mut gene = HashMap::new();
-        gene.insert("gene_id".to_string(), row.get(0)?);
-        gene.insert("gene_symbol".to_string(), row.get(1)?);
-        Ok(gene)
-    })?;
-
-    //there should only be one gene returned
-    let gene = match gene_iter.next() {
-        Some(gene) => gene?,
-        None => {
-            let mut gene = HashMap::new();
-            gene.insert("gene_id".to_string(), gene_id.to_string());
-            gene.insert("gene_symbol".to_string(), "".to_string());
-            gene
-        }
-    };
-    Ok(gene)
-}
+fn json_response_with_status<T: Serialize + ?Sized>(value: &T, status: StatusCode) -> Result<Response<String>, Rejection> {
+    let body = serde_json::to_string(value).map_err(|e| ApiError::Internal(format!("could not serialize response: {}", e)))?;
 
-fn get_gene_by_name(db_path: String, gene_name: String) -> Result<HashMap<String, String>, rusqlite::Error> {
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT * FROM Genes WHERE gene_symbol COLLATE NOCASE LIKE ?")?;
-    let mut gene_iter = stmt.query_map([&gene_name], |row| {
-        let mut gene = HashMap::new();
-        gene.insert("gene_id".to_string(), row.get(0)?);
-        gene.insert("gene_symbol".to_string(), row.get(1)?);
-        Ok(gene)
-    })?;
-
-    //there should only be one gene returned
-    let gene = match gene_iter.next() {
-        Some(gene) => gene?,
-        None => {
-            let mut gene = HashMap::new();
-            gene.insert("gene_id".to_string(), "".to_string());
-            gene.insert("gene_symbol".to_string(), gene_name.to_string());
-            gene
-        }
-    };
-    Ok(gene)
+    let response = Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Content-Type", "application/json")
+        .body(body)
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    Ok(response)
 }
 
-fn get_genes_from_names(db_path: String, gene_names: Vec<String>) -> Result<Vec<HashMap<String, String>>>{
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT * FROM Genes WHERE gene_symbol COLLATE NOCASE LIKE ?")?;
-    let mut genes: Vec<HashMap<String, String>> = Vec::new();
-    for gene_name in gene_names {
-        let mut gene_iter = stmt.query_map([&gene_name], |row| {
-            let mut gene = HashMap::new();
-            gene.insert("gene_id".to_string(), row.get(0)?);
-            gene.insert("gene_symbol".to_string(), row.get(1)?);
-            Ok(gene)
-        })?;
-
-        //there should only be one gene returned for each gene name
-        let gene = match gene_iter.next() {
-            Some(gene) => gene?,
-            None => {
-                let mut gene = HashMap::new();
-                gene.insert("gene_id".to_string(), "".to_string());
-                gene.insert("gene_symbol".to_string(), gene_name.to_string());
-                gene
-            }
-        };
-        genes.push(gene);
-    }
-    Ok(genes)
+// Turns "HP:0001250" style ids into the ontology's u32 ids, dropping (with a warning) any that don't parse or aren't in the ontology
+fn parse_hpo_ids(ontology: &Ontology, ids: &[String]) -> Vec<u32> {
+    ids.iter()
+        .filter_map(|s| {
+            let id_str = s.replace("HP:", "");
+            match id_str.parse::<u32>() {
+                Ok(id) => {
+                    // Check if the term exists in the ontology before including it
+                    if ontology.hpo(id).is_some() {
+                        Some(id)
+                    } else {
+                        eprintln!("Warning: HPO term {} not found in ontology", s);
+                        None
+                    }
+                }
+                Err(_) => {
+                    eprintln!("Warning: Failed to parse HPO ID: {}", s);
+                    None
+                }
+            }
+        })
+        .collect()
 }
 
-fn get_genes_for_term(db_path: String, term_id: String) -> Result<Vec<HashMap<String, String>>> {
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare(r#"
-        SELECT term_to_gene.*, genes.gene_symbol, diseases.disease_name
-        FROM term_to_gene 
-        LEFT JOIN genes ON term_to_gene.gene_id = genes.gene_id
-        JOIN diseases ON term_to_gene.disease_id = diseases.disease_id 
-        WHERE term_to_gene.term_id=?"#
-    )?;
-    
-    let gene_iter = stmt.query_map([term_id], |row| {
-        let mut gene = HashMap::new();
-        gene.insert("term_id".to_string(), row.get(0)?);
-        gene.insert("gene_id".to_string(), row.get(1)?);
-        gene.insert("frequency".to_string(), row.get(2)?);
-        gene.insert("disease_id".to_string(), row.get(3)?);
-        gene.insert("gene_symbol".to_string(), row.get(4)?);
-        gene.insert("disease_name".to_string(), row.get(5)?);
-        Ok(gene)
-    })?;
-
-    let mut genes: Vec<HashMap<String, String>> = Vec::new();
-    for gene in gene_iter {
-        let gene = gene?;
-        genes.push(gene);
+// Scores a comma separated list of terms against the filtered candidates of a population's current snapshot for the
+// compare routes, 400 if none of the terms are usable. Candidate genes, when given, rerank the hits. The scoring runs
+// on the blocking pool since a SimPheny rerank simulates p-values for every hit
+#[allow(clippy::too_many_arguments)]
+async fn compare_population(ontology: Arc<Ontology>, param: String, populations: Arc<populations::Populations>, name: String, filter: cohort_filter::FilterParams, genes: gene_ranking::GeneParams, simpheny: Arc<gene_ranking::Simpheny>, token: Option<String>) -> Result<Response<String>, Rejection> {
+    let snapshot = populations.get(&name).ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", name)))?;
+    let param = param.replace("%20", "");
+    let param_string = param.split(',').map(|s| s.to_string()).collect::<Vec<String>>();
+    let param_u32 = parse_hpo_ids(&ontology, &param_string);
+    if param_u32.is_empty() {
+        return Err(ApiError::BadRequest(format!("no HPO terms found in the ontology for: {}", param)).into());
     }
-    Ok(genes)
-}
 
-fn get_terms_for_gene(db_path: String, gene_id: String) -> Result<Vec<HashMap<String, String>>> {
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare(r#"
-        SELECT term_to_gene.*, genes.gene_symbol, terms.name, diseases.disease_name
-        FROM term_to_gene
-        JOIN genes ON term_to_gene.gene_id = genes.gene_id 
-        LEFT JOIN terms ON term_to_gene.term_id = terms.term_id 
-        JOIN diseases ON term_to_gene.disease_id = diseases.disease_id
-        WHERE term_to_gene.gene_id=?"#
-    )?;
-    
-    let phen_iter = stmt.query_map([gene_id], |row| {
-        let mut phen = HashMap::new();
-        phen.insert("term_id".to_string(), row.get(0)?);
-        phen.insert("gene_id".to_string(), row.get(1)?);
-        phen.insert("frequency".to_string(), row.get(2)?);
-        phen.insert("disease_id".to_string(), row.get(3)?);
-        phen.insert("gene_symbol".to_string(), row.get(4)?);
-        phen.insert("name".to_string(), row.get(5)?);
-        phen.insert("disease_name".to_string(), row.get(6)?);
-        Ok(phen)
-    })?;
-
-    let mut phens: Vec<HashMap<String, String>> = Vec::new();
-    for phen in phen_iter {
-        let phen = phen?;
-        phens.push(phen);
-    }
-    Ok(phens)
+    let filter = cohort_filter::CohortFilter::from_params(&ontology, &name, &filter)?;
+    let gene_ranking = gene_ranking::GeneRanking::from_params(&genes)?;
+    let cohort_snapshot = Arc::clone(&snapshot);
+    let return_map = tokio::task::spawn_blocking(move || {
+        let candidates = cohort_snapshot.cohort(&ontology, &filter)?;
+        let return_map = calc_scores::calc_scores(&ontology, param_u32.clone(), &candidates);
+        match gene_ranking {
+            Some(gene_ranking) => gene_ranking.apply(&ontology, &param_u32, &candidates, return_map, &name, &simpheny, admin::is_authorized(&token)),
+            None => Ok(return_map),
+        }
+    }).await.map_err(|e| ApiError::Internal(e.to_string()))??;
+    with_snapshot_headers(json_response(&return_map)?, &snapshot)
 }
 
-fn get_term_id(db_path: String, term_id: String) -> Result<HashMap<String, String>, rusqlite::Error> {
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT * FROM Terms WHERE term_id=?")?;
-    let mut term_iter = stmt.query_map([term_id], |row| {
-        let mut term = HashMap::new();
-        term.insert("hpo_id".to_string(), row.get(0)?);
-        term.insert("name".to_string(), row.get(1)?);
-        term.insert("definition".to_string(), row.get(2)?);
-        term.insert("comment".to_string(), row.get(3)?);
-        term.insert("synonyms".to_string(), row.get(4)?);
-
-        Ok(term)
-    })?;
-
-    //there should only be one term returned
-    let term = term_iter.next().unwrap()?;
-    Ok(term)
+// A population's individuals from its current snapshot that match the filter, filtered and serialized on the
+// blocking pool since a population can be large
+async fn population_response(ontology: Arc<Ontology>, populations: Arc<populations::Populations>, name: String, filter: cohort_filter::FilterParams) -> Result<Response<String>, Rejection> {
+    let snapshot = populations.get(&name).ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", name)))?;
+    let filter = cohort_filter::CohortFilter::from_params(&ontology, &name, &filter)?;
+    let cohort_snapshot = Arc::clone(&snapshot);
+    let response = tokio::task::spawn_blocking(move || json_response(&*cohort_snapshot.cohort(&ontology, &filter)?))
+        .await.map_err(|e| ApiError::Internal(e.to_string()))??;
+    with_snapshot_headers(response, &snapshot)
 }
 
-fn get_term_name(db_path: String, term_name: String) -> Result<HashMap<String, String>, rusqlite::Error> {
-    let conn = Connection::open(db_path)?;
-    let mut stmt = conn.prepare("SELECT * FROM Terms WHERE name COLLATE NOCASE LIKE ?")?;
-    let mut term_iter = stmt.query_map([term_name], |row| {
-        let mut term = HashMap::new();
-        term.insert("hpo_id".to_string(), row.get(0)?);
-        term.insert("name".to_string(), row.get(1)?);
-        term.insert("definition".to_string(), row.get(2)?);
-        term.insert("comment".to_string(), row.get(3)?);
-        term.insert("synonyms".to_string(), row.get(4)?);
-
-        Ok(term)
-    })?;
-
-    //there should only be one term returned
-    let term = match term_iter.next() {
-        Some(term) => term?,
-        None => {
-            let mut term = HashMap::new();
-            term.insert("hpo_id".to_string(), "".to_string());
-            term.insert("name".to_string(), "".to_string());
-            term.insert("definition".to_string(), "".to_string());
-            term.insert("comment".to_string(), "".to_string());
-            term.insert("synonyms".to_string(), "".to_string());
-            term
-        }
-    };
-    Ok(term)
+// Tags a response with the snapshot it was built from, so clients can tell when a listing and a compare disagree
+fn with_snapshot_headers(mut response: Response<String>, snapshot: &populations::PopulationSnapshot) -> Result<Response<String>, Rejection> {
+    let headers = response.headers_mut();
+    headers.insert("X-Population-Version", snapshot.version.into());
+    headers.insert("X-Population-Loaded-At", snapshot.loaded_at.into());
+    Ok(response)
 }
 
-fn get_all_terms_ids(db_path: String) -> Result<HashMap<String, HashMap<String, String>>, rusqlite::Error> {
-    let conn = Connection::open(db_path)?;
-
-    let mut by_hpo_id: HashMap<String, HashMap<String, String>> = HashMap::new();
-
-    let mut stmt = conn.prepare("SELECT * FROM Terms")?;
-
-    let term_iter = stmt.query_map([], |row| {
-        //make a new hashmap from the row itself
-        let mut term: HashMap<String, String> = HashMap::new();
-        term.insert("hpo_id".to_string(), row.get(0)?);
-        term.insert("name".to_string(), row.get(1)?);
-        term.insert("definition".to_string(), row.get(2)?);
-        term.insert("comment".to_string(), row.get(3)?);
-        term.insert("synonyms".to_string(), row.get(4)?);
-        Ok(term)
-    })?;
+//-------------
+// Database functions
+//-------------
 
-    for term in term_iter {
-        let term = term?;
-        //put the term into the hashmap with the hpo_id as the key and the term as the value
-        by_hpo_id.insert(term.get("hpo_id").unwrap().to_string(), term.clone());
-    }
-    Ok(by_hpo_id)
+fn get_db_path() -> String {
+    let db_path = String::from("/hpoAssociations/hpo.db"); //Production
+    // let db_path = String::from("/Users/emerson/Documents/Code/pheno_matcher_be_rust/src/hpoAssociations/hpo.db"); //Development
+    db_path
 }
 
-fn get_all_terms_names(db_path: String) -> Result<HashMap<String, HashMap<String, String>>, rusqlite::Error> {
-    let conn = Connection::open(db_path)?;
-    let mut by_name: HashMap<String, HashMap<String, String>> = HashMap::new();
-    let mut stmt = conn.prepare("SELECT * FROM Terms")?;
-
-    let term_iter = stmt.query_map([], |row| {
-        //make a new hashmap from the row itself
-        let mut term: HashMap<String, String> = HashMap::new();
-        term.insert("hpo_id".to_string(), row.get(0)?);
-        term.insert("name".to_string(), row.get(1)?);
-        term.insert("definition".to_string(), row.get(2)?);
-        term.insert("comment".to_string(), row.get(3)?);
-        term.insert("synonyms".to_string(), row.get(4)?);
-        Ok(term)
-    })?;
-
-    for term in term_iter {
-        let term = term?;
-        //put the term into the hashmap with the name as the key and the term as the value
-        by_name.insert(term.get("name").unwrap().to_string(), term.clone());
-    }
-    Ok(by_name)
-}
\ No newline at end of file
+// Hands each route its own handle to the shared pool
+fn with_db(db: &Arc<db::Db>) -> impl Filter<Extract = (Arc<db::Db>,), Error = std::convert::Infallible> + Clone {
+    let db = Arc::clone(db);
+    warp::any().map(move || Arc::clone(&db))
+}
@@ -0,0 +1,264 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use hpo::{HpoSet, Ontology};
+use hpo::term::HpoGroup;
+use crate::calc_scores;
+use crate::error::ApiError;
+use crate::gene_search::GeneIndex;
+use crate::population;
+use crate::populations::PopulationSnapshot;
+
+// Partner nodes' tokens, comma separated. /match is only enabled when this is set, requests must send one of them
+// in the X-Auth-Token header
+pub const TOKENS_ENV: &str = "PHENO_MATCHER_MME_TOKENS";
+// The populations partners can match against, comma separated, udn when unset
+pub const POPULATIONS_ENV: &str = "PHENO_MATCHER_MME_POPULATIONS";
+// Who partners contact about a match, returned with every matched patient
+pub const CONTACT_NAME_ENV: &str = "PHENO_MATCHER_MME_CONTACT_NAME";
+pub const CONTACT_HREF_ENV: &str = "PHENO_MATCHER_MME_CONTACT_HREF";
+
+// The MME API versions served, oldest first, with their media types
+const VERSIONS: [(&str, &str); 2] = [
+    ("1.0", "application/vnd.ga4gh.matchmaker.v1.0+json"),
+    ("1.1", "application/vnd.ga4gh.matchmaker.v1.1+json"),
+];
+
+pub const MAX_REQUEST_BYTES: u64 = 1024 * 1024;
+
+// At most this many patients are returned. Without genes to match on, patients below MIN_PHENOTYPE_SCORE aren't
+const MAX_RESULTS: usize = 20;
+const MIN_PHENOTYPE_SCORE: f64 = 0.3;
+
+const DISCLAIMER: &str = "The data in the Matchmaker Exchange is provided for research use only. Matches are \
+    based on phenotype similarity and shared genes and are not a diagnosis.";
+
+#[derive(Deserialize, Debug)]
+pub struct MatchRequest {
+    pub patient: Patient,
+}
+
+// The MME patient record, only the parts used for matching are read. Extra fields sent are ignored
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Patient {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub contact: Contact,
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    #[serde(default, rename = "genomicFeatures")]
+    pub genomic_features: Vec<GenomicFeature>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Contact {
+    pub name: String,
+    pub href: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub institution: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Feature {
+    pub id: String, // An HPO id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed: Option<String>, // "yes" (the default) or "no"
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GenomicFeature {
+    pub gene: GeneId,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GeneId {
+    pub id: String, // A symbol, an HGNC id or an NCBI gene id
+}
+
+#[derive(Serialize, Debug)]
+pub struct MatchResponse {
+    pub results: Vec<MatchResult>,
+    #[serde(rename = "_disclaimer")]
+    pub disclaimer: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MatchResult {
+    pub score: Score,
+    pub patient: Patient,
+}
+
+// MME only defines the overall patient score, the parts it was made from are added as "_" fields
+#[derive(Serialize, Debug)]
+pub struct Score {
+    pub patient: f64,
+    #[serde(rename = "_phenotype", skip_serializing_if = "Option::is_none")]
+    pub phenotype: Option<f64>,
+    #[serde(rename = "_genotype", skip_serializing_if = "Option::is_none")]
+    pub genotype: Option<f64>,
+}
+
+pub fn is_authorized(token: &Option<String>) -> bool {
+    match std::env::var(TOKENS_ENV) {
+        Ok(tokens) => tokens.split(',').map(|t| t.trim()).any(|t| !t.is_empty() && token.as_deref() == Some(t)),
+        _ => false,
+    }
+}
+
+pub fn shareable_populations() -> Vec<String> {
+    let names = std::env::var(POPULATIONS_ENV).unwrap_or_default();
+    let names: Vec<String> = names.split(',').map(|n| n.trim().to_string()).filter(|n| !n.is_empty()).collect();
+    if names.is_empty() { vec!["udn".to_string()] } else { names }
+}
+
+fn contact() -> Contact {
+    Contact {
+        name: std::env::var(CONTACT_NAME_ENV).unwrap_or_else(|_| "Undiagnosed Diseases Network".to_string()),
+        href: std::env::var(CONTACT_HREF_ENV).unwrap_or_else(|_| "https://undiagnosed.hms.harvard.edu/".to_string()),
+        institution: None,
+    }
+}
+
+// The MME version to answer in, as its media type. The request's Content-Type has to be a served MME version.
+// The answer is in the newest version the Accept header lists, or in the request's version when it lists none
+pub fn negotiate(content_type: Option<&str>, accept: Option<&str>) -> Result<&'static str, ApiError> {
+    let supported = || VERSIONS.iter().map(|(version, _)| *version).collect::<Vec<_>>().join(", ");
+    let requested = content_type.and_then(media_version).ok_or_else(|| {
+        ApiError::UnsupportedMediaType(format!("the Content-Type must be {}", VERSIONS[VERSIONS.len() - 1].1))
+    })?;
+    let request_version = VERSIONS.iter().find(|(version, _)| *version == requested).ok_or_else(|| {
+        ApiError::NotAcceptable(format!("unsupported API version {}, supported versions are {}", requested, supported()))
+    })?;
+
+    let accepted: Vec<String> = accept.unwrap_or("").split(',').filter_map(media_version).collect();
+    if accepted.is_empty() {
+        return Ok(request_version.1);
+    }
+    VERSIONS.iter().rev()
+        .find(|(version, _)| accepted.iter().any(|a| a == version))
+        .map(|(_, media_type)| *media_type)
+        .ok_or_else(|| ApiError::NotAcceptable(format!("unsupported API versions {}, supported versions are {}", accepted.join(", "), supported())))
+}
+
+// "1.0" from "application/vnd.ga4gh.matchmaker.v1.0+json; charset=utf-8"
+fn media_version(media_type: &str) -> Option<String> {
+    let media_type = media_type.split(';').next()?.trim().to_lowercase();
+    media_type.strip_prefix("application/vnd.ga4gh.matchmaker.v")?.strip_suffix("+json").map(|v| v.to_string())
+}
+
+// Scores every individual of the shareable populations against the patient. With genes in the query, only
+// individuals sharing one are returned and the score is the mean of the phenotype similarity and the gene match
+pub fn match_patient(ontology: &Arc<Ontology>, gene_index: &GeneIndex, snapshots: &[Arc<PopulationSnapshot>], request: &MatchRequest) -> Result<MatchResponse, ApiError> {
+    let query_terms: Vec<u32> = request.patient.features.iter()
+        .filter(|feature| !feature.observed.as_deref().is_some_and(|o| o.eq_ignore_ascii_case("no")))
+        .filter_map(|feature| feature.id.trim().trim_start_matches("HP:").parse::<u32>().ok())
+        .filter(|id| ontology.hpo(*id).is_some())
+        .collect();
+    let query_genes: HashSet<String> = request.patient.genomic_features.iter()
+        .map(|feature| approved_symbol(gene_index, &feature.gene.id))
+        .collect();
+    if query_terms.is_empty() && query_genes.is_empty() {
+        return Err(ApiError::BadRequest("the patient needs an observed HPO feature or a gene".to_string()));
+    }
+
+    let sim = calc_scores::group_similarity();
+    let query_set = HpoSet::new(ontology, HpoGroup::from(query_terms.clone()));
+    let mut symbols: HashMap<String, String> = HashMap::new();
+    let mut scored: Vec<(f64, MatchResult)> = Vec::new();
+    for snapshot in snapshots {
+        for (id, individual) in snapshot.population.iter() {
+            let genes = population::individual_genes(individual);
+            let shares_gene = genes.iter().any(|gene| {
+                let symbol = symbols.entry(gene.clone()).or_insert_with(|| approved_symbol(gene_index, gene));
+                query_genes.contains(symbol.as_str())
+            });
+            if !query_genes.is_empty() && !shares_gene {
+                continue;
+            }
+            let terms = population::individual_terms(ontology, individual);
+            let phenotype = (!query_terms.is_empty()).then(|| {
+                sim.calculate(&query_set, &HpoSet::new(ontology, HpoGroup::from(terms.clone()))) as f64
+            });
+            if query_genes.is_empty() && phenotype.is_some_and(|p| p < MIN_PHENOTYPE_SCORE) {
+                continue;
+            }
+            let genotype = (!query_genes.is_empty()).then_some(1.0);
+            let parts: Vec<f64> = phenotype.into_iter().chain(genotype).collect();
+            let score = parts.iter().sum::<f64>() / parts.len() as f64;
+
+            let patient = Patient {
+                id: format!("{}:{}", snapshot.name, id),
+                label: None,
+                contact: contact(),
+                features: terms.iter().filter_map(|term| ontology.hpo(*term)).map(|term| Feature {
+                    id: term.id().to_string(),
+                    label: Some(term.name().to_string()),
+                    observed: Some("yes".to_string()),
+                }).collect(),
+                genomic_features: genes.into_iter().map(|gene| GenomicFeature { gene: GeneId { id: gene } }).collect(),
+            };
+            scored.push((score, MatchResult { score: Score { patient: score, phenotype, genotype }, patient }));
+        }
+    }
+
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.patient.id.cmp(&b.1.patient.id)));
+    Ok(MatchResponse {
+        results: scored.into_iter().take(MAX_RESULTS).map(|(_, result)| result).collect(),
+        disclaimer: DISCLAIMER,
+    })
+}
+
+// The approved HGNC symbol of a gene named by symbol, HGNC id or NCBI gene id, uppercased. Genes HGNC doesn't
+// know are compared as given
+fn approved_symbol(gene_index: &GeneIndex, gene: &str) -> String {
+    gene_index.by_id(gene).map(|g| g.symbol.clone())
+        .or_else(|| gene_index.resolve(gene).symbol)
+        .unwrap_or_else(|| gene.trim().to_string())
+        .to_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1_0: &str = "application/vnd.ga4gh.matchmaker.v1.0+json";
+    const V1_1: &str = "application/vnd.ga4gh.matchmaker.v1.1+json";
+
+    #[test]
+    fn reads_the_version_of_a_media_type() {
+        assert_eq!(media_version(V1_0).as_deref(), Some("1.0"));
+        assert_eq!(media_version(" Application/VND.ga4gh.matchmaker.v1.1+JSON ; charset=utf-8").as_deref(), Some("1.1"));
+        assert_eq!(media_version("application/json"), None);
+        assert_eq!(media_version("application/vnd.ga4gh.matchmaker.v1.0+xml"), None);
+    }
+
+    #[test]
+    fn rejects_a_missing_or_foreign_content_type() {
+        for content_type in [None, Some("application/json"), Some("text/plain; charset=utf-8")] {
+            assert!(matches!(negotiate(content_type, None), Err(ApiError::UnsupportedMediaType(_))), "{:?}", content_type);
+        }
+    }
+
+    #[test]
+    fn rejects_an_unsupported_version() {
+        assert!(matches!(negotiate(Some("application/vnd.ga4gh.matchmaker.v2.0+json"), None), Err(ApiError::NotAcceptable(_))));
+        assert!(matches!(negotiate(Some(V1_0), Some("application/vnd.ga4gh.matchmaker.v0.9+json")), Err(ApiError::NotAcceptable(_))));
+    }
+
+    #[test]
+    fn answers_in_the_request_version_without_an_accept() {
+        assert_eq!(negotiate(Some(V1_0), None).unwrap(), V1_0);
+        assert_eq!(negotiate(Some(V1_1), Some("application/json")).unwrap(), V1_1);
+        assert_eq!(negotiate(Some(&format!("{}; charset=utf-8", V1_0)), Some("*/*")).unwrap(), V1_0);
+    }
+
+    #[test]
+    fn answers_in_the_newest_accepted_version() {
+        let accept = format!("{}, {};q=0.5, application/vnd.ga4gh.matchmaker.v3.0+json", V1_0, V1_1);
+        assert_eq!(negotiate(Some(V1_0), Some(&accept)).unwrap(), V1_1);
+        assert_eq!(negotiate(Some(V1_1), Some(&format!("application/json, {}; charset=utf-8", V1_0))).unwrap(), V1_0);
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use serde::Serialize;
+use hpo::{HpoTerm, HpoTermId, Ontology};
+use crate::db::Term;
+use crate::error::ApiError;
+
+// Every term descends from "All"
+const ROOT_ID: u32 = 1;
+
+// A term as the navigation routes return it
+#[derive(Serialize, Debug)]
+pub struct TermNode {
+    pub hpo_id: String,
+    pub name: String,
+    pub ic_gene: f32, // Information content over gene annotations
+    pub ic_omim: f32, // Information content over OMIM disease annotations
+}
+
+#[derive(Serialize, Debug)]
+pub struct CommonAncestors {
+    pub a: TermNode,
+    pub b: TermNode,
+    pub common_ancestors: Vec<TermNode>, // Includes a or b when one is an ancestor of the other
+    pub most_informative: Option<TermNode>, // The common ancestor with the highest gene IC
+}
+
+// One item of a batch lookup, in the order asked for. Unknown ids have found false and an error
+#[derive(Serialize, Debug)]
+pub struct BatchTerm {
+    pub id: String, // As given
+    pub found: bool,
+    pub hpo_id: Option<String>,
+    pub name: Option<String>,
+    pub definition: Option<String>,
+    pub synonyms: Vec<String>,
+    pub ic_gene: Option<f32>,
+    pub ic_omim: Option<f32>,
+    pub error: Option<String>,
+}
+
+impl TermNode {
+    fn new(term: &HpoTerm) -> TermNode {
+        TermNode {
+            hpo_id: term.id().to_string(),
+            name: term.name().to_string(),
+            // abs() because the root's IC comes out as -0
+            ic_gene: term.information_content().gene().abs(),
+            ic_omim: term.information_content().omim_disease().abs(),
+        }
+    }
+}
+
+// Looks up a term from "HP:0001250" or "0001250", 400 when the id is malformed and 404 when the ontology doesn't have it
+pub fn find_term<'a>(ontology: &'a Ontology, id: &str) -> Result<HpoTerm<'a>, ApiError> {
+    let number = id.trim().trim_start_matches("HP:").parse::<u32>()
+        .map_err(|_| ApiError::BadRequest(format!("invalid HPO id: {}", id)))?;
+    ontology.hpo(number).ok_or_else(|| ApiError::NotFound(format!("unknown term: {}", id)))
+}
+
+// The terms related to id by one of parents, children, ancestors, descendants or path_to_root.
+// Lists are sorted by id, the path runs from the term up to the root
+pub fn related_terms(ontology: &Ontology, id: &str, relation: &str) -> Result<Vec<TermNode>, ApiError> {
+    let term = find_term(ontology, id)?;
+    let terms: Vec<HpoTerm> = match relation {
+        "parents" => term.parents().collect(),
+        "children" => term.children().collect(),
+        "ancestors" => term.all_parents().collect(),
+        "descendants" => descendants(&term),
+        "path_to_root" => return Ok(path_to_root(ontology, &term).iter().map(TermNode::new).collect()),
+        _ => return Err(ApiError::NotFound(format!("unknown relation: {}", relation))),
+    };
+    Ok(sorted_nodes(terms))
+}
+
+// Combines the ontology (name, IC) with hpo.db's Terms rows (definition, synonyms) for each id. A term in either
+// counts as found, ids that are malformed or in neither are reported per item
+pub fn batch_terms(ontology: &Ontology, ids: &[String], db_terms: &HashMap<String, Term>) -> Vec<BatchTerm> {
+    ids.iter()
+        .map(|id| {
+            let mut item = BatchTerm { id: id.clone(), found: false, hpo_id: None, name: None, definition: None, synonyms: Vec::new(), ic_gene: None, ic_omim: None, error: None };
+            let term = match find_term(ontology, id) {
+                Ok(term) => Some(term),
+                Err(ApiError::BadRequest(message)) => {
+                    item.error = Some(message);
+                    return item;
+                }
+                Err(_) => None,
+            };
+            let hpo_id = term.as_ref().map(|term| term.id().to_string()).or_else(|| normalize_id(id)).unwrap_or_default();
+            let row = db_terms.get(&hpo_id);
+            if term.is_none() && row.is_none() {
+                item.error = Some(format!("unknown term: {}", id));
+                return item;
+            }
+
+            item.found = true;
+            item.name = term.as_ref().map(|term| term.name().to_string()).or_else(|| row.and_then(|row| row.name.clone()));
+            item.definition = row.and_then(|row| row.definition.clone());
+            item.synonyms = row.map(|row| row.synonym_list()).unwrap_or_default();
+            item.ic_gene = term.as_ref().map(|term| term.information_content().gene().abs());
+            item.ic_omim = term.as_ref().map(|term| term.information_content().omim_disease().abs());
+            item.hpo_id = Some(hpo_id);
+            item
+        })
+        .collect()
+}
+
+// The normalized "HP:0001250" form of an id, or None when it doesn't parse
+pub fn normalize_id(id: &str) -> Option<String> {
+    id.trim().trim_start_matches("HP:").parse::<u32>().ok().map(|number| HpoTermId::from(number).to_string())
+}
+
+pub fn common_ancestors(ontology: &Ontology, a: &str, b: &str) -> Result<CommonAncestors, ApiError> {
+    let term_a = find_term(ontology, a)?;
+    let term_b = find_term(ontology, b)?;
+    let ancestors: Vec<HpoTerm> = term_a.all_common_ancestor_ids(&term_b).iter().filter_map(|id| ontology.hpo(id)).collect();
+    let most_informative = ancestors
+        .iter()
+        .max_by(|x, y| x.information_content().gene().total_cmp(&y.information_content().gene()))
+        .map(TermNode::new);
+
+    Ok(CommonAncestors {
+        a: TermNode::new(&term_a),
+        b: TermNode::new(&term_b),
+        common_ancestors: sorted_nodes(ancestors),
+        most_informative,
+    })
+}
+
+fn sorted_nodes(mut terms: Vec<HpoTerm>) -> Vec<TermNode> {
+    terms.sort_by_key(|term| term.id());
+    terms.iter().map(TermNode::new).collect()
+}
+
+// Every term below term, each once
+fn descendants<'a>(term: &HpoTerm<'a>) -> Vec<HpoTerm<'a>> {
+    let mut seen: HashSet<HpoTermId> = HashSet::new();
+    let mut queue: VecDeque<HpoTerm> = term.children().collect();
+    let mut found = Vec::new();
+    while let Some(child) = queue.pop_front() {
+        if seen.insert(child.id()) {
+            queue.extend(child.children());
+            found.push(child);
+        }
+    }
+    found
+}
+
+// The shortest path from term up to the root, both included. Breadth first over parents so the first
+// time the root is reached is along a shortest path
+fn path_to_root<'a>(ontology: &'a Ontology, term: &HpoTerm<'a>) -> Vec<HpoTerm<'a>> {
+    let root = HpoTermId::from(ROOT_ID);
+    let mut came_from: HashMap<HpoTermId, HpoTermId> = HashMap::new();
+    let mut queue: VecDeque<HpoTermId> = VecDeque::from(vec![term.id()]);
+
+    while let Some(id) = queue.pop_front() {
+        if id == root {
+            break;
+        }
+        let current = match ontology.hpo(id) {
+            Some(current) => current,
+            None => continue,
+        };
+        for parent in current.parent_ids() {
+            if parent != term.id() && !came_from.contains_key(&parent) {
+                came_from.insert(parent, id);
+                queue.push_back(parent);
+            }
+        }
+    }
+
+    // Walk back down from the root, or stay at the term if it is the root or somehow isn't under it
+    let mut path = Vec::new();
+    let mut id = if came_from.contains_key(&root) { root } else { term.id() };
+    loop {
+        if let Some(current) = ontology.hpo(id) {
+            path.push(current);
+        }
+        match came_from.get(&id) {
+            Some(child) => id = *child,
+            None => break,
+        }
+    }
+    path.reverse();
+    path
+}
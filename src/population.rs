@@ -1,17 +1,75 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 use csv::Reader;
 use csv::{ReaderBuilder, Error};
+use hpo::Ontology;
+
+// Individuals keyed by id, each a map of column name to value
+pub type Population = HashMap<String, HashMap<String, String>>;
+
+// Loads one of the known populations by the name used in the routes, None if the name isn't known
+pub fn create_population(name: &str, url: String) -> Option<Result<Population, Error>> {
+    match name {
+        "udn" => Some(create_udn_population(url)),
+        "orpha" => Some(create_orpha_population(url)),
+        "decipher" => Some(create_deciper_population(url)),
+        "clinvar" => Some(create_clinvar_population(url)),
+        _ => None,
+    }
+}
+
+// The "; " separated Terms of an individual as written in the source file
+pub fn raw_terms(individual: &HashMap<String, String>) -> Vec<&str> {
+    individual.get("Terms").map(|terms| terms.as_str()).unwrap_or("")
+        .split("; ")
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+// The "; " separated Terms of an individual as ids, skipping any that don't parse or aren't in the ontology
+pub fn individual_terms(ontology: &Arc<Ontology>, individual: &HashMap<String, String>) -> Vec<u32> {
+    raw_terms(individual)
+        .into_iter()
+        .filter_map(|s| {
+            let id_str = s.replace("HP:", "");
+            match id_str.parse::<u32>() {
+                Ok(id) => {
+                    // Check if the term exists in the ontology before including it
+                    if ontology.hpo(id).is_some() {
+                        Some(id)
+                    } else {
+                        eprintln!("Warning: HPO term {} not found in ontology", s);
+                        None
+                    }
+                }
+                Err(_) => {
+                    eprintln!("Warning: Failed to parse HPO ID: {}", s);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// The Genes of an individual, these are separated by ";" or "," depending on the source
+pub fn individual_genes(individual: &HashMap<String, String>) -> Vec<String> {
+    individual.get("Genes").map(|genes| genes.as_str()).unwrap_or("")
+        .split([';', ','])
+        .map(|g| g.trim().to_string())
+        .filter(|g| !g.is_empty())
+        .collect()
+}
 
-#[allow(dead_code)]
-pub fn create_udn_population(csv_url: String) -> HashMap<String, HashMap<String, String>> {
+#[allow(dead_code, clippy::explicit_counter_loop, clippy::to_string_in_format_args)]
+pub fn create_udn_population(csv_url: String) -> Result<HashMap<String, HashMap<String, String>>, Error> {
     //This will take a csv file and create a hashmap where the numId is the key and the value is a hashmap of the other attributes
     let mut population: HashMap<String, HashMap<String, String>> = HashMap::new();
     //Read the csv file
-    let mut reader = Reader::from_path(csv_url).unwrap();
+    let mut reader = Reader::from_path(csv_url)?;
     let mut id_num = 1;
     //Iterate through the rows
     for result in reader.records() {
-        let record = result.unwrap();
+        let record = result?;
         //Create a hashmap for each row
         let mut individual: HashMap<String, String> = HashMap::new();
         //Iterate through the columns
@@ -19,12 +77,8 @@ pub fn create_udn_population(csv_url: String) -> HashMap<String, HashMap<String,
 
         id_num += 1;
 
+        //Undiagnosed patients are kept too, populations::default_diagnosis picks who the routes match against
         individual.insert("Dx/Udx".to_string(), record[1].to_string());
-        
-        //if Dx/Udx is not 'Diagnosed' skip the row
-        if individual.get("Dx/Udx").unwrap() != "Diagnosed" {
-            continue;
-        }
 
         //if Genes is NONE, None, or none, then set it to an empty string
         let mut genes = record[2].to_string();
@@ -48,21 +102,21 @@ pub fn create_udn_population(csv_url: String) -> HashMap<String, HashMap<String,
         //Add the individual to the population hashmap
         population.insert(individual.get("ID").unwrap().to_string(), individual);
     }
-    population
+    Ok(population)
 }
 
-pub fn create_orpha_population(tsv_url: String) -> HashMap<String, HashMap<String, String>> {
+#[allow(clippy::to_string_in_format_args)]
+pub fn create_orpha_population(tsv_url: String) -> Result<HashMap<String, HashMap<String, String>>, Error> {
     //This will take a csv file and create a hashmap where the numId is the key and the value is a hashmap of the other attributes
     let mut population: HashMap<String, HashMap<String, String>> = HashMap::new();
     //Read the csv file
     let mut reader = ReaderBuilder::new()
         .delimiter(b'\t')
-        .from_path(tsv_url)
-        .unwrap();
+        .from_path(tsv_url)?;
 
     //Iterate through the rows
     for result in reader.records() {
-        let record = result.unwrap();
+        let record = result?;
         //Create a hashmap for each row
         let mut individual: HashMap<String, String> = HashMap::new();
         //Iterate through the columns
@@ -95,17 +149,18 @@ pub fn create_orpha_population(tsv_url: String) -> HashMap<String, HashMap<Strin
         //Add the individual to the population hashmap
         population.insert(individual.get("ID").unwrap().to_string(), individual);
     }
-    population
+    Ok(population)
 }
 
-pub fn create_deciper_population(csv_url: String) -> HashMap<String, HashMap<String, String>> {
+#[allow(clippy::to_string_in_format_args)]
+pub fn create_deciper_population(csv_url: String) -> Result<HashMap<String, HashMap<String, String>>, Error> {
     //This will take a csv file and create a hashmap where the numId is the key and the value is a hashmap of the other attributes
     let mut population: HashMap<String, HashMap<String, String>> = HashMap::new();
     //Read the csv file
-    let mut reader = Reader::from_path(csv_url).unwrap();
+    let mut reader = Reader::from_path(csv_url)?;
     //Iterate through the rows
     for result in reader.records() {
-        let record = result.unwrap();
+        let record = result?;
         //Create a hashmap for each row
         let mut individual: HashMap<String, String> = HashMap::new();
         //Iterate through the columns
@@ -143,17 +198,18 @@ pub fn create_deciper_population(csv_url: String) -> HashMap<String, HashMap<Str
         //Add the individual to the population hashmap
         population.insert(individual.get("ID").unwrap().to_string(), individual);
     }
-    population
+    Ok(population)
 }
 
-pub fn create_clinvar_population(csv_url: String) -> HashMap<String, HashMap<String, String>> {
+#[allow(clippy::to_string_in_format_args)]
+pub fn create_clinvar_population(csv_url: String) -> Result<HashMap<String, HashMap<String, String>>, Error> {
     //This will take a csv file and create a hashmap where the numId is the key and the value is a hashmap of the other attributes
     let mut population: HashMap<String, HashMap<String, String>> = HashMap::new();
     //Read the csv file
-    let mut reader = Reader::from_path(csv_url).unwrap();
+    let mut reader = Reader::from_path(csv_url)?;
     //Iterate through the rows
     for result in reader.records() {
-        let record = result.unwrap();
+        let record = result?;
         //Create a hashmap for each row
         let mut individual: HashMap<String, String> = HashMap::new();
         //Iterate through the columns
@@ -191,5 +247,5 @@ pub fn create_clinvar_population(csv_url: String) -> HashMap<String, HashMap<Str
         //Add the individual to the population hashmap
         population.insert(individual.get("ID").unwrap().to_string(), individual);
     }
-    population
+    Ok(population)
 }
\ No newline at end of file
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::{params, Connection, OptionalExtension, Result, Transaction};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::population::{self, Population};
+
+// The columns every population has, anything else an individual has is kept in its attributes
+const STANDARD_COLUMNS: [&str; 6] = ["ID", "Dx/Udx", "Genes", "Clin diagnosis", "Terms", "HPO_Names"];
+
+// Where a stored population came from
+#[derive(Serialize, Debug, Clone)]
+pub struct Provenance {
+    pub source: String, // The file it was read from, or "upload:<file name>"
+    pub format: String,
+    pub file_hash: String, // SHA-256 of the file as read, hex
+    pub row_count: usize, // Rows in the file, before any were skipped
+    pub individuals_stored: usize, // Individuals in the store, before any cohort query
+    pub uploaded: bool, // Uploaded through the admin routes rather than one of the built in files
+    pub ingested_at: u64, // Unix seconds
+}
+
+// Which of a population's individuals a cohort filter keeps, every condition given has to hold
+#[derive(Debug, Default, Clone)]
+pub struct CohortQuery {
+    pub diagnosis: Option<String>, // Dx/Udx, case insensitive
+    pub genes: Vec<String>, // Has any of these, case insensitive
+    pub term_ids: Vec<u32>, // Has any of these HPO ids, the caller expands a term to the terms below it
+}
+
+// Every population normalized into individuals, individual_terms and individual_genes, with where each
+// population was read from. The in memory populations the routes score against are read back out of it.
+// Writes only happen on ingestion, uploads and deletes, so one connection behind a lock is enough
+pub struct PopulationStore {
+    conn: Mutex<Connection>,
+}
+
+impl PopulationStore {
+    // Opens the store, creating the file and its tables the first time
+    pub fn open(path: &str) -> Result<PopulationStore> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            CREATE TABLE IF NOT EXISTS populations (
+                name TEXT PRIMARY KEY,
+                source TEXT NOT NULL,
+                format TEXT NOT NULL,
+                file_hash TEXT NOT NULL,
+                row_count INTEGER NOT NULL,
+                individuals INTEGER NOT NULL,
+                uploaded INTEGER NOT NULL,
+                ingested_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS individuals (
+                population TEXT NOT NULL REFERENCES populations(name) ON DELETE CASCADE,
+                id TEXT NOT NULL,
+                diagnosis TEXT NOT NULL,
+                clinical_diagnosis TEXT NOT NULL,
+                term_names TEXT NOT NULL,
+                genes TEXT NOT NULL, -- As written, individual_genes holds them split
+                attributes TEXT NOT NULL, -- Any other columns as a JSON object
+                PRIMARY KEY (population, id)
+            );
+            CREATE TABLE IF NOT EXISTS individual_terms (
+                population TEXT NOT NULL,
+                individual_id TEXT NOT NULL,
+                position INTEGER NOT NULL, -- Terms are read back in the order they were written
+                term TEXT NOT NULL, -- As written, e.g. HP:0001250
+                term_id INTEGER, -- The HPO id it parses to, NULL when it doesn't
+                FOREIGN KEY (population, individual_id) REFERENCES individuals(population, id) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS individual_genes (
+                population TEXT NOT NULL,
+                individual_id TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                gene TEXT NOT NULL,
+                FOREIGN KEY (population, individual_id) REFERENCES individuals(population, id) ON DELETE CASCADE
+            );",
+        )?;
+        add_term_ids(&conn)?;
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS idx_individuals_diagnosis ON individuals(population, diagnosis COLLATE NOCASE);
+            CREATE INDEX IF NOT EXISTS idx_individual_terms_individual ON individual_terms(population, individual_id);
+            CREATE INDEX IF NOT EXISTS idx_individual_terms_term ON individual_terms(term);
+            CREATE INDEX IF NOT EXISTS idx_individual_terms_term_id ON individual_terms(population, term_id);
+            CREATE INDEX IF NOT EXISTS idx_individual_genes_individual ON individual_genes(population, individual_id);
+            CREATE INDEX IF NOT EXISTS idx_individual_genes_gene ON individual_genes(population, gene COLLATE NOCASE);",
+        )?;
+        Ok(PopulationStore { conn: Mutex::new(conn) })
+    }
+
+    // Replaces a population with the given individuals in one transaction, so readers never see half of it
+    pub fn save(&self, name: &str, provenance: &Provenance, population: &Population) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        write_population(&tx, name, provenance, population)?;
+        tx.commit()
+    }
+
+    // False when there was no population by that name
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM populations WHERE name = ?1", params![name])? > 0)
+    }
+
+    pub fn provenance(&self, name: &str) -> Result<Option<Provenance>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT source, format, file_hash, row_count, individuals, uploaded, ingested_at FROM populations WHERE name = ?1",
+            params![name],
+            |row| {
+                Ok(Provenance {
+                    source: row.get(0)?,
+                    format: row.get(1)?,
+                    file_hash: row.get(2)?,
+                    row_count: row.get::<_, i64>(3)? as usize,
+                    individuals_stored: row.get::<_, i64>(4)? as usize,
+                    uploaded: row.get(5)?,
+                    ingested_at: row.get::<_, i64>(6)? as u64,
+                })
+            },
+        ).optional()
+    }
+
+    // The names of every uploaded population
+    pub fn uploaded_names(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name FROM populations WHERE uploaded = 1 ORDER BY name")?;
+        let names = stmt.query_map([], |row| row.get(0))?.collect();
+        names
+    }
+
+    // Reads a whole population back into the shape the scoring code takes. Snapshots hold every individual,
+    // requests narrow them down with a cohort query
+    pub fn population(&self, name: &str) -> Result<Population> {
+        let conn = self.conn.lock().unwrap();
+        let mut individuals = conn.prepare_cached(
+            "SELECT id, diagnosis, clinical_diagnosis, term_names, genes, attributes FROM individuals WHERE population = ?1",
+        )?;
+        let rows = individuals.query_map(params![name], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?, row.get::<_, String>(4)?, row.get::<_, String>(5)?))
+        })?;
+
+        let mut population: Population = HashMap::new();
+        for row in rows {
+            let (id, diagnosis, clinical_diagnosis, term_names, genes, attributes) = row?;
+            let mut individual: HashMap<String, String> = serde_json::from_str(&attributes).unwrap_or_else(|e| {
+                eprintln!("Warning: ignoring the attributes of {} in population {}: {}", id, name, e);
+                HashMap::new()
+            });
+            individual.insert("ID".to_string(), id.clone());
+            individual.insert("Dx/Udx".to_string(), diagnosis);
+            individual.insert("Clin diagnosis".to_string(), clinical_diagnosis);
+            individual.insert("HPO_Names".to_string(), term_names);
+            individual.insert("Terms".to_string(), String::new());
+            // Genes are served as the source wrote them, "; " or "," separated depending on the source
+            individual.insert("Genes".to_string(), genes);
+            population.insert(id, individual);
+        }
+
+        // Terms are joined back into the "; " separated list they were split from
+        let mut stmt = conn.prepare_cached("SELECT individual_id, term FROM individual_terms WHERE population = ?1 ORDER BY individual_id, position")?;
+        let rows = stmt.query_map(params![name], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (id, term) = row?;
+            if let Some(terms) = population.get_mut(&id).and_then(|individual| individual.get_mut("Terms")) {
+                if !terms.is_empty() {
+                    terms.push_str("; ");
+                }
+                terms.push_str(&term);
+            }
+        }
+        Ok(population)
+    }
+
+    // The ids of a population's individuals matching the query, through the diagnosis, gene and term indexes.
+    // Only the conditions given are added to the query so each one can use its index
+    pub fn cohort(&self, name: &str, query: &CohortQuery) -> Result<HashSet<String>> {
+        let mut sql = "SELECT id FROM individuals WHERE population = ?1".to_string();
+        let mut values: Vec<String> = vec![name.to_string()];
+        if let Some(diagnosis) = &query.diagnosis {
+            values.push(diagnosis.clone());
+            sql.push_str(&format!(" AND diagnosis = ?{} COLLATE NOCASE", values.len()));
+        }
+        if !query.genes.is_empty() {
+            values.push(serde_json::to_string(&query.genes).unwrap_or_default());
+            sql.push_str(&format!(
+                " AND id IN (SELECT individual_id FROM individual_genes WHERE population = ?1 AND gene COLLATE NOCASE IN (SELECT value FROM json_each(?{})))",
+                values.len(),
+            ));
+        }
+        if !query.term_ids.is_empty() {
+            values.push(serde_json::to_string(&query.term_ids).unwrap_or_default());
+            sql.push_str(&format!(
+                " AND id IN (SELECT individual_id FROM individual_terms WHERE population = ?1 AND term_id IN (SELECT value FROM json_each(?{})))",
+                values.len(),
+            ));
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let ids = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| row.get(0))?.collect();
+        ids
+    }
+}
+
+// Stores written before individual_terms had term_id get the column, filled in from the terms already there
+fn add_term_ids(conn: &Connection) -> Result<()> {
+    let has_term_id = conn.prepare("SELECT 1 FROM pragma_table_info('individual_terms') WHERE name = 'term_id'")?.exists([])?;
+    if has_term_id {
+        return Ok(());
+    }
+    conn.execute_batch("ALTER TABLE individual_terms ADD COLUMN term_id INTEGER")?;
+    let terms: Vec<(i64, String)> = conn.prepare("SELECT rowid, term FROM individual_terms")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+    let mut update = conn.prepare("UPDATE individual_terms SET term_id = ?1 WHERE rowid = ?2")?;
+    for (rowid, term) in terms {
+        if let Ok(term_id) = term.trim_start_matches("HP:").parse::<u32>() {
+            update.execute(params![term_id, rowid])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_population(tx: &Transaction, name: &str, provenance: &Provenance, population: &Population) -> Result<()> {
+    tx.execute("DELETE FROM populations WHERE name = ?1", params![name])?;
+    tx.execute(
+        "INSERT INTO populations (name, source, format, file_hash, row_count, individuals, uploaded, ingested_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            name,
+            provenance.source,
+            provenance.format,
+            provenance.file_hash,
+            provenance.row_count as i64,
+            population.len() as i64,
+            provenance.uploaded,
+            provenance.ingested_at as i64,
+        ],
+    )?;
+
+    let mut insert_individual = tx.prepare(
+        "INSERT INTO individuals (population, id, diagnosis, clinical_diagnosis, term_names, genes, attributes) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    let mut insert_term = tx.prepare("INSERT INTO individual_terms (population, individual_id, position, term, term_id) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+    let mut insert_gene = tx.prepare("INSERT INTO individual_genes (population, individual_id, position, gene) VALUES (?1, ?2, ?3, ?4)")?;
+    for (id, individual) in population {
+        let field = |column: &str| individual.get(column).cloned().unwrap_or_default();
+        let attributes: HashMap<&String, &String> = individual.iter().filter(|(column, _)| !STANDARD_COLUMNS.contains(&column.as_str())).collect();
+        insert_individual.execute(params![
+            name,
+            id,
+            field("Dx/Udx"),
+            field("Clin diagnosis"),
+            field("HPO_Names"),
+            field("Genes"),
+            serde_json::to_string(&attributes).unwrap_or_else(|_| "{}".to_string()),
+        ])?;
+        for (position, term) in population::raw_terms(individual).into_iter().enumerate() {
+            let term_id = term.trim_start_matches("HP:").parse::<u32>().ok();
+            insert_term.execute(params![name, id, position as i64, term, term_id])?;
+        }
+        for (position, gene) in population::individual_genes(individual).into_iter().enumerate() {
+            insert_gene.execute(params![name, id, position as i64, gene])?;
+        }
+    }
+    Ok(())
+}
+
+// SHA-256 of a file's contents as lowercase hex
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet};
+use csv::ReaderBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use hpo::Ontology;
+use crate::ontology_nav;
+use crate::population::Population;
+
+// How many of an upload's unknown terms are listed by id
+const UNKNOWN_EXAMPLES: usize = 10;
+
+// The columns of an uploaded CSV or TSV, given as query parameters. Each defaults to the column name the built in
+// populations use, so a file in that shape needs no mapping. Phenopackets only use format and file_name
+#[derive(Deserialize, Debug)]
+pub struct ColumnMapping {
+    pub format: String, // csv, tsv or phenopackets (one JSON phenopacket per line)
+    pub file_name: Option<String>, // Recorded as the source, defaults to "upload"
+    pub id: Option<String>,
+    pub terms: Option<String>,
+    pub genes: Option<String>,
+    pub diagnosis: Option<String>, // Diagnosed or Undiagnosed
+    pub clinical_diagnosis: Option<String>,
+    pub term_names: Option<String>,
+}
+
+// An upload turned into the shape the built in populations have, with what was dropped along the way
+#[derive(Debug)]
+pub struct ParsedUpload {
+    pub population: Population,
+    pub rows: usize,
+    pub skipped_rows: usize, // No id, a repeated id, or no terms the ontology has
+    pub unknown_terms: usize, // Terms dropped because they don't parse or aren't in the ontology
+    pub unknown_examples: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadReport {
+    pub rows: usize,
+    pub skipped_rows: usize,
+    pub unknown_terms: usize,
+    pub unknown_examples: Vec<String>,
+}
+
+impl ParsedUpload {
+    pub fn report(&self) -> UploadReport {
+        UploadReport {
+            rows: self.rows,
+            skipped_rows: self.skipped_rows,
+            unknown_terms: self.unknown_terms,
+            unknown_examples: self.unknown_examples.clone(),
+        }
+    }
+}
+
+// One individual before it is checked against the ontology
+struct Row {
+    id: String,
+    terms: Vec<String>,
+    term_names: Vec<String>,
+    genes: Vec<String>,
+    diagnosis: String,
+    clinical_diagnosis: String,
+    attributes: Vec<(String, String)>, // Any other columns, kept under their own names
+}
+
+// Parses an uploaded file. Ids are prefixed with the population name like the built in populations ("UDN:1")
+pub fn parse(ontology: &Ontology, name: &str, mapping: &ColumnMapping, body: &[u8]) -> Result<ParsedUpload, String> {
+    let rows = match mapping.format.to_lowercase().as_str() {
+        "csv" => parse_delimited(mapping, body, b',')?,
+        "tsv" => parse_delimited(mapping, body, b'\t')?,
+        "phenopackets" | "jsonl" => parse_phenopackets(body)?,
+        other => return Err(format!("unknown format: {}, expected csv, tsv or phenopackets", other)),
+    };
+
+    let mut parsed = ParsedUpload { population: HashMap::new(), rows: rows.len(), skipped_rows: 0, unknown_terms: 0, unknown_examples: Vec::new() };
+    for row in rows {
+        let id = format!("{}:{}", name.to_uppercase(), row.id);
+        let mut terms = Vec::new();
+        let mut names = Vec::new();
+        for (idx, term) in row.terms.iter().enumerate() {
+            match ontology_nav::normalize_id(term).filter(|id| ontology_nav::find_term(ontology, id).is_ok()) {
+                Some(term) => {
+                    terms.push(term);
+                    names.push(row.term_names.get(idx).cloned().unwrap_or_default());
+                }
+                None => {
+                    parsed.unknown_terms += 1;
+                    if parsed.unknown_examples.len() < UNKNOWN_EXAMPLES {
+                        parsed.unknown_examples.push(term.clone());
+                    }
+                }
+            }
+        }
+        if row.id.is_empty() || terms.is_empty() || parsed.population.contains_key(&id) {
+            parsed.skipped_rows += 1;
+            continue;
+        }
+
+        let mut individual: HashMap<String, String> = row.attributes.into_iter().collect();
+        individual.insert("ID".to_string(), id.clone());
+        individual.insert("Dx/Udx".to_string(), row.diagnosis);
+        individual.insert("Genes".to_string(), row.genes.join("; "));
+        individual.insert("Clin diagnosis".to_string(), row.clinical_diagnosis);
+        individual.insert("Terms".to_string(), terms.join("; "));
+        let has_names = names.iter().any(|name| !name.is_empty());
+        individual.insert("HPO_Names".to_string(), if has_names { names.join("; ") } else { "None".to_string() });
+        parsed.population.insert(id, individual);
+    }
+    Ok(parsed)
+}
+
+fn parse_delimited(mapping: &ColumnMapping, body: &[u8], delimiter: u8) -> Result<Vec<Row>, String> {
+    let mut reader = ReaderBuilder::new().delimiter(delimiter).flexible(true).from_reader(body);
+    let headers = reader.headers().map_err(|e| format!("could not read the header row: {}", e))?.clone();
+
+    // A column given in the mapping has to exist, a defaulted one is used only when it does
+    let column = |given: &Option<String>, default: &str| -> Result<Option<usize>, String> {
+        match given {
+            Some(name) => headers.iter().position(|h| h == name).map(Some).ok_or_else(|| format!("no column named {}", name)),
+            None => Ok(headers.iter().position(|h| h == default)),
+        }
+    };
+    let id = column(&mapping.id, "ID")?.ok_or("no ID column, set id to the column holding individual ids")?;
+    let terms = column(&mapping.terms, "Terms")?.ok_or("no Terms column, set terms to the column holding HPO ids")?;
+    let genes = column(&mapping.genes, "Genes")?;
+    let diagnosis = column(&mapping.diagnosis, "Dx/Udx")?;
+    let clinical_diagnosis = column(&mapping.clinical_diagnosis, "Clin diagnosis")?;
+    let term_names = column(&mapping.term_names, "HPO_Names")?;
+    let mapped = [Some(id), Some(terms), genes, diagnosis, clinical_diagnosis, term_names];
+
+    let mut rows = Vec::new();
+    for (line, result) in (2..).zip(reader.records()) {
+        let record = result.map_err(|e| format!("line {}: {}", line, e))?;
+        let field = |idx: Option<usize>| idx.and_then(|i| record.get(i)).unwrap_or("").trim().to_string();
+        let attributes = headers.iter().enumerate()
+            .filter(|(idx, _)| !mapped.contains(&Some(*idx)))
+            .map(|(idx, header)| (header.to_string(), field(Some(idx))))
+            .collect();
+        rows.push(Row {
+            id: field(Some(id)),
+            terms: split_list(&field(Some(terms))),
+            term_names: split_names(&field(term_names)),
+            genes: split_list(&field(genes)),
+            diagnosis: field(diagnosis),
+            clinical_diagnosis: field(clinical_diagnosis),
+            attributes,
+        });
+    }
+    Ok(rows)
+}
+
+// Reads Phenopackets v2 JSON, one per line. Excluded phenotypic features are left out, genes come from the
+// interpretations' variant or gene descriptors, and a SOLVED interpretation counts as diagnosed
+fn parse_phenopackets(body: &[u8]) -> Result<Vec<Row>, String> {
+    let text = std::str::from_utf8(body).map_err(|e| format!("phenopackets must be UTF-8: {}", e))?;
+    let mut rows = Vec::new();
+    for (line, json) in (1..).zip(text.lines()) {
+        if json.trim().is_empty() {
+            continue;
+        }
+        let packet: Value = serde_json::from_str(json).map_err(|e| format!("line {}: {}", line, e))?;
+        let str_at = |value: &Value, pointer: &str| value.pointer(pointer).and_then(Value::as_str).unwrap_or("").to_string();
+        let list = |value: &Value, key: &str| value.get(key).and_then(Value::as_array).cloned().unwrap_or_default();
+
+        let features: Vec<Value> = list(&packet, "phenotypicFeatures")
+            .into_iter()
+            .filter(|feature| !feature.get("excluded").and_then(Value::as_bool).unwrap_or(false))
+            .collect();
+        let interpretations = list(&packet, "interpretations");
+
+        // A gene named by several interpretations is listed once, where it first appears
+        let mut seen: HashSet<String> = HashSet::new();
+        let genes: Vec<String> = interpretations.iter()
+            .flat_map(|interpretation| list(interpretation.get("diagnosis").unwrap_or(&Value::Null), "genomicInterpretations"))
+            .flat_map(|genomic| {
+                vec![
+                    str_at(&genomic, "/variantInterpretation/variationDescriptor/geneContext/symbol"),
+                    str_at(&genomic, "/gene/symbol"),
+                ]
+            })
+            .filter(|gene| !gene.is_empty() && seen.insert(gene.clone()))
+            .collect();
+        let solved = interpretations.iter().any(|interpretation| str_at(interpretation, "/progressStatus") == "SOLVED");
+        let diseases: Vec<String> = interpretations.iter()
+            .map(|interpretation| str_at(interpretation, "/diagnosis/disease/label"))
+            .filter(|label| !label.is_empty())
+            .collect();
+
+        let id = str_at(&packet, "/id");
+        rows.push(Row {
+            id: if id.is_empty() { str_at(&packet, "/subject/id") } else { id },
+            terms: features.iter().map(|feature| str_at(feature, "/type/id")).collect(),
+            term_names: features.iter().map(|feature| str_at(feature, "/type/label")).collect(),
+            genes,
+            diagnosis: if solved { "Diagnosed".to_string() } else { "Undiagnosed".to_string() },
+            clinical_diagnosis: if diseases.is_empty() { "None".to_string() } else { diseases.join("; ") },
+            attributes: Vec::new(),
+        });
+    }
+    Ok(rows)
+}
+
+// Lists in a cell are separated by ";", "," or "|"
+fn split_list(cell: &str) -> Vec<String> {
+    cell.split([';', ',', '|'])
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty() && !s.eq_ignore_ascii_case("none"))
+        .collect()
+}
+
+// Term names can have commas in them so only ";" and "|" separate them
+fn split_names(cell: &str) -> Vec<String> {
+    cell.split([';', '|']).map(|s| s.trim().to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // HP:0000001 with HP:0000002 and HP:0000003 below it
+    fn ontology() -> Ontology {
+        let mut ontology = Ontology::default();
+        for id in 1u32..=3 {
+            ontology.insert_term(format!("Term {}", id), id);
+        }
+        ontology.add_parent(1u32, 2u32);
+        ontology.add_parent(1u32, 3u32);
+        ontology.create_cache();
+        ontology
+    }
+
+    fn mapping(format: &str) -> ColumnMapping {
+        ColumnMapping {
+            format: format.to_string(),
+            file_name: None,
+            id: None,
+            terms: None,
+            genes: None,
+            diagnosis: None,
+            clinical_diagnosis: None,
+            term_names: None,
+        }
+    }
+
+    #[test]
+    fn reads_a_csv_in_the_built_in_shape() {
+        let body = "ID,Terms,HPO_Names,Genes,Dx/Udx,Clin diagnosis,Site\n\
+            1,HP:0000002; HP:0009999,\"Term 2, left; Unknown\",ADH6; BRCA1,Diagnosed,Something,SLC\n\
+            2,HP:0009999,None,,Undiagnosed,None,UCLA\n\
+            1,HP:0000003,,,Diagnosed,,SLC\n";
+        let parsed = parse(&ontology(), "mine", &mapping("csv"), body.as_bytes()).unwrap();
+
+        assert_eq!((parsed.rows, parsed.skipped_rows, parsed.unknown_terms), (3, 2, 2));
+        assert_eq!(parsed.unknown_examples, vec!["HP:0009999", "HP:0009999"]);
+        assert_eq!(parsed.population.len(), 1);
+        let individual = &parsed.population["MINE:1"];
+        assert_eq!(individual["ID"], "MINE:1");
+        assert_eq!(individual["Terms"], "HP:0000002");
+        assert_eq!(individual["HPO_Names"], "Term 2, left");
+        assert_eq!(individual["Genes"], "ADH6; BRCA1");
+        assert_eq!(individual["Dx/Udx"], "Diagnosed");
+        assert_eq!(individual["Clin diagnosis"], "Something");
+        assert_eq!(individual["Site"], "SLC");
+    }
+
+    #[test]
+    fn reads_a_tsv_with_mapped_columns() {
+        let body = "patient\thpo\tgene\tstatus\n\
+            A\t2|HP:3\tADH6,BRCA1\tUndiagnosed\n\
+            \tHP:0000002\t\tDiagnosed\n";
+        let mapping = ColumnMapping {
+            id: Some("patient".to_string()),
+            terms: Some("hpo".to_string()),
+            genes: Some("gene".to_string()),
+            diagnosis: Some("status".to_string()),
+            ..mapping("tsv")
+        };
+        let parsed = parse(&ontology(), "mine", &mapping, body.as_bytes()).unwrap();
+
+        assert_eq!((parsed.rows, parsed.skipped_rows, parsed.unknown_terms), (2, 1, 0));
+        let individual = &parsed.population["MINE:A"];
+        assert_eq!(individual["Terms"], "HP:0000002; HP:0000003");
+        assert_eq!(individual["HPO_Names"], "None");
+        assert_eq!(individual["Genes"], "ADH6; BRCA1");
+        assert_eq!(individual["Dx/Udx"], "Undiagnosed");
+    }
+
+    #[test]
+    fn rejects_a_mapped_column_the_file_lacks() {
+        let genes = ColumnMapping { genes: Some("gene".to_string()), ..mapping("tsv") };
+        assert_eq!(parse(&ontology(), "mine", &genes, b"ID\tTerms\n1\tHP:0000002\n").unwrap_err(), "no column named gene");
+        assert!(parse(&ontology(), "mine", &mapping("csv"), b"Name,Terms\n1,HP:0000002\n").unwrap_err().starts_with("no ID column"));
+    }
+
+    #[test]
+    fn reads_phenopackets() {
+        let solved = serde_json::json!({
+            "id": "P1",
+            "subject": { "id": "S1" },
+            "phenotypicFeatures": [
+                { "type": { "id": "HP:0000002", "label": "Term 2" } },
+                { "type": { "id": "HP:0000003", "label": "Term 3" }, "excluded": true },
+            ],
+            "interpretations": [
+                {
+                    "progressStatus": "SOLVED",
+                    "diagnosis": {
+                        "disease": { "label": "Disease A" },
+                        "genomicInterpretations": [
+                            { "variantInterpretation": { "variationDescriptor": { "geneContext": { "symbol": "ADH6" } } } },
+                            { "gene": { "symbol": "BRCA1" } },
+                        ],
+                    },
+                },
+                {
+                    "progressStatus": "IN_PROGRESS",
+                    "diagnosis": { "genomicInterpretations": [{ "gene": { "symbol": "ADH6" } }] },
+                },
+            ],
+        });
+        let unsolved = serde_json::json!({
+            "subject": { "id": "S2" },
+            "phenotypicFeatures": [{ "type": { "id": "HP:0000003" } }],
+        });
+        let body = format!("{}\n\n{}\n", solved, unsolved);
+        let parsed = parse(&ontology(), "mine", &mapping("phenopackets"), body.as_bytes()).unwrap();
+
+        assert_eq!((parsed.rows, parsed.skipped_rows), (2, 0));
+        let individual = &parsed.population["MINE:P1"];
+        assert_eq!(individual["Terms"], "HP:0000002");
+        assert_eq!(individual["HPO_Names"], "Term 2");
+        // ADH6 comes again after BRCA1 and is still listed once
+        assert_eq!(individual["Genes"], "ADH6; BRCA1");
+        assert_eq!(individual["Dx/Udx"], "Diagnosed");
+        assert_eq!(individual["Clin diagnosis"], "Disease A");
+
+        let individual = &parsed.population["MINE:S2"];
+        assert_eq!(individual["Genes"], "");
+        assert_eq!(individual["Dx/Udx"], "Undiagnosed");
+        assert_eq!(individual["Clin diagnosis"], "None");
+
+        assert!(parse(&ontology(), "mine", &mapping("phenopackets"), b"{\"id\": ").unwrap_err().starts_with("line 1"));
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use csv::ReaderBuilder;
+use serde::Serialize;
+use hpo::Ontology;
+use crate::population::{self, Population};
+use crate::population_store::{self, PopulationStore, Provenance};
+use crate::cohort_filter::CohortFilter;
+use crate::error::ApiError;
+
+// One load of a population. Routes hold on to the snapshot they started with, so a reload never changes
+// the data under a request that is already running
+pub struct PopulationSnapshot {
+    pub name: String,
+    pub population: Arc<Population>,
+    default_cohort: Arc<Population>, // The individuals routes match against when no filter is given, kept so they aren't copied per request
+    store: Arc<PopulationStore>, // Where cohort queries run
+    pub version: u64, // Starts at 1 and goes up with every reload
+    pub loaded_at: u64, // Unix seconds
+    pub provenance: Provenance,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PopulationSummary {
+    pub name: String,
+    pub individuals: usize, // Loaded, before any compare filter
+    pub version: u64,
+    pub loaded_at: u64,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+impl PopulationSnapshot {
+    // The individuals matching a filter, shared with the snapshot rather than copied for the default cohort or a
+    // filter that matches everyone
+    pub fn cohort(&self, ontology: &Ontology, filter: &CohortFilter) -> Result<Arc<Population>, ApiError> {
+        if filter.is_default_for(&self.name) {
+            return Ok(Arc::clone(&self.default_cohort));
+        }
+        filter.apply(ontology, &self.store, &self.name, &self.population)
+    }
+
+    pub fn summary(&self) -> PopulationSummary {
+        PopulationSummary {
+            name: self.name.clone(),
+            individuals: self.population.len(),
+            version: self.version,
+            loaded_at: self.loaded_at,
+            provenance: self.provenance.clone(),
+        }
+    }
+}
+
+// The loaded populations by name, each swapped out whole when it is reloaded. Every population is read out
+// of the store whole, the built in files are ingested into it when they change. Routes narrow them down with a
+// cohort_filter::CohortFilter
+pub struct Populations {
+    snapshots: RwLock<HashMap<String, Arc<PopulationSnapshot>>>,
+    store: Arc<PopulationStore>,
+}
+
+impl Populations {
+    // Loads every named population, failing on the first that can't be read, then every stored upload. An upload
+    // that no longer validates is left out with a warning rather than stopping the server
+    pub fn load(ontology: &Ontology, names: &[&str], store: PopulationStore) -> Result<Populations, String> {
+        let populations = Populations { snapshots: RwLock::new(HashMap::new()), store: Arc::new(store) };
+        for name in names {
+            populations.reload(ontology, name).map_err(|e| e.message())?;
+        }
+
+        let uploads = populations.store.uploaded_names().map_err(|e| format!("could not read the population store: {}", e))?;
+        for name in uploads {
+            if let Err(e) = populations.reload(ontology, &name) {
+                eprintln!("Warning: not loading uploaded population {}: {}", name, e.message());
+            }
+        }
+        Ok(populations)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<PopulationSnapshot>> {
+        self.snapshots.read().unwrap().get(name).cloned()
+    }
+
+    // Every current snapshot, by name
+    pub fn snapshots(&self) -> Vec<Arc<PopulationSnapshot>> {
+        let mut snapshots: Vec<Arc<PopulationSnapshot>> = self.snapshots.read().unwrap().values().cloned().collect();
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        snapshots
+    }
+
+    pub fn list(&self) -> Vec<PopulationSummary> {
+        self.snapshots().iter().map(|snapshot| snapshot.summary()).collect()
+    }
+
+    // Re-ingests a built in population's file if it has changed, or re-reads an uploaded one from the store, and
+    // swaps it in. The current snapshot stays in place when the file can't be read, is empty, or none of its terms
+    // are in the ontology
+    pub fn reload(&self, ontology: &Ontology, name: &str) -> Result<Arc<PopulationSnapshot>, ApiError> {
+        if crate::population_url(name).is_some() {
+            ingest(ontology, &self.store, name, false)?;
+        }
+        let (population, provenance) = read(&self.store, name)?;
+        validate(ontology, name, &population).map_err(ApiError::BadRequest)?;
+        self.swap(ontology, name, population, provenance)
+    }
+
+    // Validates and stores an uploaded population, replacing an earlier upload with the same name. The built in
+    // names can't be uploaded over
+    pub fn upload(&self, ontology: &Ontology, name: &str, population: Population, provenance: Provenance) -> Result<Arc<PopulationSnapshot>, ApiError> {
+        check_upload_name(name)?;
+        validate(ontology, name, &population).map_err(ApiError::BadRequest)?;
+
+        self.store.save(name, &provenance, &population)
+            .map_err(|e| ApiError::Internal(format!("could not save population {}: {}", name, e)))?;
+        let (population, provenance) = read(&self.store, name)?;
+        self.swap(ontology, name, population, provenance)
+    }
+
+    // Removes an uploaded population from the store and from the routes. Requests already running finish on it
+    pub fn delete(&self, name: &str) -> Result<(), ApiError> {
+        if crate::population_url(name).is_some() {
+            return Err(ApiError::BadRequest(format!("{} is a built in population and can't be deleted", name)));
+        }
+        let deleted = self.store.delete(name)
+            .map_err(|e| ApiError::Internal(format!("could not delete population {}: {}", name, e)))?;
+        if self.snapshots.write().unwrap().remove(name).is_none() && !deleted {
+            return Err(ApiError::NotFound(format!("unknown population: {}", name)));
+        }
+        Ok(())
+    }
+
+    // Puts a new snapshot in place, one version on from the one it replaces
+    fn swap(&self, ontology: &Ontology, name: &str, population: Population, provenance: Provenance) -> Result<Arc<PopulationSnapshot>, ApiError> {
+        let population = Arc::new(population);
+        let default_cohort = CohortFilter::default_for(name).apply(ontology, &self.store, name, &population)?;
+        let mut snapshots = self.snapshots.write().unwrap();
+        let version = snapshots.get(name).map(|snapshot| snapshot.version + 1).unwrap_or(1);
+        let snapshot = Arc::new(PopulationSnapshot {
+            name: name.to_string(),
+            population,
+            default_cohort,
+            store: Arc::clone(&self.store),
+            version,
+            loaded_at: population_store::now(),
+            provenance,
+        });
+        snapshots.insert(name.to_string(), Arc::clone(&snapshot));
+        Ok(snapshot)
+    }
+}
+
+// The Dx/Udx of the individuals a population is matched against unless asked otherwise. UDN only matches diagnosed patients
+// by default, the other sources have no undiagnosed individuals
+pub fn default_diagnosis(name: &str) -> Option<String> {
+    match name {
+        "udn" => Some("Diagnosed".to_string()),
+        _ => None,
+    }
+}
+
+// Normalizes a built in population's file into the store. Unless forced it is skipped when the file's hash matches
+// the one stored, so restarts only re-parse files that changed. Returns the stored provenance and whether it was re-read
+pub fn ingest(ontology: &Ontology, store: &PopulationStore, name: &str, force: bool) -> Result<(Provenance, bool), ApiError> {
+    let url = crate::population_url(name).ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", name)))?;
+    let bytes = std::fs::read(url).map_err(|e| ApiError::BadRequest(format!("could not read population {}: {}", name, e)))?;
+    let file_hash = population_store::hash_bytes(&bytes);
+    let stored = store.provenance(name).map_err(store_error)?;
+    if let Some(provenance) = stored.filter(|provenance| !force && provenance.file_hash == file_hash) {
+        return Ok((provenance, false));
+    }
+
+    let population = population::create_population(name, url.to_string())
+        .ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", name)))?
+        .map_err(|e| ApiError::BadRequest(format!("could not read population {}: {}", name, e)))?;
+    validate(ontology, name, &population).map_err(ApiError::BadRequest)?;
+
+    let format = if url.ends_with(".tsv") { "tsv" } else { "csv" };
+    let delimiter = if format == "tsv" { b'\t' } else { b',' };
+    let provenance = Provenance {
+        source: url.to_string(),
+        format: format.to_string(),
+        file_hash,
+        row_count: ReaderBuilder::new().delimiter(delimiter).flexible(true).from_reader(&bytes[..]).records().count(),
+        individuals_stored: population.len(),
+        uploaded: false,
+        ingested_at: population_store::now(),
+    };
+    store.save(name, &provenance, &population).map_err(store_error)?;
+    println!("Ingested population {} from {} ({} rows, {} individuals)", name, url, provenance.row_count, provenance.individuals_stored);
+    Ok((provenance, true))
+}
+
+// Reads a population out of the store
+pub fn read(store: &PopulationStore, name: &str) -> Result<(Population, Provenance), ApiError> {
+    let provenance = store.provenance(name).map_err(store_error)?
+        .ok_or_else(|| ApiError::NotFound(format!("unknown population: {}", name)))?;
+    let population = store.population(name).map_err(store_error)?;
+    Ok((population, provenance))
+}
+
+fn store_error(e: rusqlite::Error) -> ApiError {
+    ApiError::Internal(format!("population store error: {}", e))
+}
+
+// Uploads can't take a built in name, and names are kept to what is safe in a URL path
+pub fn check_upload_name(name: &str) -> Result<(), ApiError> {
+    if crate::population_url(name).is_some() {
+        return Err(ApiError::BadRequest(format!("{} is a built in population and can't be replaced", name)));
+    }
+    let valid = !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(ApiError::BadRequest(format!("invalid population name: {}, use up to 64 lowercase letters, digits, - or _", name)));
+    }
+    Ok(())
+}
+
+fn validate(ontology: &Ontology, name: &str, population: &Population) -> Result<(), String> {
+    if population.is_empty() {
+        return Err(format!("population {} has no individuals", name));
+    }
+    let has_known_term = population.values().flat_map(population::raw_terms).any(|term| {
+        term.trim_start_matches("HP:").parse::<u32>().ok().and_then(|id| ontology.hpo(id)).is_some()
+    });
+    if !has_known_term {
+        return Err(format!("none of the terms in population {} are in the ontology", name));
+    }
+    Ok(())
+}
+
+// The (name, population) pairs version::check takes
+pub fn as_checked(snapshots: &[Arc<PopulationSnapshot>]) -> Vec<(&str, &Population)> {
+    snapshots.iter().map(|snapshot| (snapshot.name.as_str(), &*snapshot.population)).collect()
+}
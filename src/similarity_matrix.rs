@@ -0,0 +1,320 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::Serialize;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use hpo::similarity::{GroupSimilarity, StandardCombiner};
+use hpo::{HpoSet, Ontology};
+use hpo::term::HpoGroup;
+use crate::population::{self, Population};
+use crate::{calc_scores, custom_jaccard_ic};
+
+// Binary layout, gzipped and all little endian: MAGIC, VERSION (u32), rows (u32), columns (u32), then each row id
+// and each column id as a length (u32) and UTF-8 bytes, then every row's column scores quantized to u16
+const MAGIC: &[u8; 4] = b"SMTX";
+const VERSION: u32 = 1;
+
+// Similarity scores are in [0, 1] and stored as u16 steps of 1 / QUANT
+const QUANT: f32 = u16::MAX as f32;
+
+// Neighbors kept per row when no N is given
+pub const DEFAULT_TOP_N: usize = 10;
+
+// Rows are computed and checkpointed this many at a time, a rerun skips the chunks already on disk
+const CHUNK_ROWS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatrixFormat {
+    Neighbors, // The top N most similar columns of every row, as TSV
+    Tsv, // The dense matrix as TSV
+    Binary, // The dense matrix in the layout above, for sets too large for TSV
+}
+
+impl MatrixFormat {
+    pub fn parse(format: &str) -> Option<MatrixFormat> {
+        match format.to_lowercase().as_str() {
+            "neighbors" => Some(MatrixFormat::Neighbors),
+            "tsv" => Some(MatrixFormat::Tsv),
+            "binary" => Some(MatrixFormat::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MatrixFormat::Neighbors => "neighbors",
+            MatrixFormat::Tsv => "tsv",
+            MatrixFormat::Binary => "binary",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MatrixFormat::Binary => "smtx.gz",
+            _ => "tsv",
+        }
+    }
+}
+
+// The individuals on one side of the matrix, sorted by id so the output is the same on every run
+pub struct Profiles {
+    pub ids: Vec<String>,
+    pub terms: Vec<Vec<u32>>,
+}
+
+impl Profiles {
+    pub fn new(ontology: &Arc<Ontology>, population: &Population) -> Profiles {
+        let mut individuals: Vec<(&String, Vec<u32>)> = population.iter()
+            .map(|(id, individual)| (id, population::individual_terms(ontology, individual)))
+            .collect();
+        individuals.sort_by(|a, b| a.0.cmp(b.0));
+        Profiles {
+            ids: individuals.iter().map(|(id, _)| id.to_string()).collect(),
+            terms: individuals.into_iter().map(|(_, terms)| terms).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct MatrixSummary {
+    pub rows: usize,
+    pub columns: usize,
+    pub format: String,
+    pub out: String,
+    pub resumed_chunks: usize, // Chunks found from an earlier, interrupted run
+}
+
+// Scores every row against every column and writes the result to out. Work is checkpointed per chunk of rows
+// in a "<out>.parts" folder next to it, so an interrupted run picks up where it stopped when run again with the
+// same inputs. progress is called with (rows done, rows) after every chunk
+pub fn compute(ontology: &Arc<Ontology>, rows: &Profiles, columns: &Profiles, format: MatrixFormat, top_n: usize, out: &str, progress: &dyn Fn(usize, usize)) -> std::io::Result<MatrixSummary> {
+    let parts = PathBuf::from(format!("{}.parts", out));
+    let manifest = manifest(rows, columns, format, top_n);
+    let manifest_path = parts.join("manifest");
+    // Parts from a run with other inputs can't be reused
+    if fs::read_to_string(&manifest_path).ok().as_deref() != Some(manifest.as_str()) {
+        if parts.exists() {
+            fs::remove_dir_all(&parts)?;
+        }
+        fs::create_dir_all(&parts)?;
+        fs::write(&manifest_path, &manifest)?;
+    }
+
+    let sim = calc_scores::group_similarity();
+    let column_sets = hpo_sets(ontology, columns);
+    let chunks = rows.len().div_ceil(CHUNK_ROWS);
+    let mut resumed_chunks = 0;
+    for chunk in 0..chunks {
+        let path = part_path(&parts, chunk);
+        let start = chunk * CHUNK_ROWS;
+        let end = (start + CHUNK_ROWS).min(rows.len());
+        if path.exists() {
+            resumed_chunks += 1;
+        } else {
+            let scores = score_rows(ontology, &sim, rows, start..end, &column_sets);
+            write_part(&path, &scores, rows, columns, start, format, top_n)?;
+        }
+        progress(end, rows.len());
+    }
+
+    let tmp = format!("{}.tmp", out);
+    assemble(&parts, chunks, rows, columns, format, &tmp)?;
+    fs::rename(&tmp, out)?;
+    fs::remove_dir_all(&parts)?;
+
+    Ok(MatrixSummary { rows: rows.len(), columns: columns.len(), format: format.name().to_string(), out: out.to_string(), resumed_chunks })
+}
+
+// Each row's top N columns as (column, score) pairs, best first, without writing anything. Rows are scored a chunk
+// at a time so only the neighbors are held in memory
+pub fn neighbor_lists(ontology: &Arc<Ontology>, rows: &Profiles, columns: &Profiles, top_n: usize) -> Vec<Vec<(usize, f32)>> {
+    let sim = calc_scores::group_similarity();
+    let column_sets = hpo_sets(ontology, columns);
+    let mut lists = Vec::with_capacity(rows.len());
+    for start in (0..rows.len()).step_by(CHUNK_ROWS) {
+        let end = (start + CHUNK_ROWS).min(rows.len());
+        for (offset, scores) in score_rows(ontology, &sim, rows, start..end, &column_sets).iter().enumerate() {
+            let neighbors = top_neighbors(&rows.ids[start + offset], scores, columns, top_n);
+            lists.push(neighbors.into_iter().map(|(column, score)| (column, score as f32 / QUANT)).collect());
+        }
+    }
+    lists
+}
+
+// The whole matrix in memory, for sets small enough that rows x columns scores fit
+pub fn dense(ontology: &Arc<Ontology>, rows: &Profiles, columns: &Profiles) -> Vec<Vec<f32>> {
+    let sim = calc_scores::group_similarity();
+    let column_sets = hpo_sets(ontology, columns);
+    score_rows(ontology, &sim, rows, 0..rows.len(), &column_sets)
+        .into_iter()
+        .map(|scores| scores.into_iter().map(|score| score as f32 / QUANT).collect())
+        .collect()
+}
+
+fn hpo_sets<'a>(ontology: &'a Arc<Ontology>, profiles: &Profiles) -> Vec<HpoSet<'a>> {
+    profiles.terms.iter().map(|terms| HpoSet::new(ontology, HpoGroup::from(terms.clone()))).collect()
+}
+
+// Quantized scores of the rows in range against every column, the rows spread across the available cores
+fn score_rows(
+    ontology: &Arc<Ontology>,
+    sim: &GroupSimilarity<custom_jaccard_ic::CustomJaccardIC, StandardCombiner>,
+    rows: &Profiles,
+    range: std::ops::Range<usize>,
+    column_sets: &[HpoSet],
+) -> Vec<Vec<u16>> {
+    let indexes: Vec<usize> = range.collect();
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let chunk_size = indexes.len().div_ceil(threads).max(1);
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = indexes
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter().map(|row| {
+                        let row_set = HpoSet::new(ontology, HpoGroup::from(rows.terms[*row].clone()));
+                        column_sets.iter().map(|column_set| quantize(sim.calculate(&row_set, column_set))).collect::<Vec<u16>>()
+                    }).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
+// Dense parts hold every score, neighbor parts only each row's top N as (column, score) pairs
+fn write_part(path: &Path, scores: &[Vec<u16>], rows: &Profiles, columns: &Profiles, start: usize, format: MatrixFormat, top_n: usize) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    for (offset, row_scores) in scores.iter().enumerate() {
+        if format == MatrixFormat::Neighbors {
+            let neighbors = top_neighbors(&rows.ids[start + offset], row_scores, columns, top_n);
+            out.write_all(&(neighbors.len() as u32).to_le_bytes())?;
+            for (column, score) in neighbors {
+                out.write_all(&(column as u32).to_le_bytes())?;
+                out.write_all(&score.to_le_bytes())?;
+            }
+        } else {
+            for score in row_scores {
+                out.write_all(&score.to_le_bytes())?;
+            }
+        }
+    }
+    out.flush()?;
+    drop(out);
+    // Renamed into place so a part on disk is always complete
+    fs::rename(tmp, path)
+}
+
+// The most similar columns, best first and by id on ties, leaving out the row itself
+fn top_neighbors(row_id: &str, scores: &[u16], columns: &Profiles, top_n: usize) -> Vec<(usize, u16)> {
+    let mut neighbors: Vec<(usize, u16)> = scores.iter().copied().enumerate().filter(|(column, _)| columns.ids[*column] != row_id).collect();
+    neighbors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| columns.ids[a.0].cmp(&columns.ids[b.0])));
+    neighbors.truncate(top_n);
+    neighbors
+}
+
+// Writes the parts out in the final format, gzipping the binary one
+fn assemble(parts: &Path, chunks: usize, rows: &Profiles, columns: &Profiles, format: MatrixFormat, tmp: &str) -> std::io::Result<()> {
+    let file = BufWriter::new(File::create(tmp)?);
+    if format == MatrixFormat::Binary {
+        let mut out = GzEncoder::new(file, Compression::default());
+        write_matrix(&mut out, parts, chunks, rows, columns, format)?;
+        out.finish()?.flush()
+    } else {
+        let mut out = file;
+        write_matrix(&mut out, parts, chunks, rows, columns, format)?;
+        out.flush()
+    }
+}
+
+fn write_matrix(out: &mut impl Write, parts: &Path, chunks: usize, rows: &Profiles, columns: &Profiles, format: MatrixFormat) -> std::io::Result<()> {
+    match format {
+        MatrixFormat::Binary => {
+            out.write_all(MAGIC)?;
+            out.write_all(&VERSION.to_le_bytes())?;
+            out.write_all(&(rows.len() as u32).to_le_bytes())?;
+            out.write_all(&(columns.len() as u32).to_le_bytes())?;
+            for id in rows.ids.iter().chain(columns.ids.iter()) {
+                out.write_all(&(id.len() as u32).to_le_bytes())?;
+                out.write_all(id.as_bytes())?;
+            }
+        }
+        MatrixFormat::Tsv => writeln!(out, "id\t{}", columns.ids.join("\t"))?,
+        MatrixFormat::Neighbors => writeln!(out, "id\trank\tneighbor\tscore")?,
+    }
+
+    let mut buf = [0u8; 4];
+    for chunk in 0..chunks {
+        let mut input = BufReader::new(File::open(part_path(parts, chunk))?);
+        let start = chunk * CHUNK_ROWS;
+        let end = (start + CHUNK_ROWS).min(rows.len());
+        for row in start..end {
+            match format {
+                MatrixFormat::Binary => {
+                    let mut scores = vec![0u8; columns.len() * 2];
+                    input.read_exact(&mut scores)?;
+                    out.write_all(&scores)?;
+                }
+                MatrixFormat::Tsv => {
+                    write!(out, "{}", rows.ids[row])?;
+                    for _ in 0..columns.len() {
+                        write!(out, "\t{:.4}", read_score(&mut input)?)?;
+                    }
+                    writeln!(out)?;
+                }
+                MatrixFormat::Neighbors => {
+                    input.read_exact(&mut buf)?;
+                    for rank in 1..=u32::from_le_bytes(buf) {
+                        input.read_exact(&mut buf)?;
+                        let column = u32::from_le_bytes(buf) as usize;
+                        writeln!(out, "{}\t{}\t{}\t{:.4}", rows.ids[row], rank, columns.ids[column], read_score(&mut input)?)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_score(input: &mut impl Read) -> std::io::Result<f32> {
+    let mut bytes = [0u8; 2];
+    input.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes) as f32 / QUANT)
+}
+
+fn part_path(parts: &Path, chunk: usize) -> PathBuf {
+    parts.join(format!("{:06}.part", chunk))
+}
+
+// Identifies the inputs of a run: the format, N, and FNV-1a over every row and column id and term
+fn manifest(rows: &Profiles, columns: &Profiles, format: MatrixFormat, top_n: usize) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    for profiles in [rows, columns] {
+        feed(&(profiles.len() as u64).to_le_bytes());
+        for (id, terms) in profiles.ids.iter().zip(&profiles.terms) {
+            feed(id.as_bytes());
+            for term in terms {
+                feed(&term.to_le_bytes());
+            }
+        }
+    }
+    format!("version {}\nformat {}\ntop {}\nchunk rows {}\ninputs {:016x}\n", VERSION, format.name(), top_n, CHUNK_ROWS, hash)
+}
+
+fn quantize(score: f32) -> u16 {
+    (score.clamp(0.0, 1.0) * QUANT).round() as u16
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use hpo::similarity::{Similarity, StandardCombiner, GroupSimilarity};
+use hpo::{Ontology, HpoSet};
+use hpo::term::HpoGroup;
+use crate::{custom_jaccard_ic, population, calc_simpheny_score};
+
+// Scale and degrees of freedom applied to the Fisher statistic by the empirical Brown's method
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BrownsParams {
+    pub scale: f64,
+    pub dof: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<CalibrationInfo>, // None for the built in values
+}
+
+// How a set of parameters was fitted, kept so a background can be refitted the same way
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CalibrationInfo {
+    pub population: String,
+    pub num_hpo_terms: u32,
+    pub num_query_genes: u32,
+    pub samples: u32,
+    pub draws: u32,
+    pub seed: u64,
+}
+
+// How many of the individuals sharing a gene with each sampled hit are scored against it while calibrating
+const PAIRS_PER_SAMPLE: usize = 50;
+
+pub type Backgrounds = HashMap<String, BrownsParams>;
+
+// The values SimPheny shipped with before calibration was available
+pub fn default_backgrounds() -> Backgrounds {
+    let mut backgrounds: Backgrounds = HashMap::new();
+    backgrounds.insert("udn".to_string(), BrownsParams { scale: 1.0703270447328037, dof: 3.737175491999793, calibration: None });
+    backgrounds.insert("clinvar".to_string(), BrownsParams { scale: 1.0334745692972533, dof: 3.8704387305049393, calibration: None });
+    backgrounds
+}
+
+// Defaults overlaid with anything previously fitted and saved to backgrounds_url
+pub fn load_backgrounds(backgrounds_url: &str) -> Backgrounds {
+    let mut backgrounds = default_backgrounds();
+    // A missing file just means nothing has been calibrated yet
+    if let Ok(contents) = fs::read_to_string(backgrounds_url) {
+        match serde_json::from_str::<Backgrounds>(&contents) {
+            Ok(saved) => backgrounds.extend(saved),
+            Err(e) => eprintln!("Warning: could not parse backgrounds file {}: {}", backgrounds_url, e),
+        }
+    }
+    backgrounds
+}
+
+pub fn save_backgrounds(backgrounds_url: &str, backgrounds: &Backgrounds) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(backgrounds)?;
+    fs::write(backgrounds_url, json)
+}
+
+// Estimates Brown's method parameters from pairs of individuals in a population.
+// Each sample picks a hit, builds its null from `draws` random queries, then scores the individuals sharing a gene
+// with it as queries the same way calc_simpheny_score would. The covariance of the two -2ln(p) terms across
+// those pairs gives the empirical Brown's method variance 8 + 2cov with the expectation fixed at 4,
+// so scale = var / 8 and dof = 32 / var.
+// Only gene-sharing pairs are used: in a pair without a shared gene the gene p-value is always 1, and with most
+// pairs like that the covariance comes out near 0 and the fit reduces to Fisher's method. A population where no two
+// individuals share a gene can't be fitted
+#[allow(clippy::too_many_arguments)]
+pub fn fit_browns_params(ontology: &Arc<Ontology>, population_name: &str, population: &HashMap<String, HashMap<String, String>>, all_term_list: &[u32], all_gene_list: &[String], num_hpo_terms: u32, num_query_genes: u32, samples: u32, draws: u32, seed: u64) -> Option<BrownsParams> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let num_terms = num_hpo_terms.min(calc_simpheny_score::MAX_QUERY_TERMS);
+
+    // Sorted so the same seed picks the same individuals regardless of HashMap ordering
+    let mut profiles: Vec<(Vec<u32>, Vec<String>)> = population
+        .values()
+        .map(|individual| (population::individual_terms(ontology, individual), population::individual_genes(individual)))
+        .filter(|(terms, _)| !terms.is_empty())
+        .collect();
+    profiles.sort();
+
+    // The individuals sharing a gene with each one, hits are only drawn from those with some
+    let mut carriers: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, (_, genes)) in profiles.iter().enumerate() {
+        for gene in genes {
+            carriers.entry(gene.as_str()).or_default().push(index);
+        }
+    }
+    let partners: Vec<Vec<usize>> = profiles.iter().enumerate().map(|(index, (_, genes))| {
+        let mut partners: Vec<usize> = genes.iter()
+            .flat_map(|gene| carriers[gene.as_str()].iter().copied())
+            .filter(|&other| other != index)
+            .collect();
+        partners.sort_unstable();
+        partners.dedup();
+        partners
+    }).collect();
+    let hits: Vec<usize> = (0..profiles.len()).filter(|&i| !partners[i].is_empty()).collect();
+
+    if hits.is_empty() || draws == 0 || all_term_list.is_empty() {
+        return None;
+    }
+
+    let sim = GroupSimilarity::new(StandardCombiner::default(), custom_jaccard_ic::CustomJaccardIC{});
+    let mut pheno_stats: Vec<f64> = Vec::new();
+    let mut gene_stats: Vec<f64> = Vec::new();
+
+    for _ in 0..samples {
+        let hit_index = hits[rng.random_range(0..hits.len())];
+        let (hit_terms, hit_genes) = &profiles[hit_index];
+        let hit_set = HpoSet::new(ontology, HpoGroup::from(hit_terms.clone()));
+
+        // The hit's null, shared by every query paired with it
+        let mut null_scores: Vec<f32> = Vec::with_capacity(draws as usize);
+        for _ in 0..draws {
+            let random_terms = calc_simpheny_score::sample_terms(all_term_list, num_terms, &mut rng);
+            let random_set = HpoSet::new(ontology, HpoGroup::from(random_terms));
+            null_scores.push(sim.calculate(&hit_set, &random_set));
+        }
+        null_scores.sort_by(f32::total_cmp);
+
+        for &query_index in partners[hit_index].choose_multiple(&mut rng, PAIRS_PER_SAMPLE) {
+            let (query_terms, query_genes) = &profiles[query_index];
+            let query_terms: Vec<u32> = query_terms.choose_multiple(&mut rng, num_terms as usize).cloned().collect();
+            let query_set = HpoSet::new(ontology, HpoGroup::from(query_terms));
+            let score = sim.calculate(&hit_set, &query_set);
+
+            let pheno_hits = null_scores.len() - null_scores.partition_point(|s| *s < score);
+            let pheno_p = (pheno_hits as f64 + 1.0) / (draws as f64 + 1.0);
+
+            // The shared gene gets the p-value the scorer would give it
+            let Some(shared) = hit_genes.iter().find(|g| query_genes.contains(g)) else { continue };
+            let gene_p = calc_simpheny_score::calc_gene_p_val(all_gene_list, shared, num_query_genes);
+
+            pheno_stats.push(-2.0 * pheno_p.ln());
+            gene_stats.push(-2.0 * gene_p.ln());
+        }
+    }
+
+    if pheno_stats.len() < 2 {
+        return None;
+    }
+    let n = pheno_stats.len() as f64;
+    let pheno_mean = pheno_stats.iter().sum::<f64>() / n;
+    let gene_mean = gene_stats.iter().sum::<f64>() / n;
+    let cov = pheno_stats.iter().zip(gene_stats.iter())
+        .map(|(p, g)| (p - pheno_mean) * (g - gene_mean))
+        .sum::<f64>() / (n - 1.0);
+
+    // Each -2ln(p) is chi-squared with 2 dof under the null, so the sum has mean 4 and variance 8 plus the covariance
+    let mean = 4.0;
+    let var = 8.0 + 2.0 * cov;
+    if var.is_nan() || var <= 0.0 {
+        return None;
+    }
+
+    Some(BrownsParams {
+        scale: var / (2.0 * mean),
+        dof: 2.0 * mean * mean / var,
+        calibration: Some(CalibrationInfo {
+            population: population_name.to_string(),
+            num_hpo_terms: num_terms,
+            num_query_genes,
+            samples,
+            draws,
+            seed,
+        }),
+    })
+}
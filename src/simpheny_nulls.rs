@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use hpo::similarity::{Similarity, StandardCombiner, GroupSimilarity};
+use hpo::annotations::AnnotationId;
+use hpo::{Ontology, HpoSet};
+use hpo::term::HpoGroup;
+use crate::{custom_jaccard_ic, calc_simpheny_score};
+
+// File layout, all little endian: MAGIC, VERSION (u32), ontology fingerprint (u64), term list fingerprint (u64),
+// draws (u32), max query size (u32), profile count (u32), then per profile its key (u64) followed by max query
+// size blocks of `draws` sorted quantized scores (u16)
+const MAGIC: &[u8; 4] = b"SPNL";
+const VERSION: u32 = 2;
+const HEADER_BYTES: u64 = 4 + 4 + 8 + 8 + 4 + 4 + 4;
+
+// Similarity scores are in [0, 1] and stored as u16 steps of 1 / QUANT
+const QUANT: f32 = u16::MAX as f32;
+
+// Null similarity distributions for every target profile of a population and every query size from 1 to
+// MAX_QUERY_TERMS, so a SimPheny phenotype p-value is a binary search rather than a simulation
+pub struct NullTable {
+    pub draws: u32,
+    pub max_size: u32,
+    ontology: u64, // ontology_fingerprint of the ontology the nulls were sampled with
+    term_list: u64, // term_list_fingerprint of the terms random queries were drawn from
+    profiles: HashMap<u64, Vec<Vec<u16>>>, // profile key -> one sorted null per query size
+}
+
+impl NullTable {
+    pub fn new(draws: u32, ontology: &Ontology, all_term_list: &[u32]) -> NullTable {
+        NullTable {
+            draws,
+            max_size: calc_simpheny_score::MAX_QUERY_TERMS,
+            ontology: ontology_fingerprint(ontology),
+            term_list: term_list_fingerprint(all_term_list),
+            profiles: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.profiles.len()
+    }
+
+    // Exceedance count and null size for a hit profile at a query size, None if the profile wasn't precomputed
+    pub fn lookup(&self, hit_terms: &[u32], num_hpo_terms: u32, sim_score: f32) -> Option<(u32, u32)> {
+        if num_hpo_terms == 0 || num_hpo_terms > self.max_size {
+            return None;
+        }
+        let null = &self.profiles.get(&profile_key(hit_terms))?[(num_hpo_terms - 1) as usize];
+        let observed = quantize(sim_score);
+        let hits = null.len() - null.partition_point(|s| *s < observed);
+        Some((hits as u32, null.len() as u32))
+    }
+
+    // Samples the nulls for every profile not already in the table, spread across the available cores.
+    // Each profile gets its own generator derived from seed so the result doesn't depend on the thread count
+    pub fn add_profiles(&mut self, ontology: &Arc<Ontology>, profiles: Vec<Vec<u32>>, all_term_list: &[u32], seed: u64) {
+        let mut todo: Vec<(u64, Vec<u32>)> = profiles
+            .into_iter()
+            .filter(|terms| !terms.is_empty())
+            .map(|terms| (profile_key(&terms), terms))
+            .filter(|(key, _)| !self.profiles.contains_key(key))
+            .collect();
+        todo.sort();
+        todo.dedup_by_key(|(key, _)| *key);
+
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let chunk_size = todo.len().div_ceil(threads).max(1);
+        let draws = self.draws;
+        let max_size = self.max_size;
+
+        let computed: Vec<(u64, Vec<Vec<u16>>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = todo
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        let sim = GroupSimilarity::new(StandardCombiner::default(), custom_jaccard_ic::CustomJaccardIC{});
+                        chunk.iter().map(|(key, terms)| {
+                            let mut rng = ChaCha8Rng::seed_from_u64(seed ^ key);
+                            let hit_set = HpoSet::new(ontology, HpoGroup::from(terms.clone()));
+                            let nulls = (1..=max_size).map(|size| {
+                                let mut null: Vec<u16> = (0..draws).map(|_| {
+                                    let random_terms = calc_simpheny_score::sample_terms(all_term_list, size, &mut rng);
+                                    let random_set = HpoSet::new(ontology, HpoGroup::from(random_terms));
+                                    quantize(sim.calculate(&hit_set, &random_set))
+                                }).collect();
+                                null.sort_unstable();
+                                null
+                            }).collect();
+                            (*key, nulls)
+                        }).collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+
+        self.profiles.extend(computed);
+    }
+
+    pub fn write(&self, path: &str) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&self.ontology.to_le_bytes())?;
+        out.write_all(&self.term_list.to_le_bytes())?;
+        out.write_all(&self.draws.to_le_bytes())?;
+        out.write_all(&self.max_size.to_le_bytes())?;
+        out.write_all(&(self.profiles.len() as u32).to_le_bytes())?;
+
+        // Written in key order so the same table always produces the same bytes
+        let mut keys: Vec<&u64> = self.profiles.keys().collect();
+        keys.sort();
+        for key in keys {
+            out.write_all(&key.to_le_bytes())?;
+            for null in &self.profiles[key] {
+                for score in null {
+                    out.write_all(&score.to_le_bytes())?;
+                }
+            }
+        }
+        out.flush()
+    }
+
+    // Fails unless the file was built from the same ontology and term list, since its nulls would be wrong for
+    // any other
+    pub fn read(path: &str, ontology: &Ontology, all_term_list: &[u32]) -> std::io::Result<NullTable> {
+        let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        let file = File::open(path)?;
+        let file_bytes = file.metadata()?.len();
+        let mut input = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a SimPheny null file".to_string()));
+        }
+        let version = read_u32(&mut input)?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported null file version {}", version)));
+        }
+        if read_u64(&mut input)? != ontology_fingerprint(ontology) {
+            return Err(invalid("the nulls were built from a different ontology, run precompute-nulls again".to_string()));
+        }
+        if read_u64(&mut input)? != term_list_fingerprint(all_term_list) {
+            return Err(invalid("the nulls were built from a different term list, run precompute-nulls again".to_string()));
+        }
+        let draws = read_u32(&mut input)?;
+        let max_size = read_u32(&mut input)?;
+        let count = read_u32(&mut input)?;
+        // Checked against the file's size before anything is allocated from the header
+        let expected = HEADER_BYTES + count as u64 * (8 + max_size as u64 * draws as u64 * 2);
+        if expected != file_bytes {
+            return Err(invalid(format!("the null file is {} bytes, its header says {}", file_bytes, expected)));
+        }
+
+        let mut profiles = HashMap::new();
+        let mut buf = vec![0u8; draws as usize * 2];
+        for _ in 0..count {
+            let mut key = [0u8; 8];
+            input.read_exact(&mut key)?;
+            let nulls = (0..max_size).map(|_| {
+                input.read_exact(&mut buf)?;
+                Ok(buf.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect())
+            }).collect::<std::io::Result<Vec<Vec<u16>>>>()?;
+            profiles.insert(u64::from_le_bytes(key), nulls);
+        }
+        Ok(NullTable { draws, max_size, ontology: ontology_fingerprint(ontology), term_list: term_list_fingerprint(all_term_list), profiles })
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn quantize(score: f32) -> u16 {
+    (score.clamp(0.0, 1.0) * QUANT).round() as u16
+}
+
+// FNV-1a over the sorted, deduplicated term ids. Stable across builds unlike the std hashers
+pub fn profile_key(terms: &[u32]) -> u64 {
+    let mut sorted = terms.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    fnv1a(sorted.iter().flat_map(|term| term.to_le_bytes()))
+}
+
+// What the nulls depend on in the ontology: its version, every term's parents and the disease information
+// content the similarity is weighted by. Ontology::as_bytes can't be used, its genes come out in hash map order
+pub fn ontology_fingerprint(ontology: &Ontology) -> u64 {
+    let terms = ontology.iter().flat_map(|term| {
+        let mut bytes = term.id().as_u32().to_le_bytes().to_vec();
+        bytes.extend(term.parent_ids().iter().flat_map(|parent| parent.as_u32().to_le_bytes()));
+        bytes.extend(term.information_content().omim_disease().to_le_bytes());
+        bytes
+    });
+    fnv1a(ontology.hpo_version().into_bytes().into_iter().chain(terms))
+}
+
+// The term list in order, since random queries are drawn from it by position
+pub fn term_list_fingerprint(all_term_list: &[u32]) -> u64 {
+    fnv1a(all_term_list.iter().flat_map(|term| term.to_le_bytes()))
+}
+
+fn fnv1a(bytes: impl Iterator<Item = u8>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // HP:0000001 with HP:0000002 and HP:0000003 below it, and HP:0000004 below HP:0000002
+    fn ontology() -> Ontology {
+        let mut ontology = Ontology::default();
+        for id in 1u32..=4 {
+            ontology.insert_term(format!("Term {}", id), id);
+        }
+        ontology.add_parent(1u32, 2u32);
+        ontology.add_parent(1u32, 3u32);
+        ontology.add_parent(2u32, 4u32);
+        ontology.create_cache();
+        ontology
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("simpheny_nulls_{}_{}", std::process::id(), name)).to_string_lossy().to_string()
+    }
+
+    // Four draws per query size, the null for size n being 0, n / 20, 0.5 and 1
+    fn table(ontology: &Ontology, all_term_list: &[u32]) -> NullTable {
+        let mut table = NullTable::new(4, ontology, all_term_list);
+        let nulls = (1..=table.max_size).map(|size| vec![0, quantize(size as f32 / 20.0), quantize(0.5), quantize(1.0)]).collect();
+        table.profiles.insert(profile_key(&[4, 2]), nulls);
+        table
+    }
+
+    #[test]
+    fn lookups_survive_a_write_and_read() {
+        let ontology = ontology();
+        let terms = vec![2, 3, 4];
+        let path = temp_path("round_trip");
+        let written = table(&ontology, &terms);
+        written.write(&path).unwrap();
+        let read = NullTable::read(&path, &ontology, &terms).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((read.draws, read.max_size, read.len()), (4, written.max_size, 1));
+        for (size, score) in [(1, 0.0), (1, 0.05), (3, 0.1), (3, 0.2), (10, 0.6), (10, 1.0)] {
+            assert_eq!(read.lookup(&[2, 4], size, score), written.lookup(&[2, 4], size, score));
+        }
+        // Profiles are keyed by their terms in any order
+        assert_eq!(read.lookup(&[4, 2, 4], 3, 0.2), Some((2, 4)));
+        assert_eq!(read.lookup(&[2, 3], 3, 0.2), None);
+        assert_eq!(read.lookup(&[2, 4], 0, 0.2), None);
+        assert_eq!(read.lookup(&[2, 4], read.max_size + 1, 0.2), None);
+    }
+
+    #[test]
+    fn rejects_nulls_from_another_ontology_or_term_list() {
+        let ontology = ontology();
+        let terms = vec![2, 3, 4];
+        let path = temp_path("mismatch");
+        table(&ontology, &terms).write(&path).unwrap();
+
+        let mut moved = ontology.clone();
+        moved.add_parent(3u32, 4u32);
+        moved.create_cache();
+        let other_ontology = NullTable::read(&path, &moved, &terms);
+        let other_terms = NullTable::read(&path, &ontology, &[2, 3]);
+        let reordered_terms = NullTable::read(&path, &ontology, &[4, 3, 2]);
+        std::fs::remove_file(&path).unwrap();
+
+        for result in [other_ontology, other_terms, reordered_terms] {
+            assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+        }
+    }
+
+    #[test]
+    fn rejects_a_header_that_disagrees_with_the_file_size() {
+        let ontology = ontology();
+        let terms = vec![2, 3, 4];
+        let path = temp_path("truncated");
+        table(&ontology, &terms).write(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 2]).unwrap();
+        let truncated = NullTable::read(&path, &ontology, &terms);
+
+        // A profile count far past what the file holds
+        let mut inflated = bytes.clone();
+        inflated[HEADER_BYTES as usize - 4..HEADER_BYTES as usize].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &inflated).unwrap();
+        let inflated = NullTable::read(&path, &ontology, &terms);
+        std::fs::remove_file(&path).unwrap();
+
+        for result in [truncated, inflated] {
+            assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use statrs::distribution::{Discrete, DiscreteCDF, Hypergeometric};
+use hpo::Ontology;
+use hpo::annotations::AnnotationId;
+use crate::calc_scores::{self, ScoreReturn};
+use crate::error::ApiError;
+use crate::population::{self, Population};
+use crate::similarity_matrix::Profiles;
+
+// Every term descends from "All", so every individual has it
+const ROOT_ID: u32 = 1;
+
+// Compare hits taken as the subset when no top is given
+const DEFAULT_TOP: usize = 50;
+
+// Terms returned when no limit is given
+const DEFAULT_LIMIT: usize = 100;
+
+// Relative tolerance when two-sided Fisher sums the tables as or less likely than the one observed
+const FISHER_TOLERANCE: f64 = 1e-7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Test {
+    Hypergeometric, // One sided, the chance of the subset having the term this often or more
+    Fisher, // Fisher's exact test, two sided, so terms the subset lacks show up too
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Correction {
+    BenjaminiHochberg, // False discovery rate, the q-value
+    Bonferroni, // Family wise error rate
+    None,
+}
+
+impl Test {
+    pub fn parse(test: Option<&str>) -> Result<Test, ApiError> {
+        match test.map(|t| t.to_lowercase()).as_deref() {
+            None | Some("hypergeometric") => Ok(Test::Hypergeometric),
+            Some("fisher") => Ok(Test::Fisher),
+            Some(other) => Err(ApiError::BadRequest(format!("unknown test: {}, expected hypergeometric or fisher", other))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Test::Hypergeometric => "hypergeometric",
+            Test::Fisher => "fisher",
+        }
+    }
+}
+
+impl Correction {
+    pub fn parse(correction: Option<&str>) -> Result<Correction, ApiError> {
+        match correction.map(|c| c.to_lowercase()).as_deref() {
+            None | Some("bh") | Some("fdr") => Ok(Correction::BenjaminiHochberg),
+            Some("bonferroni") => Ok(Correction::Bonferroni),
+            Some("none") => Ok(Correction::None),
+            Some(other) => Err(ApiError::BadRequest(format!("unknown correction: {}, expected bh, bonferroni or none", other))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Correction::BenjaminiHochberg => "bh",
+            Correction::Bonferroni => "bonferroni",
+            Correction::None => "none",
+        }
+    }
+}
+
+// The subset is one of individuals, everyone with gene, or the top hits of comparing terms against the population.
+// The population is narrowed to diagnosis first, the population's default cohort when left out
+#[derive(Deserialize, Debug)]
+pub struct EnrichmentRequest {
+    pub population: String,
+    #[serde(default)]
+    pub individuals: Vec<String>,
+    pub gene: Option<String>,
+    #[serde(default)]
+    pub terms: Vec<String>,
+    pub top: Option<usize>, // Compare hits in the subset, default DEFAULT_TOP
+    pub diagnosis: Option<String>,
+    pub test: Option<String>, // hypergeometric (default) or fisher
+    pub correction: Option<String>, // bh (default), bonferroni or none
+    pub min_count: Option<usize>, // Terms fewer subset members have aren't tested, default 2
+    pub max_q: Option<f64>, // Terms above this q-value are left out, default 1
+    pub limit: Option<usize>, // Terms returned, default DEFAULT_LIMIT
+}
+
+#[derive(Serialize, Debug)]
+pub struct EnrichmentReport {
+    pub population: String,
+    pub population_size: usize,
+    pub subset: String, // How the subset was chosen
+    pub subset_size: usize,
+    pub unknown_individuals: Vec<String>, // Asked for but not in the population's cohort
+    pub test: String,
+    pub correction: String,
+    pub tested: usize, // Terms tested, the number the correction is over
+    pub terms: Vec<TermEnrichment>,
+}
+
+// One term tested in a subset against its background
+#[derive(Serialize, Debug, Clone)]
+pub struct TermEnrichment {
+    pub hpo_id: String,
+    pub name: String,
+    pub count: usize, // Subset members with the term or a term below it
+    pub frequency: f32, // count / subset size
+    pub population_count: usize,
+    pub population_frequency: f32,
+    pub fold: f32, // frequency / population_frequency
+    pub p_value: f64,
+    pub q_value: f64, // p_value after the correction
+}
+
+// The propagated terms of every individual of a population, which a subset of them is tested against
+pub struct Background {
+    terms: Vec<HashSet<u32>>,
+    counts: HashMap<u32, usize>,
+}
+
+impl Background {
+    pub fn new(ontology: &Ontology, profiles: &[Vec<u32>]) -> Background {
+        let terms: Vec<HashSet<u32>> = profiles.iter().map(|terms| propagated_terms(ontology, terms)).collect();
+        let mut counts = HashMap::new();
+        for term in terms.iter().flatten() {
+            *counts.entry(*term).or_insert(0) += 1;
+        }
+        Background { terms, counts }
+    }
+
+    pub fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    // Tests every term at least min_count of the subset's members have, the subset given as positions in the
+    // background. Sorted by p-value, then by fold enrichment
+    pub fn test(&self, ontology: &Ontology, subset: &[usize], test: Test, correction: Correction, min_count: usize) -> Vec<TermEnrichment> {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for member in subset {
+            for term in &self.terms[*member] {
+                *counts.entry(*term).or_insert(0) += 1;
+            }
+        }
+
+        let population = self.len() as u64;
+        let draws = subset.len() as u64;
+        let mut results: Vec<TermEnrichment> = counts.into_iter()
+            .filter(|(_, count)| *count >= min_count.max(1))
+            .filter_map(|(term, count)| {
+                let hpo = ontology.hpo(term)?;
+                let population_count = self.counts[&term];
+                let p_value = p_value(test, population, population_count as u64, draws, count as u64);
+                let frequency = count as f32 / subset.len() as f32;
+                let population_frequency = population_count as f32 / self.len() as f32;
+                Some(TermEnrichment {
+                    hpo_id: hpo.id().to_string(),
+                    name: hpo.name().to_string(),
+                    count,
+                    frequency,
+                    population_count,
+                    population_frequency,
+                    fold: frequency / population_frequency,
+                    p_value,
+                    q_value: p_value,
+                })
+            })
+            .collect();
+
+        adjust(&mut results, correction);
+        results.sort_by(|a, b| {
+            a.p_value.total_cmp(&b.p_value)
+                .then_with(|| b.fold.total_cmp(&a.fold))
+                .then_with(|| a.hpo_id.cmp(&b.hpo_id))
+        });
+        results
+    }
+}
+
+// Tests which terms the requested subset of a population has more often than the population does
+pub fn enrich(ontology: &Arc<Ontology>, population: &Arc<Population>, request: &EnrichmentRequest) -> Result<EnrichmentReport, ApiError> {
+    let test = Test::parse(request.test.as_deref())?;
+    let correction = Correction::parse(request.correction.as_deref())?;
+    let profiles = Profiles::new(ontology, population);
+    let positions: HashMap<&str, usize> = profiles.ids.iter().enumerate().map(|(idx, id)| (id.as_str(), idx)).collect();
+
+    let mut unknown_individuals = Vec::new();
+    let (description, members): (String, Vec<&str>) = match (request.individuals.is_empty(), &request.gene, request.terms.is_empty()) {
+        (false, None, true) => {
+            let found = request.individuals.iter().filter(|id| {
+                let known = positions.contains_key(id.as_str());
+                if !known {
+                    unknown_individuals.push(id.to_string());
+                }
+                known
+            });
+            ("individuals".to_string(), found.map(|id| id.as_str()).collect())
+        }
+        (true, Some(gene), true) => {
+            let found = profiles.ids.iter().filter(|id| {
+                population::individual_genes(&population[id.as_str()]).iter().any(|g| g.eq_ignore_ascii_case(gene.trim()))
+            });
+            (format!("gene {}", gene.trim()), found.map(|id| id.as_str()).collect())
+        }
+        (true, None, false) => {
+            let top = request.top.unwrap_or(DEFAULT_TOP).max(1);
+            (format!("top {} compare hits", top), top_hits(ontology, population, &request.terms, top)?)
+        }
+        _ => return Err(ApiError::BadRequest("give exactly one of individuals, gene or terms".to_string())),
+    };
+    let mut subset: Vec<usize> = members.iter().map(|id| positions[id]).collect();
+    subset.sort_unstable();
+    subset.dedup();
+    if subset.is_empty() {
+        return Err(ApiError::BadRequest(format!("no individuals of {} are in the subset ({})", request.population, description)));
+    }
+
+    let background = Background::new(ontology, &profiles.terms);
+    let results = background.test(ontology, &subset, test, correction, request.min_count.unwrap_or(2));
+    let tested = results.len();
+    let max_q = request.max_q.unwrap_or(1.0);
+    let terms = results.into_iter()
+        .filter(|term| term.q_value <= max_q)
+        .take(request.limit.unwrap_or(DEFAULT_LIMIT))
+        .collect();
+
+    Ok(EnrichmentReport {
+        population: request.population.clone(),
+        population_size: background.len(),
+        subset: description,
+        subset_size: subset.len(),
+        unknown_individuals,
+        test: test.name().to_string(),
+        correction: correction.name().to_string(),
+        tested,
+        terms,
+    })
+}
+
+// The ids of the best scoring individuals when terms are compared against the population
+fn top_hits<'a>(ontology: &Arc<Ontology>, population: &'a Arc<Population>, terms: &[String], top: usize) -> Result<Vec<&'a str>, ApiError> {
+    let hpo_ids: Vec<u32> = terms.iter()
+        .filter_map(|id| id.trim().trim_start_matches("HP:").parse::<u32>().ok())
+        .filter(|id| ontology.hpo(*id).is_some())
+        .collect();
+    if hpo_ids.is_empty() {
+        return Err(ApiError::BadRequest("no HPO terms found in the ontology for the given terms".to_string()));
+    }
+    let scores = calc_scores::calc_scores(ontology, hpo_ids, population);
+    let ranked = match scores.get("ranked_vec") {
+        Some(ScoreReturn::ScoreVec(ranked)) => ranked,
+        _ => return Err(ApiError::Internal("compare returned no ranking".to_string())),
+    };
+    Ok(ranked.iter()
+        .take(top)
+        .filter_map(|hit| population.get_key_value(&hit[0]).map(|(id, _)| id.as_str()))
+        .collect())
+}
+
+// The chance of k or more of n draws having a term that successes of population have, or for Fisher the chance of
+// any table at most as likely as the one observed
+fn p_value(test: Test, population: u64, successes: u64, draws: u64, k: u64) -> f64 {
+    let distribution = match Hypergeometric::new(population, successes, draws) {
+        Ok(distribution) => distribution,
+        Err(_) => return 1.0,
+    };
+    let p = match test {
+        Test::Hypergeometric => if k == 0 { 1.0 } else { distribution.sf(k - 1) },
+        Test::Fisher => {
+            let observed = distribution.pmf(k) * (1.0 + FISHER_TOLERANCE);
+            (successes.saturating_sub(population - draws)..=successes.min(draws))
+                .map(|i| distribution.pmf(i))
+                .filter(|p| *p <= observed)
+                .sum()
+        }
+    };
+    p.clamp(0.0, 1.0)
+}
+
+// Sets q_value from p_value over all the results
+fn adjust(results: &mut [TermEnrichment], correction: Correction) {
+    let tests = results.len() as f64;
+    match correction {
+        Correction::None => {}
+        Correction::Bonferroni => {
+            for result in results.iter_mut() {
+                result.q_value = (result.p_value * tests).min(1.0);
+            }
+        }
+        Correction::BenjaminiHochberg => {
+            // q at rank i is the smallest p * tests / rank over ranks i and above
+            let mut order: Vec<usize> = (0..results.len()).collect();
+            order.sort_by(|a, b| results[*b].p_value.total_cmp(&results[*a].p_value));
+            let mut running: f64 = 1.0;
+            for (idx, position) in order.into_iter().enumerate() {
+                let rank = tests - idx as f64;
+                running = running.min(results[position].p_value * tests / rank);
+                results[position].q_value = running;
+            }
+        }
+    }
+}
+
+// A profile's terms with all their ancestors, leaving out the root
+pub fn propagated_terms(ontology: &Ontology, terms: &[u32]) -> HashSet<u32> {
+    let mut propagated = HashSet::new();
+    for term in terms.iter().filter_map(|id| ontology.hpo(*id)) {
+        propagated.insert(term.id().as_u32());
+        propagated.extend(term.all_parent_ids().iter().map(|id| id.as_u32()));
+    }
+    propagated.remove(&ROOT_ID);
+    propagated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    fn results(p_values: &[f64]) -> Vec<TermEnrichment> {
+        p_values.iter().enumerate().map(|(i, p)| TermEnrichment {
+            hpo_id: format!("HP:{:07}", i),
+            name: String::new(),
+            count: 0,
+            frequency: 0.0,
+            population_count: 0,
+            population_frequency: 0.0,
+            fold: 0.0,
+            p_value: *p,
+            q_value: *p,
+        }).collect()
+    }
+
+    fn q_values(results: &[TermEnrichment]) -> Vec<f64> {
+        results.iter().map(|r| r.q_value).collect()
+    }
+
+    #[test]
+    fn fisher_matches_r() {
+        // fisher.test(matrix(c(3, 1, 1, 3), 2))$p.value, the tea tasting table
+        assert!(close(p_value(Test::Fisher, 8, 4, 4, 3), 0.4857143));
+        // fisher.test(matrix(c(1, 11, 9, 3), 2))$p.value
+        assert!(close(p_value(Test::Fisher, 24, 12, 10, 1), 0.002759456));
+        // A table as likely as it gets
+        assert!(close(p_value(Test::Fisher, 8, 4, 4, 2), 1.0));
+    }
+
+    #[test]
+    fn hypergeometric_matches_r() {
+        // phyper(2, 4, 4, 4, lower.tail = FALSE)
+        assert!(close(p_value(Test::Hypergeometric, 8, 4, 4, 3), 0.2428571));
+        // phyper(0, 12, 12, 10, lower.tail = FALSE)
+        assert!(close(p_value(Test::Hypergeometric, 24, 12, 10, 1), 0.9999663));
+        assert!(close(p_value(Test::Hypergeometric, 24, 12, 10, 0), 1.0));
+    }
+
+    #[test]
+    fn benjamini_hochberg_matches_r() {
+        // p.adjust(c(0.01, 0.04, 0.03, 0.2), "BH")
+        let mut tested = results(&[0.01, 0.04, 0.03, 0.2]);
+        adjust(&mut tested, Correction::BenjaminiHochberg);
+        for (q, expected) in q_values(&tested).into_iter().zip([0.04, 0.05333333, 0.05333333, 0.2]) {
+            assert!(close(q, expected));
+        }
+        // p.adjust(c(0.01, 0.02, 0.03, 0.04, 0.05), "BH")
+        let mut tested = results(&[0.01, 0.02, 0.03, 0.04, 0.05]);
+        adjust(&mut tested, Correction::BenjaminiHochberg);
+        assert!(q_values(&tested).into_iter().all(|q| close(q, 0.05)));
+    }
+
+    #[test]
+    fn bonferroni_matches_r() {
+        // p.adjust(c(0.01, 0.04, 0.03, 0.3), "bonferroni")
+        let mut tested = results(&[0.01, 0.04, 0.03, 0.3]);
+        adjust(&mut tested, Correction::Bonferroni);
+        for (q, expected) in q_values(&tested).into_iter().zip([0.04, 0.16, 0.12, 1.0]) {
+            assert!(close(q, expected));
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::cmp::Ordering;
+use serde::Serialize;
+use crate::db::Term;
+
+// How much a match in each field counts towards a term's score
+const NAME_WEIGHT: f64 = 1.0;
+const SYNONYM_WEIGHT: f64 = 0.9;
+const DEFINITION_WEIGHT: f64 = 0.4;
+
+// Words shorter than this have to match exactly or as a prefix, typos are only forgiven in longer words
+const MIN_FUZZY_LEN: usize = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FieldKind {
+    Name,
+    Synonym,
+    Definition,
+}
+
+impl FieldKind {
+    fn label(self) -> &'static str {
+        match self {
+            FieldKind::Name => "name",
+            FieldKind::Synonym => "synonym",
+            FieldKind::Definition => "definition",
+        }
+    }
+
+    fn weight(self) -> f64 {
+        match self {
+            FieldKind::Name => NAME_WEIGHT,
+            FieldKind::Synonym => SYNONYM_WEIGHT,
+            FieldKind::Definition => DEFINITION_WEIGHT,
+        }
+    }
+}
+
+// A lowercased word and where it sits in the original text, in characters
+struct Word {
+    text: String,
+    start: usize,
+    end: usize,
+}
+
+struct Field {
+    kind: FieldKind,
+    text: String,
+    lowercase: String,
+    words: Vec<Word>,
+}
+
+struct Entry {
+    hpo_id: String,
+    name: Option<String>,
+    fields: Vec<Field>,
+}
+
+// In-memory search index over the names, synonyms and definitions in the Terms table, built once at startup
+pub struct TermIndex {
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TermHit {
+    pub hpo_id: String,
+    pub name: Option<String>,
+    pub score: f64,
+    pub matched_field: &'static str, // name, synonym, definition or id
+    pub matched_text: String, // The name, synonym or definition that matched
+    pub highlights: Vec<[usize; 2]>, // [start, end) character ranges of the matched words in matched_text
+}
+
+#[derive(Serialize, Debug)]
+pub struct TermSearchResults {
+    pub query: String,
+    pub total: usize, // Matches before limit and offset are applied
+    pub offset: usize,
+    pub limit: usize,
+    pub results: Vec<TermHit>,
+}
+
+impl TermIndex {
+    pub fn new(terms: Vec<Term>) -> TermIndex {
+        let entries = terms
+            .into_iter()
+            .map(|term| {
+                let mut fields = Vec::new();
+                if let Some(name) = &term.name {
+                    fields.push(Field::new(FieldKind::Name, name));
+                }
+                for synonym in term.synonym_list() {
+                    fields.push(Field::new(FieldKind::Synonym, &synonym));
+                }
+                if let Some(definition) = &term.definition {
+                    fields.push(Field::new(FieldKind::Definition, definition));
+                }
+                Entry { hpo_id: term.hpo_id, name: term.name, fields }
+            })
+            .collect();
+        TermIndex { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Every query word has to match a word of the same field, exactly, as a prefix or within a typo or two.
+    // Terms are ranked by their best field, names over synonyms over definitions
+    pub fn search(&self, query: &str, limit: usize, offset: usize) -> TermSearchResults {
+        let query_words: Vec<String> = tokenize(query).into_iter().map(|word| word.text).collect();
+        let query_lowercase = query.trim().to_lowercase();
+        // An id like "HP:00012" searches by id prefix instead
+        let id_query = query.trim().to_uppercase();
+        let by_id = id_query.starts_with("HP:");
+
+        let mut hits: Vec<TermHit> = self.entries
+            .iter()
+            .filter_map(|entry| {
+                if by_id {
+                    return entry.hpo_id.starts_with(&id_query).then(|| TermHit {
+                        hpo_id: entry.hpo_id.clone(),
+                        name: entry.name.clone(),
+                        score: id_query.len() as f64 / entry.hpo_id.len() as f64,
+                        matched_field: "id",
+                        matched_text: entry.hpo_id.clone(),
+                        highlights: vec![[0, id_query.chars().count()]],
+                    });
+                }
+                if query_words.is_empty() {
+                    return None;
+                }
+
+                entry.fields
+                    .iter()
+                    .filter_map(|field| field.score(&query_words, &query_lowercase).map(|(score, highlights)| (field, score, highlights)))
+                    .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+                    .map(|(field, score, highlights)| TermHit {
+                        hpo_id: entry.hpo_id.clone(),
+                        name: entry.name.clone(),
+                        score,
+                        matched_field: field.kind.label(),
+                        matched_text: field.text.clone(),
+                        highlights,
+                    })
+            })
+            .collect();
+
+        // Best score first, then shorter names since they are usually the more general term
+        hits.sort_by(|a, b| {
+            b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal)
+                .then_with(|| a.name.as_ref().map(|n| n.len()).cmp(&b.name.as_ref().map(|n| n.len())))
+                .then_with(|| a.hpo_id.cmp(&b.hpo_id))
+        });
+
+        let total = hits.len();
+        let results = hits.into_iter().skip(offset).take(limit).collect();
+        TermSearchResults { query: query.to_string(), total, offset, limit, results }
+    }
+}
+
+impl Field {
+    fn new(kind: FieldKind, text: &str) -> Field {
+        Field {
+            kind,
+            text: text.to_string(),
+            lowercase: text.to_lowercase(),
+            words: tokenize(text),
+        }
+    }
+
+    // The field's score for the query and the words that matched, None unless every query word matched
+    fn score(&self, query_words: &[String], query_lowercase: &str) -> Option<(f64, Vec<[usize; 2]>)> {
+        let fuzzy = self.kind != FieldKind::Definition;
+        let mut total = 0.0;
+        let mut highlights = Vec::new();
+
+        for query_word in query_words {
+            let (score, word) = self.words
+                .iter()
+                .filter_map(|word| word_score(query_word, &word.text, fuzzy).map(|score| (score, word)))
+                .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal))?;
+            total += score;
+            highlights.push([word.start, word.end]);
+        }
+        highlights.sort_unstable();
+        highlights.dedup();
+
+        let mut score = total / query_words.len() as f64;
+        // Whole phrase matches beat the same words scattered through the text
+        if self.lowercase == query_lowercase {
+            score += 0.5;
+        } else if self.lowercase.starts_with(query_lowercase) {
+            score += 0.2;
+        }
+        // And among equal matches the field with fewer extra words wins
+        let extra_words = self.words.len().saturating_sub(query_words.len()) as f64;
+        score -= (0.01 * extra_words).min(0.1);
+
+        Some((score * self.kind.weight(), highlights))
+    }
+}
+
+// How well a query word matches a word of the text, None if it doesn't
+fn word_score(query_word: &str, word: &str, fuzzy: bool) -> Option<f64> {
+    if word == query_word {
+        return Some(1.0);
+    }
+    if word.starts_with(query_word) {
+        // Longer prefixes are more specific
+        return Some(0.7 + 0.2 * query_word.len() as f64 / word.len() as f64);
+    }
+    let query_len = query_word.chars().count();
+    if !fuzzy || query_len < MIN_FUZZY_LEN {
+        return None;
+    }
+
+    let allowed = if query_len >= 8 { 2 } else { 1 };
+    if let Some(distance) = edit_distance(query_word, word, allowed) {
+        return Some(0.65 - 0.1 * distance as f64);
+    }
+    // Typos while still typing, compare against the start of the word
+    let word_prefix: String = word.chars().take(query_len).collect();
+    if word_prefix.chars().count() == query_len {
+        if let Some(distance) = edit_distance(query_word, &word_prefix, allowed) {
+            return Some(0.55 - 0.1 * distance as f64);
+        }
+    }
+    None
+}
+
+// Levenshtein distance with adjacent transpositions, None once it is certain to be over max
+fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+
+    let mut before_previous: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current: Vec<usize> = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        current[0] = i;
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current[j] = (previous[j] + 1).min(current[j - 1] + 1).min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before_previous[j - 2] + 1);
+            }
+        }
+        if current.iter().min().copied().unwrap_or(0) > max {
+            return None;
+        }
+        std::mem::swap(&mut before_previous, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    let distance = previous[b.len()];
+    if distance <= max { Some(distance) } else { None }
+}
+
+// Splits text into lowercased alphanumeric words, keeping their character offsets for highlighting
+fn tokenize(text: &str) -> Vec<Word> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+
+    for (i, c) in text.chars().enumerate() {
+        if c.is_alphanumeric() {
+            if current.is_empty() {
+                start = i;
+            }
+            current.extend(c.to_lowercase());
+        } else if !current.is_empty() {
+            words.push(Word { text: std::mem::take(&mut current), start, end: i });
+        }
+    }
+    if !current.is_empty() {
+        words.push(Word { text: current, start, end: text.chars().count() });
+    }
+    words
+}
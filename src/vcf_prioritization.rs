@@ -0,0 +1,488 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::sync::Arc;
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use hpo::{HpoSet, Ontology};
+use hpo::term::HpoGroup;
+use crate::calc_scores;
+use crate::error::ApiError;
+use crate::gene_search::GeneIndex;
+
+// SnpEff's ANN layout, used when the header doesn't describe it
+const DEFAULT_ANN_FIELDS: [&str; 4] = ["Allele", "Annotation", "Annotation_Impact", "Gene_Name"];
+
+// Population frequency fields, from INFO or from a VEP annotation. The largest one present is used. INFO's own AF
+// is left out since in a single sample VCF it is the sample's allele fraction
+const FREQUENCY_FIELDS: [&str; 7] = ["MAX_AF", "gnomAD_AF", "gnomADe_AF", "gnomADg_AF", "gnomad_AF", "AF_popmax", "AF"];
+
+// Variants listed per gene
+const MAX_GENE_VARIANTS: usize = 5;
+
+// A gzipped body is inflated up to this size, the route's length limit only bounds the compressed size
+const MAX_DECOMPRESSED_BYTES: u64 = 256 * 1024 * 1024;
+
+// How a VCF is filtered and its genes ranked, given as query parameters or CLI flags. Lists are comma separated
+#[derive(Deserialize, Debug, Default)]
+pub struct PrioritizeParams {
+    pub terms: String, // The patient's HPO terms
+    pub max_af: Option<f64>, // Variants more common than this are dropped, default 0.01
+    pub impacts: Option<String>, // Impacts kept, default HIGH,MODERATE
+    pub consequences: Option<String>, // When given, only these consequences (e.g. missense_variant) are kept
+    pub pass_only: Option<bool>, // Drop variants whose FILTER isn't PASS or ".", default true
+    pub phenotype_weight: Option<f64>, // Share of the combined score from the phenotype, default 0.5
+    pub limit: Option<usize>, // Genes returned, default 50
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct PrioritizationReport {
+    pub query_terms: Vec<String>,
+    pub variants: usize, // Records read
+    pub filtered: FilterCounts, // Records dropped, by the first filter they failed
+    pub genes_found: usize, // Genes with a variant that passed, before the limit
+    pub genes: Vec<GenePriority>, // Best first
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct FilterCounts {
+    pub malformed: usize, // Fewer than the 8 fixed VCF columns
+    pub not_pass: usize,
+    pub not_called: usize, // The first sample doesn't carry the alternate allele
+    pub no_annotation: usize, // No CSQ or ANN annotation with a gene
+    pub consequence: usize, // Only annotations with an impact or consequence that isn't kept
+    pub frequency: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GenePriority {
+    pub rank: usize,
+    pub gene: String, // As annotated in the VCF
+    pub hpo_gene: Option<String>, // The symbol the ontology knows it by, None if it has no annotations
+    pub combined_score: f64,
+    pub variant_score: f64, // The best variant's score
+    pub phenotype_score: f64, // phenotype_similarity relative to the best gene's
+    pub phenotype_similarity: f64, // Between the query terms and the gene's annotated terms
+    pub variants: Vec<VariantScore>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct VariantScore {
+    pub chrom: String,
+    pub pos: u64,
+    pub reference: String,
+    pub alternate: String,
+    pub genotype: Option<String>,
+    pub consequence: String,
+    pub impact: String,
+    pub frequency: Option<f64>, // None when no frequency field was found, treated as novel
+    pub pathogenicity: f64,
+    pub frequency_score: f64,
+    pub score: f64, // pathogenicity * frequency_score
+}
+
+// The filters once parsed
+struct Filters {
+    max_af: f64,
+    impacts: Vec<String>,
+    consequences: Vec<String>,
+    pass_only: bool,
+}
+
+// The INFO annotation fields a VCF was annotated with, in order
+struct AnnotationLayout {
+    key: &'static str, // CSQ or ANN
+    fields: Vec<String>,
+}
+
+// Reads a VCF (gzipped or not) annotated by VEP (CSQ) or SnpEff (ANN), keeps the rare variants with a kept impact
+// and ranks their genes by variant score and by how similar the gene's annotated terms are to the query
+pub fn prioritize(ontology: &Arc<Ontology>, gene_index: &GeneIndex, params: &PrioritizeParams, vcf: &[u8]) -> Result<PrioritizationReport, ApiError> {
+    let query: Vec<u32> = params.terms.split(',')
+        .filter_map(|id| id.trim().trim_start_matches("HP:").parse::<u32>().ok())
+        .filter(|id| ontology.hpo(*id).is_some())
+        .collect();
+    if query.is_empty() {
+        return Err(ApiError::BadRequest("no HPO terms found in the ontology for the given terms".to_string()));
+    }
+    let list = |value: &Option<String>, default: &str| -> Vec<String> {
+        value.as_deref().unwrap_or(default).split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+    };
+    let filters = Filters {
+        max_af: params.max_af.unwrap_or(0.01),
+        impacts: list(&params.impacts, "HIGH,MODERATE").iter().map(|i| i.to_uppercase()).collect(),
+        consequences: list(&params.consequences, "").iter().map(|c| c.to_lowercase()).collect(),
+        pass_only: params.pass_only.unwrap_or(true),
+    };
+    let weight = params.phenotype_weight.unwrap_or(0.5).clamp(0.0, 1.0);
+
+    let text = decompress(vcf)?;
+    let mut report = PrioritizationReport {
+        query_terms: query.iter().filter_map(|id| ontology.hpo(*id)).map(|term| term.id().to_string()).collect(),
+        ..PrioritizationReport::default()
+    };
+    let mut layout: Option<AnnotationLayout> = None;
+    let mut by_gene: BTreeMap<String, Vec<VariantScore>> = BTreeMap::new();
+    for line in text.lines() {
+        if let Some(header) = line.strip_prefix("##INFO=<") {
+            layout = layout.or_else(|| parse_layout(header));
+            continue;
+        }
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        report.variants += 1;
+        match score_record(line, layout.as_ref(), &filters) {
+            Ok(variants) => {
+                for (gene, variant) in variants {
+                    by_gene.entry(gene).or_default().push(variant);
+                }
+            }
+            Err(dropped) => dropped(&mut report.filtered),
+        }
+    }
+
+    // Phenotype similarity of every gene, made relative to the best so it is on the variant scores' 0 to 1 scale
+    let sim = calc_scores::group_similarity();
+    let query_set = HpoSet::new(ontology, HpoGroup::from(query));
+    let mut genes: Vec<GenePriority> = by_gene.into_iter().map(|(gene, mut variants)| {
+        variants.sort_by(|a, b| b.score.total_cmp(&a.score));
+        variants.truncate(MAX_GENE_VARIANTS);
+        let hpo_gene = ontology.gene_by_name(&gene).or_else(|| {
+            gene_index.resolve(&gene).symbol.and_then(|symbol| ontology.gene_by_name(&symbol))
+        });
+        let phenotype_similarity = hpo_gene.map(|g| sim.calculate(&query_set, &g.to_hpo_set(ontology)) as f64).unwrap_or(0.0);
+        GenePriority {
+            rank: 0,
+            hpo_gene: hpo_gene.map(|g| g.name().to_string()),
+            combined_score: 0.0,
+            variant_score: variants[0].score,
+            phenotype_score: 0.0,
+            phenotype_similarity,
+            variants,
+            gene,
+        }
+    }).collect();
+    let best_similarity = genes.iter().map(|g| g.phenotype_similarity).fold(0.0, f64::max);
+    for gene in genes.iter_mut() {
+        gene.phenotype_score = if best_similarity > 0.0 { gene.phenotype_similarity / best_similarity } else { 0.0 };
+        gene.combined_score = (1.0 - weight) * gene.variant_score + weight * gene.phenotype_score;
+    }
+    genes.sort_by(|a, b| b.combined_score.total_cmp(&a.combined_score).then_with(|| a.gene.cmp(&b.gene)));
+    report.genes_found = genes.len();
+    genes.truncate(params.limit.unwrap_or(50));
+    for (idx, gene) in genes.iter_mut().enumerate() {
+        gene.rank = idx + 1;
+    }
+    report.genes = genes;
+    Ok(report)
+}
+
+fn decompress(vcf: &[u8]) -> Result<String, ApiError> {
+    if vcf.starts_with(&[0x1f, 0x8b]) {
+        let mut text = String::new();
+        MultiGzDecoder::new(vcf).take(MAX_DECOMPRESSED_BYTES + 1).read_to_string(&mut text)
+            .map_err(|e| ApiError::BadRequest(format!("could not read the gzipped VCF: {}", e)))?;
+        if text.len() as u64 > MAX_DECOMPRESSED_BYTES {
+            return Err(ApiError::PayloadTooLarge(format!("the VCF is over {} MB uncompressed", MAX_DECOMPRESSED_BYTES / 1024 / 1024)));
+        }
+        Ok(text)
+    } else {
+        String::from_utf8(vcf.to_vec()).map_err(|e| ApiError::BadRequest(format!("the VCF must be UTF-8: {}", e)))
+    }
+}
+
+// The field order of a CSQ ("... Format: Allele|Consequence|...") or ANN ("... 'Allele | Annotation | ...'") header
+fn parse_layout(header: &str) -> Option<AnnotationLayout> {
+    let key = if header.starts_with("ID=CSQ,") { "CSQ" } else if header.starts_with("ID=ANN,") { "ANN" } else { return None };
+    let description = header.split("Description=\"").nth(1)?.trim_end_matches(">").trim_end_matches('"');
+    let format = match key {
+        "CSQ" => description.split("Format: ").nth(1)?,
+        _ => description.split('\'').nth(1).unwrap_or(description),
+    };
+    Some(AnnotationLayout { key, fields: format.split('|').map(|f| f.trim().to_string()).collect() })
+}
+
+type Dropped = fn(&mut FilterCounts);
+
+// The scored (gene, variant) pairs of one record, one per gene with its most damaging annotation, or the filter
+// it failed
+fn score_record(line: &str, layout: Option<&AnnotationLayout>, filters: &Filters) -> Result<Vec<(String, VariantScore)>, Dropped> {
+    let columns: Vec<&str> = line.split('\t').collect();
+    if columns.len() < 8 {
+        return Err(|counts| counts.malformed += 1);
+    }
+    if filters.pass_only && !matches!(columns[6], "PASS" | ".") {
+        return Err(|counts| counts.not_pass += 1);
+    }
+    let genotype = genotype(&columns);
+    if genotype.as_deref().is_some_and(|gt| !gt.split(['/', '|']).any(|allele| allele != "0" && allele != ".")) {
+        return Err(|counts| counts.not_called += 1);
+    }
+
+    let info: HashMap<&str, &str> = columns[7].split(';')
+        .map(|entry| entry.split_once('=').unwrap_or((entry, "")))
+        .collect();
+    let default_ann = AnnotationLayout { key: "ANN", fields: DEFAULT_ANN_FIELDS.iter().map(|f| f.to_string()).collect() };
+    let layout = match layout {
+        Some(layout) if info.contains_key(layout.key) => layout,
+        _ if info.contains_key("ANN") => &default_ann,
+        _ => return Err(|counts| counts.no_annotation += 1),
+    };
+    let info_frequency = max_frequency(FREQUENCY_FIELDS.iter().filter(|f| **f != "AF").filter_map(|f| info.get(f).copied()));
+    let clinvar_pathogenic = info.get("CLNSIG").is_some_and(|sig| sig.to_lowercase().contains("pathogenic") && !sig.to_lowercase().contains("conflicting"));
+
+    let mut best: BTreeMap<String, VariantScore> = BTreeMap::new();
+    let mut annotated = false;
+    let mut kept_consequence = false;
+    for entry in info[layout.key].split(',') {
+        let values: Vec<&str> = entry.split('|').collect();
+        let field = |names: &[&str]| -> Option<&str> {
+            names.iter()
+                .filter_map(|name| layout.fields.iter().position(|f| f == name))
+                .filter_map(|idx| values.get(idx).copied())
+                .find(|value| !value.is_empty())
+        };
+        let gene = match field(&["SYMBOL", "Gene_Name"]) {
+            Some(gene) => gene.to_string(),
+            None => continue,
+        };
+        annotated = true;
+        let consequence = field(&["Consequence", "Annotation"]).unwrap_or("").to_string();
+        let impact = field(&["IMPACT", "Annotation_Impact"]).unwrap_or("").to_uppercase();
+        let consequence_kept = filters.consequences.is_empty() || consequence.split('&').any(|c| filters.consequences.contains(&c.to_lowercase()));
+        if !filters.impacts.contains(&impact) || !consequence_kept {
+            continue;
+        }
+        kept_consequence = true;
+
+        let frequency = match (max_frequency(FREQUENCY_FIELDS.iter().filter_map(|f| field(&[f]))), info_frequency) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        if frequency.is_some_and(|f| f > filters.max_af) {
+            continue;
+        }
+        let pathogenicity = if clinvar_pathogenic { 1.0 } else { pathogenicity(&impact, &field) };
+        let frequency_score = frequency_score(frequency.unwrap_or(0.0));
+        let variant = VariantScore {
+            chrom: columns[0].to_string(),
+            pos: columns[1].parse().unwrap_or(0),
+            reference: columns[3].to_string(),
+            alternate: field(&["Allele"]).unwrap_or(columns[4]).to_string(),
+            genotype: genotype.clone(),
+            consequence,
+            impact,
+            frequency,
+            pathogenicity,
+            frequency_score,
+            score: pathogenicity * frequency_score,
+        };
+        if best.get(&gene).is_none_or(|current| variant.score > current.score) {
+            best.insert(gene, variant);
+        }
+    }
+
+    if !annotated {
+        return Err(|counts| counts.no_annotation += 1);
+    }
+    if !kept_consequence {
+        return Err(|counts| counts.consequence += 1);
+    }
+    if best.is_empty() {
+        return Err(|counts| counts.frequency += 1);
+    }
+    Ok(best.into_iter().collect())
+}
+
+// The first sample's GT, None for a sites only VCF
+fn genotype(columns: &[&str]) -> Option<String> {
+    let format = columns.get(8)?;
+    let sample = columns.get(9)?;
+    let idx = format.split(':').position(|f| f == "GT")?;
+    sample.split(':').nth(idx).map(|gt| gt.to_string())
+}
+
+// The largest of the frequencies given, each possibly a "&" or "," separated list
+fn max_frequency<'a>(values: impl Iterator<Item = &'a str>) -> Option<f64> {
+    values
+        .flat_map(|value| value.split(['&', ',']))
+        .filter_map(|value| value.trim().parse::<f64>().ok())
+        .fold(None, |max: Option<f64>, f| Some(max.map_or(f, |m| m.max(f))))
+}
+
+// How damaging an annotation looks, 0 to 1. Loss of function (HIGH) counts as 1. A MODERATE change, typically
+// missense, takes one predictor, the first annotated of REVEL, CADD, PolyPhen and SIFT, and 0.6 when none are.
+// REVEL comes first since it is an ensemble calibrated on missense pathogenicity, SIFT last since it calls the
+// most benign changes damaging. Taking the largest would let the least specific predictor decide
+fn pathogenicity<'a>(impact: &str, field: &dyn Fn(&[&str]) -> Option<&'a str>) -> f64 {
+    match impact {
+        "HIGH" => 1.0,
+        "MODERATE" => {
+            let number = |names: &[&str]| field(names).and_then(|value| {
+                // PolyPhen and SIFT come as "probably_damaging(0.99)"
+                let inner = value.split('(').nth(1).map(|v| v.trim_end_matches(')')).unwrap_or(value);
+                inner.split('&').filter_map(|v| v.parse::<f64>().ok()).reduce(f64::max)
+            });
+            number(&["REVEL", "REVEL_score"])
+                .or_else(|| number(&["CADD_PHRED", "CADD_phred"]).map(|phred| 1.0 - 10f64.powf(-phred / 10.0)))
+                .or_else(|| number(&["PolyPhen"]))
+                .or_else(|| number(&["SIFT"]).map(|sift| 1.0 - sift))
+                .unwrap_or(0.6)
+                .clamp(0.0, 1.0)
+        }
+        "LOW" => 0.2,
+        _ => 0.0,
+    }
+}
+
+// Exomiser's frequency score: 1 for a novel variant, falling to 0 once the frequency passes about 2%
+fn frequency_score(frequency: f64) -> f64 {
+    (1.13533 - 0.13533 * (frequency * 100.0).exp()).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSQ_HEADER: &str = "ID=CSQ,Number=.,Type=String,Description=\"Consequence annotations from Ensembl VEP. Format: Allele|Consequence|IMPACT|SYMBOL|gnomAD_AF|REVEL|SIFT\">";
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    fn filters() -> Filters {
+        Filters { max_af: 0.01, impacts: vec!["HIGH".to_string(), "MODERATE".to_string()], consequences: vec![], pass_only: true }
+    }
+
+    fn csq() -> AnnotationLayout {
+        parse_layout(CSQ_HEADER).unwrap()
+    }
+
+    fn record(filter: &str, info: &str, gt: &str) -> String {
+        format!("1\t1000\t.\tA\tT\t50\t{}\t{}\tGT:DP\t{}:30", filter, info, gt)
+    }
+
+    // The filter count a dropped record lands in
+    fn dropped(result: Result<Vec<(String, VariantScore)>, Dropped>) -> FilterCounts {
+        let mut counts = FilterCounts::default();
+        result.expect_err("the record should have been dropped")(&mut counts);
+        counts
+    }
+
+    #[test]
+    fn reads_csq_and_ann_layouts() {
+        let layout = csq();
+        assert_eq!(layout.key, "CSQ");
+        assert_eq!(layout.fields, vec!["Allele", "Consequence", "IMPACT", "SYMBOL", "gnomAD_AF", "REVEL", "SIFT"]);
+
+        let layout = parse_layout("ID=ANN,Number=.,Type=String,Description=\"Functional annotations: 'Allele | Annotation | Annotation_Impact | Gene_Name | Gene_ID '\">").unwrap();
+        assert_eq!(layout.key, "ANN");
+        assert_eq!(layout.fields, vec!["Allele", "Annotation", "Annotation_Impact", "Gene_Name", "Gene_ID"]);
+
+        assert!(parse_layout("ID=DP,Number=1,Type=Integer,Description=\"Depth\">").is_none());
+    }
+
+    #[test]
+    fn reads_the_first_samples_genotype() {
+        assert_eq!(genotype(&["1", "1", ".", "A", "T", ".", "PASS", ".", "GT:DP", "0/1:30"]).as_deref(), Some("0/1"));
+        assert_eq!(genotype(&["1", "1", ".", "A", "T", ".", "PASS", ".", "DP:GT", "30:1|1", "0/0:12"]).as_deref(), Some("1|1"));
+        assert_eq!(genotype(&["1", "1", ".", "A", "T", ".", "PASS", "."]), None);
+        assert_eq!(genotype(&["1", "1", ".", "A", "T", ".", "PASS", ".", "DP", "30"]), None);
+    }
+
+    #[test]
+    fn takes_the_largest_frequency() {
+        assert_eq!(max_frequency(["0.001&0.02", "0.005,.", "unknown"].iter().copied()), Some(0.02));
+        assert_eq!(max_frequency(["", "."].iter().copied()), None);
+        assert_eq!(max_frequency(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn frequency_score_falls_from_novel_to_two_percent() {
+        assert!(close(frequency_score(0.0), 1.0));
+        assert!(frequency_score(0.001) > frequency_score(0.01));
+        assert!(close(frequency_score(0.02), 1.13533 - 0.13533 * 2f64.exp()));
+        assert_eq!(frequency_score(0.03), 0.0);
+    }
+
+    #[test]
+    fn pathogenicity_takes_the_preferred_predictor() {
+        let score = |impact: &str, values: &[(&'static str, &'static str)]| {
+            let values: HashMap<&str, &str> = values.iter().copied().collect();
+            let field = |names: &[&str]| names.iter().find_map(|name| values.get(name).copied());
+            pathogenicity(impact, &field)
+        };
+        assert_eq!(score("HIGH", &[("REVEL", "0.1")]), 1.0);
+        assert_eq!(score("LOW", &[]), 0.2);
+        assert_eq!(score("MODIFIER", &[]), 0.0);
+        assert_eq!(score("MODERATE", &[]), 0.6);
+
+        // REVEL decides when it is there, even though SIFT alone would call it damaging
+        assert!(close(score("MODERATE", &[("REVEL", "0.1"), ("SIFT", "deleterious(0.01)")]), 0.1));
+        assert!(close(score("MODERATE", &[("REVEL_score", "0.2&0.7")]), 0.7));
+        assert!(close(score("MODERATE", &[("CADD_PHRED", "20"), ("PolyPhen", "benign(0.1)")]), 0.99));
+        assert!(close(score("MODERATE", &[("PolyPhen", "probably_damaging(0.95)"), ("SIFT", "deleterious(0)")]), 0.95));
+        assert!(close(score("MODERATE", &[("SIFT", "deleterious(0.02)")]), 0.98));
+    }
+
+    #[test]
+    fn scores_each_gene_by_its_most_damaging_annotation() {
+        let info = "DP=30;CSQ=T|missense_variant|MODERATE|GENE1|0.0001|0.5|,T|stop_gained|HIGH|GENE1|0.0001||,T|missense_variant|MODERATE|GENE2|||0.2";
+        let variants = score_record(&record("PASS", info, "0/1"), Some(&csq()), &filters()).unwrap();
+        assert_eq!(variants.len(), 2);
+
+        let (gene, variant) = &variants[0];
+        assert_eq!(gene, "GENE1");
+        assert_eq!(variant.consequence, "stop_gained");
+        assert_eq!(variant.pathogenicity, 1.0);
+        assert_eq!(variant.frequency, Some(0.0001));
+        assert!(close(variant.score, frequency_score(0.0001)));
+        assert_eq!(variant.genotype.as_deref(), Some("0/1"));
+        assert_eq!((variant.chrom.as_str(), variant.pos, variant.alternate.as_str()), ("1", 1000, "T"));
+
+        let (gene, variant) = &variants[1];
+        assert_eq!(gene, "GENE2");
+        assert_eq!(variant.frequency, None);
+        assert!(close(variant.pathogenicity, 0.8));
+        assert!(close(variant.score, 0.8));
+    }
+
+    #[test]
+    fn clinvar_pathogenic_counts_as_damaging() {
+        let info = "CLNSIG=Likely_pathogenic;CSQ=T|missense_variant|MODERATE|GENE1||0.1|";
+        let variants = score_record(&record("PASS", info, "1/1"), Some(&csq()), &filters()).unwrap();
+        assert_eq!(variants[0].1.pathogenicity, 1.0);
+
+        let info = "CLNSIG=Conflicting_interpretations_of_pathogenicity;CSQ=T|missense_variant|MODERATE|GENE1||0.1|";
+        let variants = score_record(&record("PASS", info, "1/1"), Some(&csq()), &filters()).unwrap();
+        assert!(close(variants[0].1.pathogenicity, 0.1));
+    }
+
+    #[test]
+    fn reads_snpeff_annotations_without_a_header() {
+        // INFO's own AF is the sample's allele fraction, not a population frequency
+        let info = "AF=0.5;ANN=T|missense_variant|MODERATE|GENE1";
+        let variants = score_record(&record(".", info, "0|1"), None, &filters()).unwrap();
+        assert_eq!(variants[0].0, "GENE1");
+        assert_eq!(variants[0].1.frequency, None);
+        assert_eq!(variants[0].1.pathogenicity, 0.6);
+    }
+
+    #[test]
+    fn counts_each_dropped_record_under_its_filter() {
+        let kept = "CSQ=T|missense_variant|MODERATE|GENE1|||";
+        assert_eq!(dropped(score_record("1\t1000\t.\tA\tT", Some(&csq()), &filters())).malformed, 1);
+        assert_eq!(dropped(score_record(&record("LowQual", kept, "0/1"), Some(&csq()), &filters())).not_pass, 1);
+        assert_eq!(dropped(score_record(&record("PASS", kept, "0/0"), Some(&csq()), &filters())).not_called, 1);
+        assert_eq!(dropped(score_record(&record("PASS", kept, "./."), Some(&csq()), &filters())).not_called, 1);
+        assert_eq!(dropped(score_record(&record("PASS", "DP=30", "0/1"), Some(&csq()), &filters())).no_annotation, 1);
+        assert_eq!(dropped(score_record(&record("PASS", "CSQ=T|intergenic_variant|MODIFIER||||", "0/1"), Some(&csq()), &filters())).no_annotation, 1);
+        assert_eq!(dropped(score_record(&record("PASS", "CSQ=T|synonymous_variant|LOW|GENE1|||", "0/1"), Some(&csq()), &filters())).consequence, 1);
+        assert_eq!(dropped(score_record(&record("PASS", "CSQ=T|missense_variant|MODERATE|GENE1|0.05||", "0/1"), Some(&csq()), &filters())).frequency, 1);
+        assert_eq!(dropped(score_record(&record("PASS", &format!("MAX_AF=0.2;{}", kept), "0/1"), Some(&csq()), &filters())).frequency, 1);
+
+        // A filtered record is kept when only PASS isn't asked for
+        let filters = Filters { pass_only: false, ..filters() };
+        assert!(score_record(&record("LowQual", kept, "0/1"), Some(&csq()), &filters).is_ok());
+    }
+}
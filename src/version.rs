@@ -0,0 +1,88 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde::Serialize;
+use hpo::Ontology;
+use crate::population::{self, Population};
+
+// Set to refuse to start when the ontology, hpo.db and populations don't agree
+pub const STRICT_ENV: &str = "PHENO_MATCHER_STRICT_VERSIONS";
+
+// How many of a population's missing terms are listed by id
+const MISSING_EXAMPLES: usize = 10;
+
+#[derive(Serialize, Debug)]
+pub struct PopulationCheck {
+    pub name: String,
+    pub individuals: usize,
+    pub terms: usize, // Distinct terms used
+    pub missing_terms: usize, // Distinct terms that don't parse or aren't in the ontology
+    pub missing_examples: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VersionReport {
+    pub server_version: String,
+    pub ontology_version: String, // From bin_hpo_file
+    pub ontology_terms: usize,
+    pub db_hpo_version: Option<String>, // From hpo.db's metadata, None when it has none
+    pub db_metadata: BTreeMap<String, String>,
+    pub populations: Vec<PopulationCheck>,
+    pub consistent: bool,
+    pub mismatches: Vec<String>, // Why the sources disagree, empty when consistent
+}
+
+// Compares the ontology's release with the one hpo.db was built from, and checks every population's terms exist
+pub fn check(ontology: &Ontology, db_metadata: BTreeMap<String, String>, populations: &[(&str, &Population)]) -> VersionReport {
+    let ontology_version = ontology.hpo_version();
+    let db_hpo_version = db_metadata.get("hpo_version").cloned();
+    let mut mismatches = Vec::new();
+
+    match &db_hpo_version {
+        Some(version) if *version != ontology_version => {
+            mismatches.push(format!("hpo.db was built from HPO {} but bin_hpo_file is {}", version, ontology_version));
+        }
+        Some(_) => {}
+        None => mismatches.push("hpo.db has no build metadata, its HPO release is unknown".to_string()),
+    }
+
+    let populations: Vec<PopulationCheck> = populations
+        .iter()
+        .map(|(name, population)| check_population(ontology, name, population))
+        .collect();
+    for population in populations.iter().filter(|p| p.missing_terms > 0) {
+        mismatches.push(format!("{} of the {} terms in {} are not in the ontology", population.missing_terms, population.terms, population.name));
+    }
+
+    VersionReport {
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        ontology_version,
+        ontology_terms: ontology.len(),
+        db_hpo_version,
+        db_metadata,
+        populations,
+        consistent: mismatches.is_empty(),
+        mismatches,
+    }
+}
+
+fn check_population(ontology: &Ontology, name: &str, population: &Population) -> PopulationCheck {
+    let terms: BTreeSet<&str> = population.values().flat_map(population::raw_terms).collect();
+    let missing: Vec<&str> = terms
+        .iter()
+        .filter(|term| {
+            term.trim_start_matches("HP:").parse::<u32>().ok().and_then(|id| ontology.hpo(id)).is_none()
+        })
+        .copied()
+        .collect();
+
+    PopulationCheck {
+        name: name.to_string(),
+        individuals: population.len(),
+        terms: terms.len(),
+        missing_terms: missing.len(),
+        missing_examples: missing.iter().take(MISSING_EXAMPLES).map(|term| term.to_string()).collect(),
+    }
+}
+
+pub fn is_strict() -> bool {
+    std::env::var(STRICT_ENV).map(|v| !v.is_empty() && v != "0" && v != "false").unwrap_or(false)
+}